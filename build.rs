@@ -1,175 +1,146 @@
 extern crate shaderc;
 
+#[allow(dead_code)]
+#[path = "src/renderer/shader_compiler.rs"]
+mod shader_compiler;
+#[allow(dead_code)]
+#[path = "src/renderer/shader_manifest.rs"]
+mod shader_manifest;
+#[path = "src/renderer/shader_variant.rs"]
+mod shader_variant;
+
 use std::{
-    fs::{self, File},
-    io::{BufRead, BufReader, Read, Write},
+    collections::BTreeSet,
+    fs,
     path::{Path, PathBuf},
 };
 
-use color_eyre::eyre::{eyre, OptionExt, Result};
-use shaderc::CompilationArtifact;
+use color_eyre::eyre::{Context, Result};
 
-const COMBINED_SHADER_EXT: &str = "combined";
-const COMP_SHADER_EXT: &str = "comp";
-const INCLUDE_SHADER_EXT: &str = "glsl";
+use shader_compiler::{ShaderCompiler, COMBINED_SHADER_EXT, COMP_SHADER_EXT};
+use shader_manifest::{
+    read_manifest, remove_outputs, source_hash, write_manifest, Manifest,
+    ManifestEntry, MANIFEST_FILE,
+};
 
 fn main() -> Result<()> {
-    println!("cargo:rerun-if-changed=shaders/*");
+    // Also notices shader files being added or removed
+    println!("cargo:rerun-if-changed=shaders");
+    println!("cargo:rerun-if-env-changed=SHADER_BUILD_DIR");
 
     color_eyre::install()?;
 
-    let shaderbuild_dirpath = std::env::var("SHADER_BUILD_DIR")
-        .unwrap_or_else(|_| "./shaderbuild".to_string());
-    fs::create_dir_all(shaderbuild_dirpath.clone())?;
-
-    let compiler = shaderc::Compiler::new()
-        .ok_or_eyre("Failed to create shaderc compiler")?;
-    let options = shaderc::CompileOptions::new()
-        .ok_or_eyre("Failed to create shaderc options")?;
+    let shaderbuild_dirpath = PathBuf::from(
+        std::env::var("SHADER_BUILD_DIR")
+            .unwrap_or_else(|_| "./shaderbuild".to_string()),
+    );
+    fs::create_dir_all(&shaderbuild_dirpath)?;
 
     let shaders_dirpath = Path::new("./shaders");
+    let compiler = ShaderCompiler::new()?.include_dir(shaders_dirpath);
+
+    let manifest_path = shaderbuild_dirpath.join(MANIFEST_FILE);
+    let old_manifest = read_manifest(&manifest_path);
+    let mut manifest = Manifest::new();
+    let mut includes = BTreeSet::new();
+
     for entry in fs::read_dir(shaders_dirpath)? {
         let filepath = entry?.path();
-        if filepath.is_file() {
-            let ext = filepath.extension();
-            if ext.is_none() {
+        if !filepath.is_file() {
+            continue;
+        }
+        println!("cargo:rerun-if-changed={}", filepath.display());
+
+        // Include files are compiled as part of the shaders including them
+        let is_shader = filepath.extension().is_some_and(|ext| {
+            ext == COMBINED_SHADER_EXT || ext == COMP_SHADER_EXT
+        });
+        if !is_shader {
+            continue;
+        }
+        let Some(filename) = filepath.file_name().and_then(|n| n.to_str())
+        else {
+            continue;
+        };
+
+        let shader_includes = compiler.includes(&filepath)?;
+        let hash = source_hash(&filepath, &shader_includes)?;
+        includes.extend(shader_includes);
+
+        let old_entry = old_manifest.get(filename);
+        if let Some(old_entry) = old_entry {
+            let up_to_date = old_entry.hash == hash
+                && old_entry
+                    .outputs
+                    .iter()
+                    .all(|output| shaderbuild_dirpath.join(output).is_file());
+            if up_to_date {
+                manifest.insert(filename.to_string(), old_entry.clone());
                 continue;
             }
-            let ext = ext.unwrap();
-            let filestem = filepath.file_stem().unwrap().to_str().unwrap();
-            let filename = filepath.file_name().unwrap().to_str().unwrap();
-
-            if ext == COMBINED_SHADER_EXT {
-                let (vert_glsl, frag_glsl) =
-                    parse_combined_shaderfile(&filepath)?;
-                let vert_spirv = compile_shader(
-                    &vert_glsl,
-                    shaderc::ShaderKind::Vertex,
-                    &compiler,
-                    &options,
-                    filename,
-                )?;
-                let frag_spirv = compile_shader(
-                    &frag_glsl,
-                    shaderc::ShaderKind::Fragment,
-                    &compiler,
-                    &options,
-                    filename,
-                )?;
-
-                let vert_spv_filepath =
-                    format!("{}/{}-vert.spv", shaderbuild_dirpath, filestem);
-                let mut vert_spv_file = File::create(vert_spv_filepath)?;
-                vert_spv_file.write_all(vert_spirv.as_binary_u8())?;
-
-                let frag_spv_filepath =
-                    format!("{}/{}-frag.spv", shaderbuild_dirpath, filestem);
-                let mut frag_spv_file = File::create(frag_spv_filepath)?;
-                frag_spv_file.write_all(frag_spirv.as_binary_u8())?;
-            } else if ext == COMP_SHADER_EXT {
-                let mut file = File::open(&filepath)?;
-                let mut comp_glsl = String::new();
-                file.read_to_string(&mut comp_glsl)?;
-
-                let comp_spirv = compile_shader(
-                    &comp_glsl,
-                    shaderc::ShaderKind::Compute,
-                    &compiler,
-                    &options,
-                    filename,
-                )?;
-
-                let mut comp_spv_filepath = PathBuf::from(&shaderbuild_dirpath);
-                comp_spv_filepath.push(format!("{}-comp.spv", filestem));
-                let mut comp_spv_file = File::create(comp_spv_filepath)?;
-                comp_spv_file.write_all(comp_spirv.as_binary_u8())?;
-            }
         }
-    }
-
-    Ok(())
-}
 
-fn parse_combined_shaderfile(filepath: &PathBuf) -> Result<(String, String)> {
-    let file = File::open(filepath)?;
-    let reader = BufReader::new(file);
-    let lines = reader.lines();
-
-    let mut vert_glsl = String::new();
-    let mut frag_glsl = String::new();
-    let mut shadertype = None;
-
-    for line in lines {
-        let line = line?;
-
-        if line.trim_start().starts_with("#shader") {
-            if let Some(stype) = line.split_whitespace().nth(1) {
-                shadertype = match stype {
-                    "vertex" => Some(shaderc::ShaderKind::Vertex),
-                    "fragment" => Some(shaderc::ShaderKind::Fragment),
-                    _ => None,
-                };
-                continue;
-            }
+        // Every keyword permutation is compiled ahead of time
+        let mut outputs = Vec::new();
+        for shader in compiler.compile_file(&filepath)? {
+            outputs.extend(
+                shader
+                    .write(&shaderbuild_dirpath)?
+                    .iter()
+                    .filter_map(|path| path.file_name()?.to_str())
+                    .map(String::from),
+            );
+        }
 
-            return Err(eyre!("Invalid #shader type specifier: {}", line));
+        // e.g. variants of keywords that were removed
+        if let Some(old_entry) = old_entry {
+            remove_outputs(&shaderbuild_dirpath, &old_entry.outputs, &outputs);
         }
+        manifest.insert(filename.to_string(), ManifestEntry { hash, outputs });
+    }
 
-        if let Some(stype) = &shadertype {
-            let str_buf = match stype {
-                shaderc::ShaderKind::Vertex => Ok(&mut vert_glsl),
-                shaderc::ShaderKind::Fragment => Ok(&mut frag_glsl),
-                _ => Err(eyre!("Invalid shadertype")),
-            }?;
-
-            // Handle #include directives
-            if line.trim_start().starts_with("#include") {
-                if let Some(filename) = line.split_whitespace().nth(1) {
-                    // Open file and append its contents to the the current string buffer
-                    let path =
-                        filepath.parent().unwrap().join(strip_quotes(filename));
-                    eprintln!("path: {:?}", path);
-                    if path.is_file() {
-                        str_buf.push_str(&std::fs::read_to_string(path)?);
-                    }
-                }
-            } else {
-                // No #include directive, just append the line to the current string buffer
-                str_buf.push_str(&line);
-                str_buf.push('\n');
-            }
+    // Outputs of deleted shaders
+    for (filename, entry) in &old_manifest {
+        if !manifest.contains_key(filename) {
+            remove_outputs(&shaderbuild_dirpath, &entry.outputs, &[]);
         }
     }
 
-    if vert_glsl.is_empty() {
-        Err(eyre!("No vertex #shader type specifier found"))
-    } else if frag_glsl.is_empty() {
-        Err(eyre!("No fragment #shader type specifier found"))
-    } else {
-        Ok((vert_glsl, frag_glsl))
+    // Includes may live outside of the shaders directory
+    for include in includes {
+        println!("cargo:rerun-if-changed={}", include.display());
     }
-}
 
-fn compile_shader(
-    glsl: &str,
-    kind: shaderc::ShaderKind,
-    compiler: &shaderc::Compiler,
-    options: &shaderc::CompileOptions,
-    filename: &str,
-) -> Result<CompilationArtifact> {
-    Ok(compiler.compile_into_spirv(
-        glsl,
-        kind,
-        filename,
-        "main",
-        Some(options),
-    )?)
+    write_manifest(&manifest_path, &manifest)?;
+
+    if std::env::var_os("CARGO_FEATURE_EMBED_SHADERS").is_some() {
+        write_embedded_shaders(&shaderbuild_dirpath, &manifest)?;
+    }
+
+    Ok(())
 }
 
-fn strip_quotes(s: &str) -> &str {
-    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
-        &s[1..s.len() - 1]
-    } else {
-        s
+/// Generate the table of src/renderer/embedded_shaders.rs,
+/// with every output of the manifest included by absolute path
+fn write_embedded_shaders(
+    shaderbuild_dirpath: &Path,
+    manifest: &Manifest,
+) -> Result<()> {
+    let shaderbuild_dirpath = fs::canonicalize(shaderbuild_dirpath)?;
+    let mut contents =
+        String::from("pub static EMBEDDED_SHADERS: &[(&str, &[u8])] = &[\n");
+    for output in manifest.values().flat_map(|entry| &entry.outputs) {
+        let filepath = shaderbuild_dirpath.join(output);
+        contents.push_str(&format!(
+            "    ({:?}, include_bytes!({:?}) as &[u8]),\n",
+            output,
+            filepath.to_string_lossy()
+        ));
     }
+    contents.push_str("];\n");
+
+    let out_dirpath = PathBuf::from(std::env::var("OUT_DIR")?);
+    let path = out_dirpath.join("embedded_shaders.rs");
+    fs::write(&path, contents)
+        .with_context(|| format!("Failed to write file: {:#?}", path))
 }
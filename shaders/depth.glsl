@@ -0,0 +1,15 @@
+#ifndef DEPTH_GLSL
+#define DEPTH_GLSL
+
+// Linear depth is the depth that is linearly interpolated between the near and far planes
+float linear_depth(mat4 viewproj, vec3 world_pos, float near, float far) {
+    // Transform world pos to clip space
+    vec4 clip_pos = viewproj * vec4(world_pos, 1.0);
+    // Calculate clip space depth and scale to range [-1, 1]
+    float clip_depth = (clip_pos.z / clip_pos.w) * 2.0 - 1.0;
+    // Get linear depth value between near and far
+    float linear_depth = (2.0 * near * far) / (far + near - clip_depth * (far - near));
+    return linear_depth / far; // Normalize
+}
+
+#endif
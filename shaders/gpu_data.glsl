@@ -0,0 +1,47 @@
+// Generated from src/renderer/gpu_data.rs, do not edit.
+// Regenerate with `UPDATE_GPU_DATA_GLSL=1 cargo test gpu_data_glsl`
+
+#ifndef GPU_DATA_GLSL
+#define GPU_DATA_GLSL
+
+#extension GL_EXT_buffer_reference : require
+
+struct GpuSceneData {
+    mat4 viewproj;
+    float near;
+    float far;
+    vec4 ambient_color;
+    vec4 sunlight_direction;
+    vec4 sunlight_color;
+};
+
+struct GpuVertexData {
+    vec3 position;
+    float uv_x;
+    vec3 normal;
+    float uv_y;
+    vec4 color;
+};
+
+layout (buffer_reference, std430) readonly buffer VertexBuffer {
+    GpuVertexData vertices[];
+};
+
+struct GpuDrawPushConstants {
+    mat4 world_matrix;
+    VertexBuffer vertex_buffer;
+};
+
+struct GpuTexturedPushConstants {
+    mat4 world_matrix;
+    uint texture_index;
+};
+
+struct MaterialConstants {
+    vec4 color_factors;
+    vec4 metal_rough_factors;
+    uint color_texture;
+    uint metal_rough_texture;
+};
+
+#endif
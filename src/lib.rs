@@ -1,6 +1,8 @@
 use bevy::{prelude::*, window::WindowResolution};
 use color_eyre::eyre::{eyre, Result};
-use renderer::{plugins::RenderPlugin, ASSETS_DIR, SHADERBUILD_DIR};
+use renderer::{
+    plugins::RenderPlugin, ASSETS_DIR, SHADERBUILD_DIR, SHADERS_DIR,
+};
 use std::process::ExitCode;
 
 mod renderer;
@@ -56,5 +58,11 @@ fn set_directories() -> Result<()> {
         unsafe { ASSETS_DIR = Some(dir) };
     }
 
+    // Set shader source directory, used to compile shader variants
+    // that weren't built ahead of time and for hot-reloading
+    let dir = std::env::var("SHADERS_DIR")
+        .unwrap_or_else(|_| "./shaders".to_string());
+    unsafe { SHADERS_DIR = Some(dir) };
+
     Ok(())
 }
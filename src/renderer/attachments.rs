@@ -0,0 +1,224 @@
+use ash::vk;
+use color_eyre::eyre::{eyre, Result};
+use gpu_allocator::vulkan::Allocator;
+
+use super::image::AllocatedImage;
+
+/// Multisampling of the geometry pass.
+/// The attachments of every target and the pipelines of every material
+/// drawn into them must use the same sample count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Msaa {
+    pub samples: vk::SampleCountFlags,
+    /// Fraction of samples that are shaded individually,
+    /// None shades once per pixel
+    pub min_sample_shading: Option<f32>,
+}
+
+impl Default for Msaa {
+    fn default() -> Self {
+        Self {
+            samples: vk::SampleCountFlags::TYPE_1,
+            min_sample_shading: None,
+        }
+    }
+}
+
+impl Msaa {
+    /// Highest count of 1, 2, 4 or 8 samples that doesn't exceed
+    /// `sample_count` and is in `supported`.
+    /// `sample_count` must be a power of two.
+    pub fn new(
+        sample_count: u32,
+        supported: vk::SampleCountFlags,
+    ) -> Result<Self> {
+        if !sample_count.is_power_of_two() {
+            return Err(eyre!(
+                "MSAA sample count must be a power of two, got {}",
+                sample_count
+            ));
+        }
+        let samples = [
+            vk::SampleCountFlags::TYPE_8,
+            vk::SampleCountFlags::TYPE_4,
+            vk::SampleCountFlags::TYPE_2,
+        ]
+        .into_iter()
+        .find(|samples| {
+            samples.as_raw() <= sample_count && supported.contains(*samples)
+        })
+        .unwrap_or(vk::SampleCountFlags::TYPE_1);
+
+        Ok(Self {
+            samples,
+            min_sample_shading: None,
+        })
+    }
+
+    /// Requires the sampleRateShading feature
+    pub fn with_sample_shading(mut self, min_sample_shading: f32) -> Self {
+        self.min_sample_shading = Some(min_sample_shading.clamp(0.0, 1.0));
+        self
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.samples.as_raw()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.samples != vk::SampleCountFlags::TYPE_1
+    }
+}
+
+/// Images the geometry pass renders into besides its color target
+pub struct RenderAttachments {
+    pub depth_image: AllocatedImage,
+    /// Resolved into the color target at the end of the pass,
+    /// None if multisampling is disabled
+    pub msaa_color_image: Option<AllocatedImage>,
+    color_format: vk::Format,
+}
+
+impl RenderAttachments {
+    pub fn new(
+        extent: vk::Extent2D,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        samples: vk::SampleCountFlags,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<Self> {
+        let depth_image = AllocatedImage::new_depth_image(
+            extent.width,
+            extent.height,
+            depth_format,
+            samples,
+            device,
+            allocator,
+        )?;
+
+        let msaa_color_image = if samples != vk::SampleCountFlags::TYPE_1 {
+            let image = AllocatedImage::new_msaa_color_image(
+                extent.width,
+                extent.height,
+                color_format,
+                samples,
+                device,
+                allocator,
+            );
+            match image {
+                Ok(image) => Some(image),
+                Err(err) => {
+                    depth_image.cleanup(device, allocator);
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(Self {
+            depth_image,
+            msaa_color_image,
+            color_format,
+        })
+    }
+
+    pub fn samples(&self) -> vk::SampleCountFlags {
+        self.depth_image.samples
+    }
+
+    /// Recreate the images with a different sample count.
+    /// The old images must not be in use anymore.
+    pub fn set_samples(
+        &mut self,
+        samples: vk::SampleCountFlags,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<()> {
+        if samples == self.samples() {
+            return Ok(());
+        }
+
+        let extent = vk::Extent2D {
+            width: self.depth_image.extent.width,
+            height: self.depth_image.extent.height,
+        };
+        let attachments = Self::new(
+            extent,
+            self.color_format,
+            self.depth_image.format,
+            samples,
+            device,
+            allocator,
+        )?;
+        std::mem::replace(self, attachments).cleanup(device, allocator);
+
+        Ok(())
+    }
+
+    pub fn cleanup(self, device: &ash::Device, allocator: &mut Allocator) {
+        self.depth_image.cleanup(device, allocator);
+        if let Some(image) = self.msaa_color_image {
+            image.cleanup(device, allocator);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_msaa_clamps_to_supported_sample_counts() {
+        let all = vk::SampleCountFlags::TYPE_1
+            | vk::SampleCountFlags::TYPE_2
+            | vk::SampleCountFlags::TYPE_4
+            | vk::SampleCountFlags::TYPE_8
+            | vk::SampleCountFlags::TYPE_16;
+        let sample_count = |count, supported| {
+            Msaa::new(count, supported).unwrap().sample_count()
+        };
+
+        assert_eq!(sample_count(1, all), 1);
+        assert_eq!(sample_count(4, all), 4);
+        // Capped at 8 even if the GPU supports more
+        assert_eq!(sample_count(16, all), 8);
+        assert_eq!(sample_count(64, all), 8);
+
+        let up_to_4 = vk::SampleCountFlags::TYPE_1
+            | vk::SampleCountFlags::TYPE_2
+            | vk::SampleCountFlags::TYPE_4;
+        assert_eq!(sample_count(8, up_to_4), 4);
+        // Skips counts the GPU doesn't support
+        let no_2 = vk::SampleCountFlags::TYPE_1 | vk::SampleCountFlags::TYPE_4;
+        assert_eq!(sample_count(2, no_2), 1);
+        assert_eq!(sample_count(8, vk::SampleCountFlags::TYPE_1), 1);
+
+        let msaa = Msaa::new(1, all).unwrap();
+        assert!(!msaa.is_enabled());
+        assert_eq!(msaa.min_sample_shading, None);
+    }
+
+    #[test]
+    fn test_msaa_rejects_non_power_of_two() {
+        let all = vk::SampleCountFlags::TYPE_1
+            | vk::SampleCountFlags::TYPE_2
+            | vk::SampleCountFlags::TYPE_4
+            | vk::SampleCountFlags::TYPE_8;
+        for count in [0, 3, 6, 12] {
+            assert!(Msaa::new(count, all).is_err(), "{}x", count);
+        }
+    }
+
+    #[test]
+    fn test_msaa_sample_shading_is_clamped() {
+        let msaa = Msaa::default();
+        assert_eq!(msaa.with_sample_shading(2.0).min_sample_shading, Some(1.0));
+        assert_eq!(
+            msaa.with_sample_shading(-1.0).min_sample_shading,
+            Some(0.0)
+        );
+        assert_eq!(msaa.with_sample_shading(0.5).min_sample_shading, Some(0.5));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{HashMap, VecDeque};
+
+use ash::vk;
+use bevy::log;
+use color_eyre::eyre::{eyre, OptionExt, Result};
+
+use super::{
+    descriptors::{DescriptorSetLayoutBuilder, DescriptorWriter},
+    inner::FRAME_OVERLAP,
+    texture::Texture,
+};
+
+/// Every graphics texture in one partially bound array of combined image
+/// samplers, so that materials and draws pick textures by index
+/// instead of allocating a descriptor set per texture.
+/// Shaders declare it as `layout (set = N, binding = 0) uniform sampler2D
+/// textures[];` and index it with `nonuniformEXT`.
+pub struct BindlessTextures {
+    pool: vk::DescriptorPool,
+    /// Owned by the layout cache it was built with
+    layout: vk::DescriptorSetLayout,
+    desc_set: vk::DescriptorSet,
+    capacity: u32,
+    indices: HashMap<String, u32>,
+    /// Indices of removed textures with the frame they were removed in.
+    /// Frames in flight may still sample them,
+    /// so they only become free FRAME_OVERLAP frames later.
+    retired_indices: VecDeque<(u32, u64)>,
+    /// Indices of removed textures, reused before growing
+    free_indices: Vec<u32>,
+    next_index: u32,
+    frame_number: u64,
+    writer: DescriptorWriter,
+}
+
+impl BindlessTextures {
+    const BINDING: u32 = 0;
+    /// Upper bound regardless of what the GPU allows
+    const MAX_TEXTURES: u32 = 4096;
+
+    /// Number of textures the array holds on a GPU with the given limit
+    pub fn capacity_for(max_bindless_textures: u32) -> u32 {
+        max_bindless_textures.min(Self::MAX_TEXTURES)
+    }
+
+    pub fn layout_builder(capacity: u32) -> DescriptorSetLayoutBuilder {
+        DescriptorSetLayoutBuilder::new().add_bindless_binding(
+            Self::BINDING,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            capacity,
+            vk::ShaderStageFlags::ALL_GRAPHICS | vk::ShaderStageFlags::COMPUTE,
+        )
+    }
+
+    /// `layout` must have been built by `layout_builder` with `capacity`
+    pub fn new(
+        device: &ash::Device,
+        layout: vk::DescriptorSetLayout,
+        capacity: u32,
+    ) -> Result<Self> {
+        let pool_sizes = [vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: capacity,
+        }];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .max_sets(1)
+            .pool_sizes(&pool_sizes)
+            .build();
+        let pool = unsafe { device.create_descriptor_pool(&pool_info, None)? };
+
+        let counts = [capacity];
+        let mut count_info =
+            vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
+                .descriptor_counts(&counts)
+                .build();
+        let layouts = [layout];
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts)
+            .push_next(&mut count_info)
+            .build();
+        let desc_set =
+            match unsafe { device.allocate_descriptor_sets(&alloc_info) } {
+                Ok(sets) => sets[0],
+                Err(err) => {
+                    unsafe { device.destroy_descriptor_pool(pool, None) };
+                    return Err(eyre!(
+                        "Failed to allocate bindless texture set: {:?}",
+                        err
+                    ));
+                }
+            };
+        log::info!("Bindless texture array holds {} textures", capacity);
+
+        Ok(Self {
+            pool,
+            layout,
+            desc_set,
+            capacity,
+            indices: HashMap::new(),
+            retired_indices: VecDeque::new(),
+            free_indices: Vec::new(),
+            next_index: 0,
+            frame_number: 0,
+            writer: DescriptorWriter::new(),
+        })
+    }
+
+    pub fn desc_set(&self) -> vk::DescriptorSet {
+        self.desc_set
+    }
+
+    pub fn layout(&self) -> vk::DescriptorSetLayout {
+        self.layout
+    }
+
+    pub fn index(&self, name: &str) -> Option<u32> {
+        self.indices.get(name).copied()
+    }
+
+    /// Write a texture into a free element of the array and return its index.
+    /// Adding a name again rewrites its element, e.g. after a resize.
+    /// The texture must be in SHADER_READ_ONLY_OPTIMAL whenever a shader
+    /// samples it.
+    pub fn add(
+        &mut self,
+        device: &ash::Device,
+        name: &str,
+        texture: &Texture,
+    ) -> Result<u32> {
+        let sampler = texture
+            .sampler()
+            .ok_or_eyre("Only textures with a sampler can be bindless")?;
+        let index = match self.indices.get(name) {
+            Some(index) => *index,
+            None => match self.free_indices.pop() {
+                Some(index) => index,
+                None if self.next_index < self.capacity => {
+                    self.next_index += 1;
+                    self.next_index - 1
+                }
+                None => {
+                    return Err(eyre!(
+                        "Bindless texture array is full ({} textures)",
+                        self.capacity
+                    ))
+                }
+            },
+        };
+
+        self.writer.clear();
+        self.writer.write_image_element(
+            Self::BINDING,
+            index,
+            texture.image().view,
+            sampler,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        );
+        self.writer.update_set(device, self.desc_set);
+        self.indices.insert(name.into(), index);
+
+        Ok(index)
+    }
+
+    /// The element keeps pointing at the removed texture until it is reused,
+    /// so shaders must not sample it anymore.
+    /// It isn't reused before the frames in flight have finished.
+    pub fn remove(&mut self, name: &str) -> Option<u32> {
+        let index = self.indices.remove(name)?;
+        self.retired_indices.push_back((index, self.frame_number));
+        Some(index)
+    }
+
+    /// Call once per drawn frame,
+    /// frees the indices no frame in flight can sample anymore
+    pub fn end_frame(&mut self) {
+        self.frame_number += 1;
+        while let Some(&(index, removed_in)) = self.retired_indices.front() {
+            if self.frame_number - removed_in < FRAME_OVERLAP as u64 {
+                break;
+            }
+            self.free_indices.push(index);
+            self.retired_indices.pop_front();
+        }
+    }
+
+    /// The layout is destroyed along with the other layouts in
+    /// RenderResources
+    pub fn cleanup(self, device: &ash::Device) {
+        unsafe { device.destroy_descriptor_pool(self.pool, None) };
+    }
+}
@@ -1,8 +1,37 @@
 use std::f32::consts::PI;
 
-use bevy::{ecs::component::Component, log};
+use bevy::{
+    ecs::{component::Component, entity::Entity},
+    log,
+};
 use glam::{Mat4, Vec2, Vec3, Vec4, Vec4Swizzles};
 
+/// Where a camera's image ends up
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RenderTarget {
+    #[default]
+    PrimaryWindow,
+    Window(Entity),
+    /// Offscreen color texture stored in the render resources under `name`,
+    /// so that materials can sample it like any other texture
+    Texture {
+        name: String,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl RenderTarget {
+    /// Returns None if the target is not a window
+    pub fn window_entity(&self, primary_window: Entity) -> Option<Entity> {
+        match self {
+            Self::PrimaryWindow => Some(primary_window),
+            Self::Window(window) => Some(*window),
+            Self::Texture { .. } => None,
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct Camera {
     position: Vec3,
@@ -14,6 +43,7 @@ pub struct Camera {
     pub near: f32,
     pub far: f32,
     pivot: Vec3,
+    target: RenderTarget,
 }
 
 impl Default for Camera {
@@ -28,6 +58,7 @@ impl Default for Camera {
             near: 0.1,
             far: 100.0,
             pivot: Vec3::ZERO,
+            target: RenderTarget::default(),
         }
     }
 }
@@ -35,6 +66,15 @@ impl Default for Camera {
 impl Camera {
     const DEFAULT_FOV_Y_DEG: f32 = 45.0;
 
+    pub fn with_target(mut self, target: RenderTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn target(&self) -> &RenderTarget {
+        &self.target
+    }
+
     pub fn set_position(&mut self, position: Vec3) {
         self.position = position;
         self.look_at(self.pivot);
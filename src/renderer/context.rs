@@ -21,10 +21,21 @@ pub struct Context {
     pub present_queue_family: u32,
 
     pub instance: ash::Instance,
-    pub surface: vk::SurfaceKHR,
     pub surface_loader: ash::extensions::khr::Surface,
     pub physical_device: vk::PhysicalDevice,
     pub physical_device_props: vk::PhysicalDeviceProperties,
+    /// Optional features that the device supports and that were enabled
+    pub enabled_features: vk::PhysicalDeviceFeatures,
+    /// Most sampled images a single update-after-bind set can hold
+    pub max_bindless_textures: u32,
+    /// Format of every depth attachment, with a stencil aspect if supported
+    pub depth_format: vk::Format,
+    /// Pipeline state that materials can set when they are bound
+    pub dynamic_state_support: DynamicStateSupport,
+    /// Loaded if dynamic color blending is supported
+    pub ext_dynamic_state3: Option<ash::extensions::ext::ExtendedDynamicState3>,
+    /// Loaded if VK_KHR_push_descriptor is supported
+    pub push_descriptor: Option<ash::extensions::khr::PushDescriptor>,
 
     entry: ash::Entry,
     debug_messenger: vk::DebugUtilsMessengerEXT,
@@ -37,7 +48,11 @@ impl Context {
     const REQUIRED_VALIDATION_LAYERS: [&'static str; 1] =
         ["VK_LAYER_KHRONOS_validation"];
 
-    pub fn new(window: &winit::window::Window) -> Result<Self> {
+    /// Create the core Vulkan objects along with a surface for the given window.
+    /// The returned surface is owned by the caller.
+    pub fn new(
+        window: &winit::window::Window,
+    ) -> Result<(Self, vk::SurfaceKHR)> {
         let req_instance_exts = Self::get_required_instance_extensions(window)?;
         let req_device_exts = Self::get_required_device_extensions();
 
@@ -63,6 +78,29 @@ impl Context {
                 .min_uniform_buffer_offset_alignment
         );
 
+        let enabled_features =
+            Self::get_optional_features(&instance, physical_device);
+        let max_bindless_textures =
+            Self::get_max_bindless_textures(&instance, physical_device);
+        let depth_format =
+            Self::choose_depth_format(&instance, physical_device);
+        let dynamic_state_support =
+            Self::get_dynamic_state_support(&instance, physical_device)?;
+        let push_descriptor_support =
+            Self::get_push_descriptor_support(&instance, physical_device)?;
+
+        // Required extensions and the optional ones the device supports
+        let mut device_exts = req_device_exts.clone();
+        if dynamic_state_support.color_blend {
+            device_exts.push(
+                ash::extensions::ext::ExtendedDynamicState3::name().to_owned(),
+            );
+        }
+        if push_descriptor_support {
+            device_exts
+                .push(ash::extensions::khr::PushDescriptor::name().to_owned());
+        }
+
         let (
             device,
             graphics_queue,
@@ -74,13 +112,21 @@ impl Context {
             &physical_device,
             &surface,
             &surface_loader,
-            &req_device_exts,
+            &device_exts,
+            &enabled_features,
+            dynamic_state_support,
         )?;
+        let ext_dynamic_state3 = dynamic_state_support.color_blend.then(|| {
+            ash::extensions::ext::ExtendedDynamicState3::new(&instance, &device)
+        });
+        let push_descriptor = push_descriptor_support.then(|| {
+            ash::extensions::khr::PushDescriptor::new(&instance, &device)
+        });
 
         let upload_context =
             UploadContext::new(&device, graphics_queue_family, graphics_queue)?;
 
-        Ok(Self {
+        let ctx = Self {
             device,
             graphics_queue,
             present_queue,
@@ -88,16 +134,57 @@ impl Context {
             present_queue_family,
 
             instance,
-            surface,
             surface_loader,
             physical_device,
             physical_device_props,
+            enabled_features,
+            max_bindless_textures,
+            depth_format,
+            dynamic_state_support,
+            ext_dynamic_state3,
+            push_descriptor,
 
             entry,
             debug_messenger,
             debug_messenger_loader,
             upload_context,
-        })
+        };
+
+        Ok((ctx, surface))
+    }
+
+    /// Create a surface for an additional window.
+    /// The surface must be destroyed with `destroy_surface` before the window is.
+    pub fn create_window_surface(
+        &self,
+        window: &winit::window::Window,
+    ) -> Result<vk::SurfaceKHR> {
+        let (surface, _) =
+            Self::create_surface(&self.entry, &self.instance, window)?;
+
+        // The present queue was chosen for the primary surface,
+        // so make sure it can also present to this one
+        let present_support = unsafe {
+            self.surface_loader.get_physical_device_surface_support(
+                self.physical_device,
+                self.present_queue_family,
+                surface,
+            )?
+        };
+        if !present_support {
+            self.destroy_surface(surface);
+            return Err(eyre!(
+                "Present queue family does not support the window surface"
+            ));
+        }
+
+        Ok(surface)
+    }
+
+    pub fn destroy_surface(&self, surface: vk::SurfaceKHR) {
+        unsafe {
+            self.surface_loader.destroy_surface(surface, None);
+        }
     }
 
     pub fn execute_one_time_command<F>(&self, func: F) -> Result<()>
@@ -113,8 +200,6 @@ impl Context {
         unsafe {
             self.device.destroy_device(None);
 
-            // Segfault occurs here if window gets destroyed before surface
-            self.surface_loader.destroy_surface(self.surface, None);
             if Self::ENABLE_VALIDATION_LAYERS {
                 self.debug_messenger_loader
                     .destroy_debug_utils_messenger(self.debug_messenger, None);
@@ -129,6 +214,13 @@ impl Context {
             .min_uniform_buffer_offset_alignment
     }
 
+    /// Sample counts usable for both the color and depth attachments
+    pub fn supported_sample_counts(&self) -> vk::SampleCountFlags {
+        let limits = &self.physical_device_props.limits;
+        limits.framebuffer_color_sample_counts
+            & limits.framebuffer_depth_sample_counts
+    }
+
     /// Returns the padded size of the buffer according to the min alignment
     pub fn pad_uniform_buffer_size(&self, original_size: u64) -> u64 {
         vkutils::pad_uniform_buffer_size(
@@ -293,12 +385,179 @@ impl Context {
         }
     }
 
+    /// Optional features are enabled only if the device supports them
+    fn get_optional_features(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> vk::PhysicalDeviceFeatures {
+        let supported =
+            unsafe { instance.get_physical_device_features(physical_device) };
+        if supported.fill_mode_non_solid == vk::FALSE {
+            log::warn!("GPU does not support wireframe rendering");
+        }
+        if supported.sample_rate_shading == vk::FALSE {
+            log::warn!("GPU does not support sample shading");
+        }
+        if supported.geometry_shader == vk::FALSE {
+            log::warn!("GPU does not support geometry shaders");
+        }
+        if supported.tessellation_shader == vk::FALSE {
+            log::warn!("GPU does not support tessellation shaders");
+        }
+        vk::PhysicalDeviceFeatures {
+            // Needed for wireframe materials
+            fill_mode_non_solid: supported.fill_mode_non_solid,
+            // Needed to shade multisampled pixels per sample
+            sample_rate_shading: supported.sample_rate_shading,
+            // Needed for shaders with geometry or tessellation stages
+            geometry_shader: supported.geometry_shader,
+            tessellation_shader: supported.tessellation_shader,
+            ..Default::default()
+        }
+    }
+
+    /// Prefer formats with a stencil aspect, which selection outlines need
+    fn choose_depth_format(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> vk::Format {
+        let format = [
+            vk::Format::D32_SFLOAT_S8_UINT,
+            vk::Format::D24_UNORM_S8_UINT,
+        ]
+        .into_iter()
+        .find(|format| {
+            let props = unsafe {
+                instance.get_physical_device_format_properties(
+                    physical_device,
+                    *format,
+                )
+            };
+            props
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+        });
+
+        match format {
+            Some(format) => format,
+            None => {
+                log::warn!("GPU does not support depth-stencil attachments");
+                vk::Format::D32_SFLOAT
+            }
+        }
+    }
+
+    /// Extended dynamic state is core in Vulkan 1.3,
+    /// dynamic color blending needs VK_EXT_extended_dynamic_state3
+    fn get_dynamic_state_support(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<DynamicStateSupport> {
+        let has_ext = Self::physical_device_has_extensions(
+            &physical_device,
+            &vec![
+                ash::extensions::ext::ExtendedDynamicState3::name().to_owned()
+            ],
+            instance,
+        )?;
+        let color_blend = has_ext && {
+            let mut eds3_feats =
+                vk::PhysicalDeviceExtendedDynamicState3FeaturesEXT::default();
+            let mut feats = vk::PhysicalDeviceFeatures2::builder()
+                .push_next(&mut eds3_feats)
+                .build();
+            unsafe {
+                instance
+                    .get_physical_device_features2(physical_device, &mut feats)
+            };
+            eds3_feats.extended_dynamic_state3_color_blend_enable == vk::TRUE
+                && eds3_feats.extended_dynamic_state3_color_blend_equation
+                    == vk::TRUE
+                && eds3_feats.extended_dynamic_state3_color_write_mask
+                    == vk::TRUE
+        };
+
+        if !color_blend {
+            log::warn!("GPU does not support dynamic color blending");
+        }
+
+        Ok(DynamicStateSupport { color_blend })
+    }
+
+    /// Per-draw descriptors are written into sets from descriptor pools
+    /// if VK_KHR_push_descriptor is missing
+    fn get_push_descriptor_support(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<bool> {
+        let supported = Self::physical_device_has_extensions(
+            &physical_device,
+            &vec![ash::extensions::khr::PushDescriptor::name().to_owned()],
+            instance,
+        )?;
+        if !supported {
+            log::warn!("GPU does not support push descriptors");
+        }
+        Ok(supported)
+    }
+
+    fn get_max_bindless_textures(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> u32 {
+        let mut indexing_props =
+            vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut props = vk::PhysicalDeviceProperties2::builder()
+            .push_next(&mut indexing_props)
+            .build();
+        unsafe {
+            instance
+                .get_physical_device_properties2(physical_device, &mut props)
+        };
+        indexing_props
+            .max_descriptor_set_update_after_bind_sampled_images
+            .min(
+                indexing_props
+                    .max_per_stage_descriptor_update_after_bind_sampled_images,
+            )
+            .min(
+                indexing_props
+                    .max_per_stage_descriptor_update_after_bind_samplers,
+            )
+    }
+
+    /// Bindless textures need runtime sized, partially bound arrays
+    /// of sampled images that can be updated while bound
+    fn supports_descriptor_indexing(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> bool {
+        let mut indexing_feats =
+            vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let mut feats = vk::PhysicalDeviceFeatures2::builder()
+            .push_next(&mut indexing_feats)
+            .build();
+        unsafe {
+            instance.get_physical_device_features2(physical_device, &mut feats)
+        };
+        indexing_feats.shader_sampled_image_array_non_uniform_indexing
+            == vk::TRUE
+            && indexing_feats.descriptor_binding_sampled_image_update_after_bind
+                == vk::TRUE
+            && indexing_feats.descriptor_binding_partially_bound == vk::TRUE
+            && indexing_feats.descriptor_binding_variable_descriptor_count
+                == vk::TRUE
+            && indexing_feats.runtime_descriptor_array == vk::TRUE
+    }
+
     fn create_logical_device(
         instance: &ash::Instance,
         physical_device: &vk::PhysicalDevice,
         surface: &vk::SurfaceKHR,
         surface_loader: &ash::extensions::khr::Surface,
-        req_device_exts: &[CString],
+        device_exts: &[CString],
+        enabled_features: &vk::PhysicalDeviceFeatures,
+        dynamic_state_support: DynamicStateSupport,
     ) -> Result<(ash::Device, vk::Queue, vk::Queue, u32, u32)> {
         let indices = QueueFamilyIndices::new(
             instance,
@@ -323,8 +582,7 @@ impl Context {
             })
             .collect::<Vec<_>>();
 
-        let physical_device_features = vk::PhysicalDeviceFeatures::default();
-        let req_device_exts = req_device_exts
+        let device_exts = device_exts
             .iter()
             .map(|ext| ext.as_ptr())
             .collect::<Vec<_>>();
@@ -340,27 +598,55 @@ impl Context {
             p_next: dyn_rendering_feats.as_ptr() as *mut c_void,
             ..Default::default()
         }];
+        // Enable descriptor indexing for bindless textures
+        let descriptor_indexing_feats =
+            [vk::PhysicalDeviceDescriptorIndexingFeatures {
+                shader_sampled_image_array_non_uniform_indexing: vk::TRUE,
+                descriptor_binding_sampled_image_update_after_bind: vk::TRUE,
+                descriptor_binding_partially_bound: vk::TRUE,
+                descriptor_binding_variable_descriptor_count: vk::TRUE,
+                runtime_descriptor_array: vk::TRUE,
+                p_next: sync2_feats.as_ptr() as *mut c_void,
+                ..Default::default()
+            }];
         // Enable buffer device address
         let mut buffer_device_address_features =
             vk::PhysicalDeviceBufferDeviceAddressFeatures {
                 buffer_device_address: vk::TRUE,
-                p_next: sync2_feats.as_ptr() as *mut c_void,
+                p_next: descriptor_indexing_feats.as_ptr() as *mut c_void,
                 ..Default::default()
             };
-        let shader_draw_params_features =
-            vk::PhysicalDeviceShaderDrawParametersFeatures {
-                shader_draw_parameters: vk::TRUE,
+        // Enable dynamic color blending if supported
+        let mut eds3_feats =
+            vk::PhysicalDeviceExtendedDynamicState3FeaturesEXT {
+                extended_dynamic_state3_color_blend_enable: vk::TRUE,
+                extended_dynamic_state3_color_blend_equation: vk::TRUE,
+                extended_dynamic_state3_color_write_mask: vk::TRUE,
                 p_next: &mut buffer_device_address_features
                     as *mut vk::PhysicalDeviceBufferDeviceAddressFeatures
                     as *mut c_void,
                 ..Default::default()
             };
+        let shader_draw_params_features =
+            vk::PhysicalDeviceShaderDrawParametersFeatures {
+                shader_draw_parameters: vk::TRUE,
+                p_next: if dynamic_state_support.color_blend {
+                    &mut eds3_feats
+                        as *mut vk::PhysicalDeviceExtendedDynamicState3FeaturesEXT
+                        as *mut c_void
+                } else {
+                    &mut buffer_device_address_features
+                        as *mut vk::PhysicalDeviceBufferDeviceAddressFeatures
+                        as *mut c_void
+                },
+                ..Default::default()
+            };
         let device_info = vk::DeviceCreateInfo {
             p_queue_create_infos: queue_infos.as_ptr(),
-            p_enabled_features: &physical_device_features,
+            p_enabled_features: enabled_features,
             queue_create_info_count: queue_infos.len() as u32,
-            enabled_extension_count: req_device_exts.len() as u32,
-            pp_enabled_extension_names: req_device_exts.as_ptr(),
+            enabled_extension_count: device_exts.len() as u32,
+            pp_enabled_extension_names: device_exts.as_ptr(),
             p_next: &shader_draw_params_features
                 as *const vk::PhysicalDeviceShaderDrawParametersFeatures
                 as *const c_void,
@@ -437,7 +723,13 @@ impl Context {
             !details.formats.is_empty() && !details.present_modes.is_empty()
         };
 
-        Ok(indices.is_complete() && exts_supported && swapchain_adequate)
+        let descriptor_indexing =
+            Self::supports_descriptor_indexing(instance, *physical_device);
+
+        Ok(indices.is_complete()
+            && exts_supported
+            && swapchain_adequate
+            && descriptor_indexing)
     }
 
     fn log_physical_device_info(
@@ -528,6 +820,16 @@ impl Context {
     }
 }
 
+/// Pipeline state the device can set while recording
+/// instead of baking it into pipelines.
+/// Cull mode, front face, topology and depth testing
+/// are always dynamic since Vulkan 1.3 is required.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DynamicStateSupport {
+    /// Color blend enable, equation and write mask
+    pub color_blend: bool,
+}
+
 struct QueueFamilyIndices {
     graphics_family: Option<u32>,
     present_family: Option<u32>,
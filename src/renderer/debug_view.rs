@@ -0,0 +1,48 @@
+/// Global visualization that replaces the shading of scene geometry
+/// to diagnose meshes that look wrong
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    #[default]
+    Off,
+    /// Regular shading with the edges of every triangle drawn on top
+    Wireframe,
+    /// World space normals
+    Normals,
+    UvChecker,
+    /// Linear depth from the camera to the far plane, same as the grid's
+    LinearDepth,
+    VertexColor,
+    /// Brighter where more fragments land on the same pixel
+    Overdraw,
+}
+
+impl DebugView {
+    const ALL: [DebugView; 7] = [
+        Self::Off,
+        Self::Wireframe,
+        Self::Normals,
+        Self::UvChecker,
+        Self::LinearDepth,
+        Self::VertexColor,
+        Self::Overdraw,
+    ];
+
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|view| *view == self).unwrap();
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// Material that geometry is drawn with in this view.
+    /// The wireframe material is drawn on top of the regular materials.
+    pub fn material_name(self) -> Option<&'static str> {
+        match self {
+            Self::Off => None,
+            Self::Wireframe => Some("debug-wireframe"),
+            Self::Normals => Some("debug-normals"),
+            Self::UvChecker => Some("debug-uv-checker"),
+            Self::LinearDepth => Some("debug-linear-depth"),
+            Self::VertexColor => Some("debug-vertex-color"),
+            Self::Overdraw => Some("debug-overdraw"),
+        }
+    }
+}
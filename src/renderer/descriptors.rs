@@ -1,17 +1,41 @@
+use std::collections::HashMap;
+
 use ash::vk;
 use color_eyre::eyre::{eyre, Result};
 
+/// Description of a single binding in a descriptor set layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DescriptorBinding {
+    pub binding: u32,
+    pub desc_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+}
+
 pub struct DescriptorSetLayoutBuilder {
     bindings: Vec<vk::DescriptorSetLayoutBinding>,
+    /// Flags of each entry in `bindings`
+    binding_flags: Vec<vk::DescriptorBindingFlags>,
+    flags: vk::DescriptorSetLayoutCreateFlags,
 }
 
 impl DescriptorSetLayoutBuilder {
     pub fn new() -> Self {
         Self {
             bindings: Vec::new(),
+            binding_flags: Vec::new(),
+            flags: vk::DescriptorSetLayoutCreateFlags::empty(),
         }
     }
 
+    /// Sets of this layout aren't allocated but pushed into command buffers,
+    /// which needs VK_KHR_push_descriptor.
+    /// A pipeline layout can only have one set with such a layout.
+    pub fn push_descriptor(mut self) -> Self {
+        self.flags |= vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR;
+        self
+    }
+
     pub fn add_binding(
         mut self,
         binding: u32,
@@ -26,72 +50,295 @@ impl DescriptorSetLayoutBuilder {
                 .stage_flags(stage_flags)
                 .build(),
         );
+        self.binding_flags.push(vk::DescriptorBindingFlags::empty());
+        self
+    }
+
+    /// Array of up to `max_count` descriptors that doesn't need every element
+    /// written and can be updated while bound.
+    /// Its actual size is chosen when the set is allocated,
+    /// so it must be the binding with the highest number.
+    /// Sets of this layout must come from UPDATE_AFTER_BIND pools.
+    pub fn add_bindless_binding(
+        mut self,
+        binding: u32,
+        desc_type: vk::DescriptorType,
+        max_count: u32,
+        stage_flags: vk::ShaderStageFlags,
+    ) -> Self {
+        self.bindings.push(
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(desc_type)
+                .descriptor_count(max_count)
+                .stage_flags(stage_flags)
+                .build(),
+        );
+        self.binding_flags.push(
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT,
+        );
         self
     }
 
+    pub fn add_desc_bindings(mut self, bindings: &[DescriptorBinding]) -> Self {
+        for binding in bindings {
+            self.bindings.push(
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding.binding)
+                    .descriptor_type(binding.desc_type)
+                    // Runtime arrays in shaders have no fixed count
+                    .descriptor_count(binding.count.max(1))
+                    .stage_flags(binding.stages)
+                    .build(),
+            );
+            self.binding_flags.push(vk::DescriptorBindingFlags::empty());
+        }
+        self
+    }
+
+    /// Bindings added so far, used to check layouts against shaders
+    pub fn desc_bindings(&self) -> Vec<DescriptorBinding> {
+        self.bindings
+            .iter()
+            .map(|binding| DescriptorBinding {
+                binding: binding.binding,
+                desc_type: binding.descriptor_type,
+                count: binding.descriptor_count,
+                stages: binding.stage_flags,
+            })
+            .collect()
+    }
+
     pub fn clear(mut self) -> Self {
         self.bindings.clear();
+        self.binding_flags.clear();
+        self.flags = vk::DescriptorSetLayoutCreateFlags::empty();
         self
     }
 
+    /// Returns the cached layout if one with the same bindings was built
+    /// before, otherwise creates it and adds it to the cache
     pub fn build(
         self,
         device: &ash::Device,
+        cache: &mut DescriptorSetLayoutCache,
     ) -> Result<vk::DescriptorSetLayout> {
-        let info = vk::DescriptorSetLayoutCreateInfo::builder()
+        let key = self.key()?;
+        if let Some(layout) = cache.layouts.get(&key) {
+            return Ok(*layout);
+        }
+
+        let mut flags_info =
+            vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
+                .binding_flags(&self.binding_flags)
+                .build();
+        let mut info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .flags(key.flags)
             .bindings(&self.bindings)
             .build();
-        Ok(unsafe { device.create_descriptor_set_layout(&info, None)? })
+        if self.binding_flags.iter().any(|flags| !flags.is_empty()) {
+            info.p_next = &mut flags_info
+                as *mut vk::DescriptorSetLayoutBindingFlagsCreateInfo
+                as *const std::ffi::c_void;
+        }
+        let layout =
+            unsafe { device.create_descriptor_set_layout(&info, None)? };
+
+        cache.bindings.insert(layout, self.desc_bindings());
+        cache.layouts.insert(key, layout);
+        Ok(layout)
+    }
+
+    /// Identifies the layout in the cache.
+    /// Bindless bindings need an UPDATE_AFTER_BIND_POOL layout.
+    fn key(&self) -> Result<DescriptorSetLayoutKey> {
+        let mut create_flags = self.flags;
+        if self.binding_flags.iter().any(|flags| {
+            flags.contains(vk::DescriptorBindingFlags::UPDATE_AFTER_BIND)
+        }) {
+            if create_flags.contains(
+                vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR,
+            ) {
+                return Err(eyre!(
+                    "Push descriptor layouts can't have bindless bindings"
+                ));
+            }
+            create_flags |=
+                vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL;
+        }
+
+        let mut bindings = self
+            .desc_bindings()
+            .into_iter()
+            .zip(self.binding_flags.iter().copied())
+            .collect::<Vec<_>>();
+        bindings.sort_by_key(|(binding, _)| binding.binding);
+        Ok(DescriptorSetLayoutKey {
+            flags: create_flags,
+            bindings,
+        })
     }
 }
 
-#[derive(Debug, Clone)]
+/// Descriptor set layouts keyed by their bindings and flags,
+/// so that identical layouts are created once and shared.
+/// The cache never evicts: every layout lives until the cache is cleaned up,
+/// even once nothing uses it anymore, so its handle is never reused
+/// for another layout while pipelines or cached sets may still refer to it.
+#[derive(Default)]
+pub struct DescriptorSetLayoutCache {
+    layouts: HashMap<DescriptorSetLayoutKey, vk::DescriptorSetLayout>,
+    /// Bindings of each layout, used to check layouts against shaders
+    bindings: HashMap<vk::DescriptorSetLayout, Vec<DescriptorBinding>>,
+}
+
+impl DescriptorSetLayoutCache {
+    pub fn bindings(
+        &self,
+        layout: vk::DescriptorSetLayout,
+    ) -> Option<&[DescriptorBinding]> {
+        self.bindings
+            .get(&layout)
+            .map(|bindings| bindings.as_slice())
+    }
+
+    /// Pipeline layouts and descriptor sets using the layouts
+    /// must not be used afterwards
+    pub fn cleanup(&mut self, device: &ash::Device) {
+        self.bindings.clear();
+        self.layouts.drain().for_each(|(_, layout)| unsafe {
+            device.destroy_descriptor_set_layout(layout, None)
+        });
+    }
+}
+
+/// Everything a descriptor set layout is created from
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct DescriptorSetLayoutKey {
+    flags: vk::DescriptorSetLayoutCreateFlags,
+    /// Sorted by binding number, since binding order doesn't change the layout
+    bindings: Vec<(DescriptorBinding, vk::DescriptorBindingFlags)>,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct PoolSizeRatio {
     pub desc_type: vk::DescriptorType,
+    /// Descriptors of the type per set in the pool
     pub ratio: f32,
 }
 
+/// Ratios of allocators that don't know what they allocate
+pub const DEFAULT_POOL_RATIOS: [PoolSizeRatio; 4] = [
+    PoolSizeRatio {
+        desc_type: vk::DescriptorType::STORAGE_IMAGE,
+        ratio: 3.0,
+    },
+    PoolSizeRatio {
+        desc_type: vk::DescriptorType::STORAGE_BUFFER,
+        ratio: 3.0,
+    },
+    PoolSizeRatio {
+        desc_type: vk::DescriptorType::UNIFORM_BUFFER,
+        ratio: 3.0,
+    },
+    PoolSizeRatio {
+        desc_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        ratio: 4.0,
+    },
+];
+
+/// Pools stop growing at this many sets unless configured otherwise
+pub const DEFAULT_MAX_SETS_PER_POOL: u32 = 4092;
+
+/// What a `DescriptorAllocator` has done so far, for diagnostics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DescriptorAllocatorStats {
+    /// Pools the allocator holds, whether full or not
+    pub pools: usize,
+    /// Pools that failed an allocation since they were last cleared
+    pub full_pools: usize,
+    /// Sets the next pool will be created with
+    pub sets_per_pool: u32,
+    /// Sets allocated since the pools were last cleared
+    pub sets_allocated: u64,
+    /// Pools created since the pools were last cleared,
+    /// more than one means the allocator ran out of ready pools
+    pub pools_created: u64,
+    /// Allocations that failed with ERROR_FRAGMENTED_POOL
+    /// since the pools were last cleared
+    pub fragmented_pool: u64,
+}
+
+/// Allocates descriptor sets from pools that grow by 50% each time
+/// they run out, up to `max_sets_per_pool`.
+/// Pools are created on the first allocation, so registered layouts
+/// can still raise the pool ratios until then.
 #[derive(Debug)]
 pub struct DescriptorAllocator {
     pool_ratios: Vec<PoolSizeRatio>, // Needed to reallocate pools
     full_pools: Vec<vk::DescriptorPool>, // Pools that cannot allocate more sets
     ready_pools: Vec<vk::DescriptorPool>, // Pools that can allocate more sets
     sets_per_pool: u32,
+    max_sets_per_pool: u32,
+    stats: DescriptorAllocatorStats,
 }
 
 impl DescriptorAllocator {
-    pub fn new(device: &ash::Device, max_sets: u32) -> Result<Self> {
-        let pool_ratios = [
-            PoolSizeRatio {
-                desc_type: vk::DescriptorType::STORAGE_IMAGE,
-                ratio: 3.0,
-            },
-            PoolSizeRatio {
-                desc_type: vk::DescriptorType::STORAGE_BUFFER,
-                ratio: 3.0,
-            },
-            PoolSizeRatio {
-                desc_type: vk::DescriptorType::UNIFORM_BUFFER,
-                ratio: 3.0,
-            },
-            PoolSizeRatio {
-                desc_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                ratio: 4.0,
-            },
-        ];
-
-        // Allocate the first descriptor pool and add it to ready_pools
-        let new_pool = Self::create_pool(device, max_sets, &pool_ratios)?;
-        let ready_pools = vec![new_pool];
-        // Incrase number of sets per pool by 50% for the next pool allocation
-        let sets_per_pool = (max_sets as f32 * 1.5) as u32;
-
-        Ok(Self {
-            pool_ratios: pool_ratios.to_vec(),
+    /// The first pool has room for `max_sets` sets
+    pub fn new(max_sets: u32) -> Self {
+        Self {
+            pool_ratios: DEFAULT_POOL_RATIOS.to_vec(),
             full_pools: Vec::new(),
-            ready_pools,
-            sets_per_pool,
-        })
+            ready_pools: Vec::new(),
+            sets_per_pool: max_sets,
+            max_sets_per_pool: DEFAULT_MAX_SETS_PER_POOL,
+            stats: DescriptorAllocatorStats::default(),
+        }
+    }
+
+    pub fn max_sets_per_pool(mut self, max_sets: u32) -> Self {
+        self.max_sets_per_pool = max_sets;
+        self.sets_per_pool = self.sets_per_pool.min(max_sets);
+        self
+    }
+
+    /// Raise the pool ratios so that every set of the layout fits.
+    /// Only pools created afterwards have room for it.
+    pub fn register_layout(&mut self, bindings: &[DescriptorBinding]) {
+        let mut needed = Vec::<PoolSizeRatio>::new();
+        for binding in bindings {
+            // Runtime arrays in shaders have no fixed count
+            let count = binding.count.max(1) as f32;
+            match needed.iter_mut().find(|r| r.desc_type == binding.desc_type) {
+                Some(ratio) => ratio.ratio += count,
+                None => needed.push(PoolSizeRatio {
+                    desc_type: binding.desc_type,
+                    ratio: count,
+                }),
+            }
+        }
+
+        for needed in needed {
+            match self
+                .pool_ratios
+                .iter_mut()
+                .find(|r| r.desc_type == needed.desc_type)
+            {
+                Some(ratio) => ratio.ratio = ratio.ratio.max(needed.ratio),
+                None => self.pool_ratios.push(needed),
+            }
+        }
+    }
+
+    pub fn stats(&self) -> DescriptorAllocatorStats {
+        DescriptorAllocatorStats {
+            pools: self.ready_pools.len() + self.full_pools.len(),
+            full_pools: self.full_pools.len(),
+            sets_per_pool: self.sets_per_pool,
+            ..self.stats
+        }
     }
 
     pub fn allocate(
@@ -118,6 +365,9 @@ impl DescriptorAllocator {
                 if err == vk::Result::ERROR_OUT_OF_POOL_MEMORY
                     || err == vk::Result::ERROR_FRAGMENTED_POOL
                 {
+                    if err == vk::Result::ERROR_FRAGMENTED_POOL {
+                        self.stats.fragmented_pool += 1;
+                    }
                     self.full_pools.push(pool_to_use);
                     pool_to_use = self.get_pool(device)?;
                     alloc_info.descriptor_pool = pool_to_use;
@@ -131,6 +381,7 @@ impl DescriptorAllocator {
             }
         }?;
         self.ready_pools.push(pool_to_use);
+        self.stats.sets_allocated += 1;
 
         Ok(desc_set)
     }
@@ -154,10 +405,16 @@ impl DescriptorAllocator {
                 self.ready_pools.push(pool);
             }
         }
+        self.reset_stats();
 
         Ok(())
     }
 
+    /// Counters start over whenever the pools are cleared or destroyed
+    fn reset_stats(&mut self) {
+        self.stats = DescriptorAllocatorStats::default();
+    }
+
     pub fn destroy_pools(&mut self, device: &ash::Device) {
         for pool in self.ready_pools.drain(..) {
             unsafe {
@@ -170,6 +427,7 @@ impl DescriptorAllocator {
                 device.destroy_descriptor_pool(pool, None);
             }
         }
+        self.reset_stats();
     }
 
     pub fn cleanup(mut self, device: &ash::Device) {
@@ -185,12 +443,13 @@ impl DescriptorAllocator {
                 device,
                 self.sets_per_pool,
                 &self.pool_ratios,
-            );
+            )?;
+            self.stats.pools_created += 1;
 
-            // Increase number of sets per pool
+            // Increase number of sets per pool by 50% for the next pool
             let sets_per_pool = (self.sets_per_pool as f32 * 1.5) as u32;
-            self.sets_per_pool = sets_per_pool.min(4092); // Limit max sets per pool
-            new_pool
+            self.sets_per_pool = sets_per_pool.min(self.max_sets_per_pool);
+            Ok(new_pool)
         }
     }
 
@@ -203,7 +462,8 @@ impl DescriptorAllocator {
             .iter()
             .map(|ratio| vk::DescriptorPoolSize {
                 ty: ratio.desc_type,
-                descriptor_count: (ratio.ratio * set_count as f32) as u32,
+                descriptor_count: (ratio.ratio * set_count as f32).ceil()
+                    as u32,
             })
             .collect::<Vec<vk::DescriptorPoolSize>>();
 
@@ -216,9 +476,130 @@ impl DescriptorAllocator {
     }
 }
 
+/// Descriptor sets keyed by their layout and the resources written into them.
+/// A set is allocated and written once and then reused,
+/// a changed resource makes a different key and so a new set.
+/// Sets are only freed all at once, so each frame in flight
+/// needs its own cache.
+/// Keys hold raw handles, which Vulkan may reuse once a resource is
+/// destroyed, so the cache is cleared when resources were removed.
+#[derive(Debug)]
+pub struct DescriptorSetCache {
+    allocator: DescriptorAllocator,
+    sets: HashMap<DescriptorSetKey, vk::DescriptorSet>,
+    max_sets: usize,
+    /// `RenderResources::generation` the sets were written in
+    generation: u64,
+}
+
+impl DescriptorSetCache {
+    /// Pools never need more than `max_sets` sets, the cache is trimmed
+    /// before it holds more
+    pub fn new(max_sets: u32) -> Self {
+        Self {
+            allocator: DescriptorAllocator::new(max_sets)
+                .max_sets_per_pool(max_sets),
+            sets: HashMap::new(),
+            max_sets: max_sets as usize,
+            generation: 0,
+        }
+    }
+
+    /// Set of the layout with the writer's resources,
+    /// allocated and written if it isn't cached yet
+    pub fn get_or_write(
+        &mut self,
+        device: &ash::Device,
+        layout: vk::DescriptorSetLayout,
+        writer: &mut DescriptorWriter,
+    ) -> Result<vk::DescriptorSet> {
+        let key = DescriptorSetKey {
+            layout,
+            writes: writer.write_keys(),
+        };
+        if let Some(desc_set) = self.sets.get(&key) {
+            return Ok(*desc_set);
+        }
+
+        let desc_set = self.allocator.allocate(device, layout)?;
+        writer.update_set(device, desc_set);
+        self.sets.insert(key, desc_set);
+        Ok(desc_set)
+    }
+
+    /// Free every set once sets of resources that are gone have piled up,
+    /// or once resources were removed since the sets were written.
+    /// `generation` is the current `RenderResources::generation`.
+    /// None of the sets may still be in use by the GPU.
+    pub fn trim(
+        &mut self,
+        device: &ash::Device,
+        generation: u64,
+    ) -> Result<()> {
+        if self.is_stale(generation) {
+            self.sets.clear();
+            self.allocator.clear_pools(device)?;
+        }
+        self.generation = generation;
+        Ok(())
+    }
+
+    fn is_stale(&self, generation: u64) -> bool {
+        self.sets.len() >= self.max_sets
+            || (generation != self.generation && !self.sets.is_empty())
+    }
+
+    pub fn stats(&self) -> DescriptorAllocatorStats {
+        self.allocator.stats()
+    }
+
+    pub fn cleanup(self, device: &ash::Device) {
+        self.allocator.cleanup(device);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DescriptorSetKey {
+    layout: vk::DescriptorSetLayout,
+    writes: Vec<DescriptorWriteKey>,
+}
+
+/// A single descriptor written by a `DescriptorWriter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DescriptorWriteKey {
+    binding: u32,
+    array_element: u32,
+    desc_type: vk::DescriptorType,
+    resource: DescriptorResource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum DescriptorResource {
+    Buffer {
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize,
+    },
+    Image {
+        image_view: vk::ImageView,
+        sampler: vk::Sampler,
+        layout: vk::ImageLayout,
+    },
+}
+
+/// Binding and array element a descriptor is written to
+#[derive(Debug, Clone, Copy)]
+struct DescriptorSlot {
+    binding: u32,
+    array_element: u32,
+    desc_type: vk::DescriptorType,
+}
+
+/// Only keeps the descriptor infos, since `vk::WriteDescriptorSet` holds
+/// pointers to them and would make everything holding a writer !Send
 pub struct DescriptorWriter {
-    image_infos: Vec<(vk::DescriptorImageInfo, vk::WriteDescriptorSet)>,
-    buffer_infos: Vec<(vk::DescriptorBufferInfo, vk::WriteDescriptorSet)>,
+    image_infos: Vec<(vk::DescriptorImageInfo, DescriptorSlot)>,
+    buffer_infos: Vec<(vk::DescriptorBufferInfo, DescriptorSlot)>,
 }
 
 impl DescriptorWriter {
@@ -242,20 +623,20 @@ impl DescriptorWriter {
             offset,
             range: size,
         };
-        let write = vk::WriteDescriptorSet {
-            dst_binding: binding,
-            dst_set: vk::DescriptorSet::null(), // Filled in later
-            descriptor_count: 1,
-            descriptor_type: desc_type,
-            p_buffer_info: std::ptr::null(), // Filled in later
-            ..Default::default()
+        let slot = DescriptorSlot {
+            binding,
+            array_element: 0,
+            desc_type,
         };
-        self.buffer_infos.push((buffer_info, write));
+        self.buffer_infos.push((buffer_info, slot));
     }
 
-    pub fn write_image(
+    /// Write a single element of an array binding,
+    /// element 0 of bindings that aren't arrays
+    pub fn write_image_element(
         &mut self,
         binding: u32,
+        array_element: u32,
         image_view: vk::ImageView,
         sampler: vk::Sampler,
         layout: vk::ImageLayout,
@@ -266,15 +647,12 @@ impl DescriptorWriter {
             image_view,
             image_layout: layout,
         };
-        let write = vk::WriteDescriptorSet {
-            dst_binding: binding,
-            dst_set: vk::DescriptorSet::null(), // Filled in later
-            descriptor_count: 1,
-            descriptor_type: desc_type,
-            p_image_info: std::ptr::null(), // Filled in later
-            ..Default::default()
+        let slot = DescriptorSlot {
+            binding,
+            array_element,
+            desc_type,
         };
-        self.image_infos.push((image_info, write));
+        self.image_infos.push((image_info, slot));
     }
 
     pub fn clear(&mut self) {
@@ -282,25 +660,355 @@ impl DescriptorWriter {
         self.image_infos.clear();
     }
 
+    /// Identifies the written resources,
+    /// buffers in the order they were written followed by images
+    fn write_keys(&self) -> Vec<DescriptorWriteKey> {
+        let buffers =
+            self.buffer_infos
+                .iter()
+                .map(|(info, slot)| DescriptorWriteKey {
+                    binding: slot.binding,
+                    array_element: slot.array_element,
+                    desc_type: slot.desc_type,
+                    resource: DescriptorResource::Buffer {
+                        buffer: info.buffer,
+                        offset: info.offset,
+                        range: info.range,
+                    },
+                });
+        let images =
+            self.image_infos
+                .iter()
+                .map(|(info, slot)| DescriptorWriteKey {
+                    binding: slot.binding,
+                    array_element: slot.array_element,
+                    desc_type: slot.desc_type,
+                    resource: DescriptorResource::Image {
+                        image_view: info.image_view,
+                        sampler: info.sampler,
+                        layout: info.image_layout,
+                    },
+                });
+        buffers.chain(images).collect()
+    }
+
     pub fn update_set(
-        &mut self,
+        &self,
         device: &ash::Device,
         desc_set: vk::DescriptorSet,
     ) {
-        let mut writes = Vec::new();
+        let writes = self.writes(desc_set);
+        unsafe { device.update_descriptor_sets(&writes, &[]) }
+    }
 
-        for (buffer_info, write) in self.buffer_infos.iter_mut() {
-            write.dst_set = desc_set;
-            write.p_buffer_info = buffer_info;
-            writes.push(*write);
+    /// Record the writes into the command buffer instead of a set.
+    /// `set` of the pipeline layout must have a push descriptor layout.
+    pub fn push_set(
+        &self,
+        push_descriptor: &ash::extensions::khr::PushDescriptor,
+        cmd: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        pipeline_layout: vk::PipelineLayout,
+        set: u32,
+    ) {
+        // Pushed writes have no destination set
+        let writes = self.writes(vk::DescriptorSet::null());
+        unsafe {
+            push_descriptor.cmd_push_descriptor_set(
+                cmd,
+                bind_point,
+                pipeline_layout,
+                set,
+                &writes,
+            );
         }
+    }
 
-        for (image_info, write) in self.image_infos.iter_mut() {
-            write.dst_set = desc_set;
-            write.p_image_info = image_info;
-            writes.push(*write);
+    /// The writes point into `self`, so they must not outlive it
+    fn writes(
+        &self,
+        desc_set: vk::DescriptorSet,
+    ) -> Vec<vk::WriteDescriptorSet> {
+        let write = |slot: &DescriptorSlot| vk::WriteDescriptorSet {
+            dst_set: desc_set,
+            dst_binding: slot.binding,
+            dst_array_element: slot.array_element,
+            descriptor_count: 1,
+            descriptor_type: slot.desc_type,
+            ..Default::default()
+        };
+        let buffers = self.buffer_infos.iter().map(|(info, slot)| {
+            vk::WriteDescriptorSet {
+                p_buffer_info: info,
+                ..write(slot)
+            }
+        });
+        let images = self.image_infos.iter().map(|(info, slot)| {
+            vk::WriteDescriptorSet {
+                p_image_info: info,
+                ..write(slot)
+            }
+        });
+        buffers.chain(images).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::{self, Handle};
+
+    use super::*;
+
+    fn write_buffer(writer: &mut DescriptorWriter, binding: u32, buffer: u64) {
+        writer.write_buffer(
+            binding,
+            vk::Buffer::from_raw(buffer),
+            64,
+            0,
+            vk::DescriptorType::UNIFORM_BUFFER,
+        );
+    }
+
+    fn write_image(writer: &mut DescriptorWriter, binding: u32, view: u64) {
+        writer.write_image_element(
+            binding,
+            0,
+            vk::ImageView::from_raw(view),
+            vk::Sampler::from_raw(1),
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        );
+    }
+
+    fn ratio(
+        allocator: &DescriptorAllocator,
+        desc_type: vk::DescriptorType,
+    ) -> Option<f32> {
+        allocator
+            .pool_ratios
+            .iter()
+            .find(|ratio| ratio.desc_type == desc_type)
+            .map(|ratio| ratio.ratio)
+    }
+
+    fn binding(
+        binding: u32,
+        desc_type: vk::DescriptorType,
+        count: u32,
+    ) -> DescriptorBinding {
+        DescriptorBinding {
+            binding,
+            desc_type,
+            count,
+            stages: vk::ShaderStageFlags::FRAGMENT,
         }
+    }
 
-        unsafe { device.update_descriptor_sets(&writes, &[]) }
+    #[test]
+    fn test_register_layout() {
+        let mut allocator = DescriptorAllocator::new(10);
+        allocator.register_layout(&[
+            binding(0, vk::DescriptorType::UNIFORM_BUFFER, 1),
+            binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 3),
+            binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 4),
+            // Runtime arrays count as one descriptor
+            binding(3, vk::DescriptorType::STORAGE_BUFFER, 0),
+            binding(4, vk::DescriptorType::INPUT_ATTACHMENT, 2),
+        ]);
+
+        // Defaults that are high enough are kept
+        assert_eq!(
+            ratio(&allocator, vk::DescriptorType::UNIFORM_BUFFER),
+            Some(3.0)
+        );
+        assert_eq!(
+            ratio(&allocator, vk::DescriptorType::STORAGE_BUFFER),
+            Some(3.0)
+        );
+        // Bindings of a type add up
+        assert_eq!(
+            ratio(&allocator, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            Some(7.0)
+        );
+        assert_eq!(
+            ratio(&allocator, vk::DescriptorType::INPUT_ATTACHMENT),
+            Some(2.0)
+        );
+
+        // Ratios are never lowered
+        allocator.register_layout(&[binding(
+            0,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            1,
+        )]);
+        assert_eq!(
+            ratio(&allocator, vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            Some(7.0)
+        );
+    }
+
+    #[test]
+    fn test_allocator_stats() {
+        let allocator = DescriptorAllocator::new(100).max_sets_per_pool(50);
+        assert_eq!(
+            allocator.stats(),
+            DescriptorAllocatorStats {
+                sets_per_pool: 50,
+                ..Default::default()
+            }
+        );
+
+        let mut allocator = DescriptorAllocator::new(100);
+        allocator.stats.sets_allocated = 3;
+        allocator.stats.pools_created = 2;
+        allocator.stats.fragmented_pool = 1;
+        let stats = allocator.stats();
+        assert_eq!(stats.sets_per_pool, 100);
+        assert_eq!(
+            (
+                stats.sets_allocated,
+                stats.pools_created,
+                stats.fragmented_pool
+            ),
+            (3, 2, 1)
+        );
+
+        // Clearing or destroying pools resets every counter
+        allocator.reset_stats();
+        assert_eq!(
+            allocator.stats(),
+            DescriptorAllocatorStats {
+                sets_per_pool: 100,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_layout_key() {
+        let uniform = |builder: DescriptorSetLayoutBuilder, binding| {
+            builder.add_binding(
+                binding,
+                vk::DescriptorType::UNIFORM_BUFFER,
+                vk::ShaderStageFlags::VERTEX,
+            )
+        };
+        let sampler = |builder: DescriptorSetLayoutBuilder, binding| {
+            builder.add_binding(
+                binding,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::ShaderStageFlags::FRAGMENT,
+            )
+        };
+        let key = |builder: DescriptorSetLayoutBuilder| builder.key().unwrap();
+
+        // The same bindings in another order share a layout
+        let layout =
+            key(sampler(uniform(DescriptorSetLayoutBuilder::new(), 0), 1));
+        assert_eq!(
+            key(uniform(sampler(DescriptorSetLayoutBuilder::new(), 1), 0)),
+            layout
+        );
+
+        // Other bindings or flags need a layout of their own
+        assert_ne!(
+            key(sampler(uniform(DescriptorSetLayoutBuilder::new(), 0), 2)),
+            layout
+        );
+        assert_ne!(
+            key(sampler(
+                uniform(DescriptorSetLayoutBuilder::new().push_descriptor(), 0),
+                1
+            )),
+            layout
+        );
+        let bindless = key(uniform(DescriptorSetLayoutBuilder::new(), 0)
+            .add_bindless_binding(
+                1,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                1,
+                vk::ShaderStageFlags::FRAGMENT,
+            ));
+        assert_ne!(bindless, layout);
+        assert!(bindless.flags.contains(
+            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL
+        ));
+
+        // Bindless bindings can't be pushed
+        assert!(uniform(
+            DescriptorSetLayoutBuilder::new().push_descriptor(),
+            0
+        )
+        .add_bindless_binding(
+            1,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            1,
+            vk::ShaderStageFlags::FRAGMENT,
+        )
+        .key()
+        .is_err());
+    }
+
+    #[test]
+    fn test_write_keys() {
+        let mut writer = DescriptorWriter::new();
+        write_image(&mut writer, 1, 10);
+        write_buffer(&mut writer, 0, 20);
+        write_buffer(&mut writer, 2, 30);
+
+        // Buffers come first, each kind in the order it was written
+        let keys = writer.write_keys();
+        let bindings = keys.iter().map(|key| key.binding).collect::<Vec<_>>();
+        assert_eq!(bindings, [0, 2, 1]);
+        assert_eq!(
+            keys[2].resource,
+            DescriptorResource::Image {
+                image_view: vk::ImageView::from_raw(10),
+                sampler: vk::Sampler::from_raw(1),
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            }
+        );
+
+        // The same resources make the same keys
+        let mut same = DescriptorWriter::new();
+        write_buffer(&mut same, 0, 20);
+        write_image(&mut same, 1, 10);
+        write_buffer(&mut same, 2, 30);
+        assert_eq!(same.write_keys(), keys);
+
+        // Another resource makes another key
+        let mut other = DescriptorWriter::new();
+        write_image(&mut other, 1, 11);
+        write_buffer(&mut other, 0, 20);
+        write_buffer(&mut other, 2, 30);
+        assert_ne!(other.write_keys(), keys);
+
+        writer.clear();
+        assert!(writer.write_keys().is_empty());
+    }
+
+    #[test]
+    fn test_set_cache_is_stale() {
+        let mut cache = DescriptorSetCache::new(2);
+        let insert = |cache: &mut DescriptorSetCache, layout: u64| {
+            let key = DescriptorSetKey {
+                layout: vk::DescriptorSetLayout::from_raw(layout),
+                writes: Vec::new(),
+            };
+            cache.sets.insert(key, vk::DescriptorSet::from_raw(layout));
+        };
+
+        // Nothing to free in an empty cache
+        assert!(!cache.is_stale(0));
+        assert!(!cache.is_stale(1));
+
+        insert(&mut cache, 1);
+        assert!(!cache.is_stale(0));
+        // Resources were removed since the set was written
+        assert!(cache.is_stale(1));
+
+        // Full
+        insert(&mut cache, 2);
+        assert!(cache.is_stale(0));
     }
 }
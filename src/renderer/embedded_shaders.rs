@@ -0,0 +1,11 @@
+// Table of every .spv file the build script wrote to the shader build
+// directory, generated when the embed-shaders feature is enabled
+include!(concat!(env!("OUT_DIR"), "/embedded_shaders.rs"));
+
+/// SPIR-V of a file of the shader build directory, as it was at build time
+pub fn spirv(filename: &str) -> Option<&'static [u8]> {
+    EMBEDDED_SHADERS
+        .iter()
+        .find(|(name, _)| *name == filename)
+        .map(|(_, spv)| *spv)
+}
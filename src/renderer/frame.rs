@@ -1,33 +1,50 @@
 use ash::vk;
 use bevy::log;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{OptionExt, Result};
+use glam::{Mat4, Vec3};
 use gpu_allocator::vulkan::Allocator;
 
 use crate::renderer::buffer::AllocatedBuffer;
 
 use super::{
+    attachments::RenderAttachments,
     context::Context,
-    descriptors::{DescriptorAllocator, DescriptorWriter},
-    gpu_data::{GpuCameraData, GpuSceneData},
-    inner::DrawContext,
+    debug_view::DebugView,
+    descriptors::{
+        DescriptorAllocatorStats, DescriptorSetCache, DescriptorWriter,
+    },
+    gpu_data::{
+        GpuCameraData, GpuDrawPushConstants, GpuSceneData,
+        GpuTexturedPushConstants,
+    },
+    inner::{DrawContext, DrawTarget},
+    swapchain::Swapchain,
     texture::Texture,
     vkutils,
 };
 
+/// Render texture shown on the monitor quad next to the backpack
+pub const MONITOR_TEXTURE: &str = "monitor";
+/// Compute material that fills the background of every window
+pub const BACKGROUND_MATERIAL: &str = "background";
+/// Workgroup size of the background material, set through its
+/// specialization constants and used to size its dispatches
+pub const BACKGROUND_WORKGROUP_SIZE: [u32; 2] = [16, 16];
+
 #[derive(Debug)]
 pub struct Frame {
     present_semaphore: vk::Semaphore, // Signals when the swapchain is ready to present
     render_semaphore: vk::Semaphore,  // Signals when rendering is done
     render_fence: vk::Fence, // Signals when rendering commands all get executed
     command_buffer: vk::CommandBuffer,
-    desc_allocator: DescriptorAllocator,
+    desc_cache: DescriptorSetCache,
 
     scene_buffer: AllocatedBuffer,
 }
 
 impl Frame {
     pub fn new(
-        ctx: &mut Context,
+        ctx: &Context,
         allocator: &mut Allocator,
         command_pool: &vk::CommandPool,
     ) -> Result<Self> {
@@ -39,8 +56,8 @@ impl Frame {
         let (present_semaphore, render_semaphore, render_fence) =
             Self::create_sync_objs(&ctx.device)?;
 
-        // Create descriptor allocator exclusive to this frame
-        let desc_allocator = DescriptorAllocator::new(&ctx.device, 1000)?;
+        // Create descriptor set cache exclusive to this frame
+        let desc_cache = DescriptorSetCache::new(1000);
 
         // Allocate a new uniform buffer for the scene data
         let scene_buffer = AllocatedBuffer::new(
@@ -57,7 +74,7 @@ impl Frame {
             render_semaphore,
             render_fence,
             command_buffer,
-            desc_allocator,
+            desc_cache,
 
             scene_buffer,
         })
@@ -73,21 +90,17 @@ impl Frame {
             ctx.context.device.reset_fences(&fences)?;
         }
 
-        self.desc_allocator.clear_pools(&ctx.context.device)?;
-
-        // Create a descriptor set for the scene buffer
-        let scene_desc_set = self.desc_allocator.allocate(
-            &ctx.context.device,
-            ctx.resources.lock().unwrap().desc_set_layouts["scene buffer"],
-        )?;
+        // The GPU is done with this frame's sets
+        let generation = ctx.resources.lock().unwrap().generation();
+        self.desc_cache.trim(&ctx.context.device, generation)?;
 
         // Write to the buffer
+        let extent = ctx.target.extent();
         let scene_data = GpuSceneData {
             cam_data: GpuCameraData {
-                viewproj: ctx.camera.viewproj_mat(
-                    ctx.swapchain.image_extent.width as f32,
-                    ctx.swapchain.image_extent.height as f32,
-                ),
+                viewproj: ctx
+                    .camera
+                    .viewproj_mat(extent.width as f32, extent.height as f32),
                 near: ctx.camera.near,
                 far: ctx.camera.far,
             },
@@ -95,22 +108,51 @@ impl Frame {
         };
         self.scene_buffer.write(&[scene_data], 0)?;
 
-        // Update the scene descriptor set with the updated scene buffer
-        let mut writer = DescriptorWriter::new();
-        writer.write_buffer(
+        // Pushed into set 0 of every material,
+        // or written into a set of the cache without push descriptors
+        let mut scene_writer = DescriptorWriter::new();
+        scene_writer.write_buffer(
             0,
             self.scene_buffer.buffer,
             self.scene_buffer.size,
             0,
             vk::DescriptorType::UNIFORM_BUFFER,
         );
-        writer.update_set(&ctx.context.device, scene_desc_set);
 
+        match ctx.target.clone() {
+            DrawTarget::Window {
+                swapchain,
+                background_texture,
+            } => self.draw_to_window(
+                &mut ctx,
+                &swapchain,
+                &mut background_texture.lock().unwrap(),
+                &mut scene_writer,
+            ),
+            DrawTarget::Texture {
+                name, attachments, ..
+            } => self.draw_to_texture(
+                &mut ctx,
+                &name,
+                &attachments,
+                &mut scene_writer,
+            ),
+        }
+    }
+
+    /// Draw the scene into the next swapchain image and present it
+    fn draw_to_window(
+        &mut self,
+        ctx: &mut DrawContext,
+        swapchain: &Swapchain,
+        background_texture: &mut Texture,
+        scene_writer: &mut DescriptorWriter,
+    ) -> Result<()> {
         // Request image from swapchain (1 sec timeout)
         let swapchain_image_index = unsafe {
             let (index, suboptimal) =
-                ctx.swapchain.swapchain_loader.acquire_next_image(
-                    ctx.swapchain.swapchain,
+                swapchain.swapchain_loader.acquire_next_image(
+                    swapchain.swapchain,
                     1000000000,
                     self.present_semaphore,
                     vk::Fence::null(),
@@ -120,37 +162,136 @@ impl Frame {
             }
             index
         };
+        let swapchain_image = swapchain.images[swapchain_image_index as usize];
 
         //----------------------------------------------------------------------
         let cmd = self.command_buffer;
-        self.begin_command_buffer(cmd, &ctx)?;
+        self.begin_command_buffer(cmd, ctx)?;
         //----------------------------------------------------------------------
 
-        // Compute operations
-        self.draw_background(cmd, &ctx)?;
-        self.copy_background_texture_to_swapchain(
+        let color_load_op = if swapchain.attachments.msaa_color_image.is_some()
+        {
+            // Nothing can be copied into a multisampled image,
+            // so the pass clears it instead of drawing the background
+            vkutils::transition_image_layout(
+                cmd,
+                swapchain_image,
+                vk::ImageAspectFlags::COLOR,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                &ctx.context.device,
+            );
+            vk::AttachmentLoadOp::CLEAR
+        } else {
+            // Compute operations
+            self.draw_background(cmd, ctx, background_texture)?;
+            self.copy_background_texture_to_swapchain(
+                cmd,
+                &ctx.context.device,
+                background_texture,
+                swapchain_image,
+                swapchain.image_extent,
+            );
+            vk::AttachmentLoadOp::LOAD
+        };
+
+        // Render operations
+        self.begin_renderpass(
+            cmd,
+            ctx,
+            swapchain.image_views[swapchain_image_index as usize],
+            color_load_op,
+            &swapchain.attachments,
+            swapchain.image_extent,
+        );
+        self.draw_geometry(cmd, ctx, scene_writer)?;
+        self.draw_monitor(cmd, ctx, scene_writer)?;
+        self.draw_grid(cmd, ctx, scene_writer)?;
+        self.draw_outlines(cmd, ctx, scene_writer)?;
+        self.end_renderpass(cmd, ctx);
+        vkutils::transition_image_layout(
             cmd,
+            swapchain_image,
+            vk::ImageAspectFlags::COLOR,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            vk::ImageLayout::PRESENT_SRC_KHR,
             &ctx.context.device,
-            &mut ctx.background_texture.lock().unwrap(),
-            ctx.swapchain.images[swapchain_image_index as usize],
-            ctx.swapchain.image_extent,
         );
 
-        // Render operations
-        self.begin_renderpass(swapchain_image_index, cmd, &ctx);
-        self.set_viewport_scissor(
+        //----------------------------------------------------------------------
+        self.end_command_buffer(cmd, ctx, true)?;
+        self.present(swapchain_image_index, swapchain, ctx)?;
+        //----------------------------------------------------------------------
+
+        Ok(())
+    }
+
+    /// Draw the scene into an offscreen texture.
+    /// The texture is left in SHADER_READ_ONLY_OPTIMAL so that materials
+    /// drawn in later submissions can sample it.
+    fn draw_to_texture(
+        &mut self,
+        ctx: &mut DrawContext,
+        texture_name: &str,
+        attachments: &RenderAttachments,
+        scene_writer: &mut DescriptorWriter,
+    ) -> Result<()> {
+        let (color_image, color_view, extent) = {
+            let resources = ctx.resources.lock().unwrap();
+            let texture = resources
+                .textures
+                .get(texture_name)
+                .ok_or_eyre("Render texture not found")?;
+            (
+                texture.image().image,
+                texture.image().view,
+                vk::Extent2D {
+                    width: texture.width(),
+                    height: texture.height(),
+                },
+            )
+        };
+
+        //----------------------------------------------------------------------
+        let cmd = self.command_buffer;
+        self.begin_command_buffer(cmd, ctx)?;
+        //----------------------------------------------------------------------
+
+        // Previous contents are cleared anyway, so discard them
+        vkutils::transition_image_layout(
+            cmd,
+            color_image,
+            vk::ImageAspectFlags::COLOR,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            &ctx.context.device,
+        );
+
+        self.begin_renderpass(
+            cmd,
+            ctx,
+            color_view,
+            vk::AttachmentLoadOp::CLEAR,
+            attachments,
+            extent,
+        );
+        self.draw_geometry(cmd, ctx, scene_writer)?;
+        self.draw_monitor(cmd, ctx, scene_writer)?;
+        self.draw_grid(cmd, ctx, scene_writer)?;
+        self.draw_outlines(cmd, ctx, scene_writer)?;
+        self.end_renderpass(cmd, ctx);
+
+        vkutils::transition_image_layout(
             cmd,
+            color_image,
+            vk::ImageAspectFlags::COLOR,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
             &ctx.context.device,
-            ctx.swapchain.image_extent.width,
-            ctx.swapchain.image_extent.height,
         );
-        self.draw_geometry(cmd, &mut ctx, scene_desc_set)?;
-        self.draw_grid(cmd, &ctx, scene_desc_set)?;
-        self.end_renderpass(swapchain_image_index, cmd, &ctx);
 
         //----------------------------------------------------------------------
-        self.end_command_buffer(cmd, &ctx)?;
-        self.present(swapchain_image_index, &ctx)?;
+        self.end_command_buffer(cmd, ctx, false)?;
         //----------------------------------------------------------------------
 
         Ok(())
@@ -159,10 +300,11 @@ impl Frame {
     fn present(
         &self,
         swapchain_image_index: u32,
+        swapchain: &Swapchain,
         ctx: &DrawContext,
     ) -> Result<()> {
         let present_info = vk::PresentInfoKHR {
-            p_swapchains: &ctx.swapchain.swapchain,
+            p_swapchains: &swapchain.swapchain,
             swapchain_count: 1,
             p_wait_semaphores: &self.render_semaphore, // Wait until rendering is done before presenting
             wait_semaphore_count: 1,
@@ -170,97 +312,193 @@ impl Frame {
             ..Default::default()
         };
         unsafe {
-            ctx.swapchain
+            swapchain
                 .swapchain_loader
                 .queue_present(ctx.context.present_queue, &present_info)?;
         }
         Ok(())
     }
 
-    /// Call this function AFTER starting a renderpass
+    /// Fill the background texture with the background material
     fn draw_background(
         &mut self,
         cmd: vk::CommandBuffer,
         ctx: &DrawContext,
+        background_texture: &mut Texture,
     ) -> Result<()> {
-        let mut background_texture = ctx.background_texture.lock().unwrap();
+        let resources = ctx.resources.lock().unwrap();
+        let device = &ctx.context.device;
+        let background_mat = resources
+            .materials
+            .get(BACKGROUND_MATERIAL)
+            .ok_or_eyre("Background material not found")?;
+
+        // Every texel is written, so previous contents can be discarded
         background_texture.image_mut().transition_layout(
             cmd,
             vk::ImageLayout::UNDEFINED,
             vk::ImageLayout::GENERAL,
-            &ctx.context.device,
+            device,
         );
 
+        let mut writer = DescriptorWriter::new();
+        writer.write_image_element(
+            0,
+            0,
+            background_texture.image().view,
+            vk::Sampler::null(),
+            vk::ImageLayout::GENERAL,
+            vk::DescriptorType::STORAGE_IMAGE,
+        );
+        background_mat.bind_pipeline(cmd, &ctx.context);
+        background_mat.push_desc_set(
+            cmd,
+            &ctx.context,
+            0,
+            &mut writer,
+            &mut self.desc_cache,
+        )?;
+
+        let [group_width, group_height] = BACKGROUND_WORKGROUP_SIZE;
         unsafe {
-            ctx.context.device.cmd_clear_color_image(
+            device.cmd_dispatch(
                 cmd,
-                background_texture.image().image,
-                vk::ImageLayout::GENERAL,
-                &vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-                &[vk::ImageSubresourceRange {
-                    aspect_mask: background_texture.image().aspect,
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                }],
+                background_texture.width().div_ceil(group_width),
+                background_texture.height().div_ceil(group_height),
+                1,
             );
         }
 
         Ok(())
+    }
 
-        /*
-        // Execute the compute pipeline dispatch
-        // The gradient compute shader uses a 16x16 workgroup, so divide by 16
-        // The compute shader will write to the draw image
-        unsafe {
-            self.core.device.cmd_dispatch(
+    /// Call this function AFTER starting a renderpass
+    pub fn draw_geometry(
+        &mut self,
+        cmd: vk::CommandBuffer,
+        ctx: &mut DrawContext,
+        scene_writer: &mut DescriptorWriter,
+    ) -> Result<()> {
+        let resources = ctx.resources.lock().unwrap();
+        let device = &ctx.context.device;
+
+        let backpack_instance = &resources.material_instances["backpack"];
+        let backpack_model = &resources.models["backpack"];
+        let push_constants = GpuDrawPushConstants::new(
+            Mat4::IDENTITY,
+            backpack_model.vertex_buffer_address()?,
+        );
+
+        // Debug views other than wireframe replace the regular materials
+        let debug_mat = ctx
+            .debug_view
+            .material_name()
+            .and_then(|name| resources.materials.get(name));
+        if debug_mat.is_none() || ctx.debug_view == DebugView::Wireframe {
+            let backpack_mat =
+                &resources.materials[&backpack_instance.material_name];
+            let bindless_desc_set = resources
+                .bindless_textures
+                .as_ref()
+                .ok_or_eyre("Bindless textures not initialized")?
+                .desc_set();
+            backpack_mat.bind_pipeline(cmd, &ctx.context);
+            backpack_mat.push_desc_set(
                 cmd,
-                (self.background_texture.width() as f64 / 16.0).ceil() as u32,
-                (self.background_texture.height() as f64 / 16.0).ceil() as u32,
+                &ctx.context,
+                0,
+                scene_writer,
+                &mut self.desc_cache,
+            )?;
+            backpack_mat.bind_desc_sets(
+                cmd,
+                device,
                 1,
+                &[backpack_instance.desc_set, bindless_desc_set],
+                &[],
+            );
+            backpack_mat.update_push_constants(
+                cmd,
+                device,
+                vk::ShaderStageFlags::VERTEX,
+                push_constants.as_bytes(),
+            );
+            backpack_model.draw(cmd, device)?;
+        }
+
+        // Debug materials only use the scene descriptor set
+        if let Some(debug_mat) = debug_mat {
+            debug_mat.bind_pipeline(cmd, &ctx.context);
+            debug_mat.push_desc_set(
+                cmd,
+                &ctx.context,
+                0,
+                scene_writer,
+                &mut self.desc_cache,
+            )?;
+            debug_mat.update_push_constants(
+                cmd,
+                device,
+                vk::ShaderStageFlags::VERTEX,
+                push_constants.as_bytes(),
             );
+            backpack_model.draw(cmd, device)?;
         }
-        */
+
+        Ok(())
     }
 
-    /// Call this function AFTER starting a renderpass
-    pub fn draw_geometry(
+    /// Show the monitor render texture on a quad next to the backpack.
+    /// Does nothing until a camera has rendered into the texture,
+    /// or while this frame renders into it.
+    fn draw_monitor(
         &mut self,
         cmd: vk::CommandBuffer,
-        ctx: &mut DrawContext,
-        scene_desc_set: vk::DescriptorSet,
+        ctx: &DrawContext,
+        scene_writer: &mut DescriptorWriter,
     ) -> Result<()> {
-        let resources = ctx.resources.lock().unwrap();
-        let graphics_texture_desc_set = self.desc_allocator.allocate(
-            &ctx.context.device,
-            resources.desc_set_layouts["graphics texture"],
-        )?;
+        if matches!(
+            &ctx.target,
+            DrawTarget::Texture { name, .. } if name == MONITOR_TEXTURE
+        ) {
+            return Ok(());
+        }
 
-        // Update the texture descriptor set
-        let mut writer = DescriptorWriter::new();
-        writer.write_image(
-            0,
-            resources.textures["backpack"].image().view,
-            resources.textures["backpack"].sampler().unwrap(),
-            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        let resources = ctx.resources.lock().unwrap();
+        let device = &ctx.context.device;
+        let (Some(monitor_mat), Ok(texture_index)) = (
+            resources.materials.get("textured"),
+            resources.texture_index(MONITOR_TEXTURE),
+        ) else {
+            return Ok(());
+        };
+        let quad_model = &resources.models["quad"];
+        let bindless_desc_set = resources
+            .bindless_textures
+            .as_ref()
+            .ok_or_eyre("Bindless textures not initialized")?
+            .desc_set();
+        let push_constants = GpuTexturedPushConstants::new(
+            Mat4::from_translation(Vec3::new(3.0, 1.0, 0.0)),
+            texture_index,
         );
-        writer.update_set(&ctx.context.device, graphics_texture_desc_set);
 
-        let monkey_mat = &resources.materials["textured"];
-        let monkey_model = &resources.models["backpack"];
-        monkey_mat.bind_pipeline(cmd, &ctx.context.device);
-        monkey_mat.bind_desc_sets(
+        monitor_mat.bind_pipeline(cmd, &ctx.context);
+        monitor_mat.push_desc_set(
             cmd,
-            &ctx.context.device,
+            &ctx.context,
             0,
-            &[scene_desc_set, graphics_texture_desc_set],
-            &[],
+            scene_writer,
+            &mut self.desc_cache,
+        )?;
+        monitor_mat.bind_desc_sets(cmd, device, 1, &[bindless_desc_set], &[]);
+        monitor_mat.update_push_constants(
+            cmd,
+            device,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            push_constants.as_bytes(),
         );
-        monkey_model.draw(cmd, &ctx.context.device)?;
+        quad_model.draw(cmd, device)?;
 
         Ok(())
     }
@@ -270,25 +508,81 @@ impl Frame {
         &mut self,
         cmd: vk::CommandBuffer,
         ctx: &DrawContext,
-        scene_desc_set: vk::DescriptorSet,
+        scene_writer: &mut DescriptorWriter,
     ) -> Result<()> {
         let resources = ctx.resources.lock().unwrap();
         let grid_mat = &resources.materials["grid"];
         let grid_model = &resources.models["quad"];
 
-        grid_mat.bind_pipeline(cmd, &ctx.context.device);
-        grid_mat.bind_desc_sets(
+        grid_mat.bind_pipeline(cmd, &ctx.context);
+        grid_mat.push_desc_set(
             cmd,
-            &ctx.context.device,
+            &ctx.context,
             0,
-            &[scene_desc_set],
-            &[],
-        );
+            scene_writer,
+            &mut self.desc_cache,
+        )?;
         grid_model.draw(cmd, &ctx.context.device)?;
 
         Ok(())
     }
 
+    /// Outline selected models where the stencil buffer hasn't been marked
+    /// by the model itself, so only the silhouette around it remains.
+    /// Does nothing without a stencil attachment.
+    fn draw_outlines(
+        &mut self,
+        cmd: vk::CommandBuffer,
+        ctx: &DrawContext,
+        scene_writer: &mut DescriptorWriter,
+    ) -> Result<()> {
+        if ctx.selected_models.is_empty()
+            || !vkutils::format_has_stencil(ctx.context.depth_format)
+        {
+            return Ok(());
+        }
+
+        let resources = ctx.resources.lock().unwrap();
+        let device = &ctx.context.device;
+        let (Some(mask_mat), Some(outline_mat)) = (
+            resources.materials.get("outline-mask"),
+            resources.materials.get("outline"),
+        ) else {
+            return Ok(());
+        };
+
+        // Mark every selected model first,
+        // so overlapping outlines don't cover other selected models
+        for material in [mask_mat, outline_mat] {
+            material.bind_pipeline(cmd, &ctx.context);
+            material.push_desc_set(
+                cmd,
+                &ctx.context,
+                0,
+                scene_writer,
+                &mut self.desc_cache,
+            )?;
+            for name in ctx.selected_models {
+                let Some(model) = resources.models.get(name) else {
+                    continue;
+                };
+                let push_constants = GpuDrawPushConstants::new(
+                    Mat4::IDENTITY,
+                    model.vertex_buffer_address()?,
+                );
+                material.update_push_constants(
+                    cmd,
+                    device,
+                    vk::ShaderStageFlags::VERTEX,
+                    push_constants.as_bytes(),
+                );
+                model.draw(cmd, device)?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn begin_command_buffer(
         &self,
         cmd: vk::CommandBuffer,
@@ -316,10 +610,13 @@ impl Frame {
         Ok(())
     }
 
+    /// Offscreen frames are not presented,
+    /// so they neither wait on nor signal the present semaphores
     fn end_command_buffer(
         &self,
         cmd: vk::CommandBuffer,
         ctx: &DrawContext,
+        present: bool,
     ) -> Result<()> {
         unsafe {
             // Finalize the main command buffer
@@ -327,11 +624,12 @@ impl Frame {
 
             // Prepare submission to the graphics queue
             let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+            let semaphore_count = if present { 1 } else { 0 };
             let submit_info = vk::SubmitInfo {
                 p_wait_dst_stage_mask: wait_stages.as_ptr(),
-                wait_semaphore_count: 1,
+                wait_semaphore_count: semaphore_count,
                 p_wait_semaphores: &self.present_semaphore, // Wait for presentation to finish
-                signal_semaphore_count: 1,
+                signal_semaphore_count: semaphore_count,
                 p_signal_semaphores: &self.render_semaphore, // Signal rendering is done
                 command_buffer_count: 1,
                 p_command_buffers: &cmd,
@@ -348,25 +646,59 @@ impl Frame {
 
     fn begin_renderpass(
         &self,
-        swapchain_image_index: u32,
         cmd: vk::CommandBuffer,
         ctx: &DrawContext,
+        color_view: vk::ImageView,
+        color_load_op: vk::AttachmentLoadOp,
+        attachments: &RenderAttachments,
+        extent: vk::Extent2D,
     ) {
-        let color_attachments = [vk::RenderingAttachmentInfo::builder()
-            .image_view(
-                ctx.swapchain.image_views[swapchain_image_index as usize],
-            )
+        let depth_image = &attachments.depth_image;
+        // Depth is cleared every pass, so previous contents can be discarded
+        vkutils::transition_image_layout(
+            cmd,
+            depth_image.image,
+            depth_image.aspect,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            &ctx.context.device,
+        );
+
+        let mut color_attachment = vk::RenderingAttachmentInfo::builder()
+            .image_view(color_view)
             .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-            .load_op(vk::AttachmentLoadOp::LOAD)
+            .load_op(color_load_op)
             .store_op(vk::AttachmentStoreOp::STORE)
             .clear_value(vk::ClearValue {
                 color: vk::ClearColorValue {
                     float32: [0.0, 0.0, 0.0, 1.0],
                 },
-            })
-            .build()];
+            });
+        if let Some(msaa_image) = &attachments.msaa_color_image {
+            // Render into the multisampled image and average its samples
+            // into the color target at the end of the pass.
+            // Only the resolved result is kept between passes.
+            vkutils::transition_image_layout(
+                cmd,
+                msaa_image.image,
+                msaa_image.aspect,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                &ctx.context.device,
+            );
+            color_attachment = color_attachment
+                .image_view(msaa_image.view)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+                .resolve_image_view(color_view)
+                .resolve_image_layout(
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                );
+        }
+        let color_attachments = [color_attachment.build()];
         let depth_attachment = vk::RenderingAttachmentInfo::builder()
-            .image_view(ctx.swapchain.depth_image.view)
+            .image_view(depth_image.view)
             .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
             .load_op(vk::AttachmentLoadOp::CLEAR)
             .store_op(vk::AttachmentStoreOp::STORE)
@@ -378,42 +710,38 @@ impl Frame {
             })
             .build();
 
-        let rendering_info = vk::RenderingInfo::builder()
+        let mut rendering_info = vk::RenderingInfo::builder()
             .render_area(vk::Rect2D {
                 offset: vk::Offset2D { x: 0, y: 0 },
-                extent: vk::Extent2D {
-                    width: ctx.swapchain.image_extent.width,
-                    height: ctx.swapchain.image_extent.height,
-                },
+                extent,
             })
             .layer_count(1)
             .color_attachments(&color_attachments)
-            .depth_attachment(&depth_attachment)
-            .build();
+            .depth_attachment(&depth_attachment);
+        // Depth and stencil live in the same image
+        if vkutils::format_has_stencil(depth_image.format) {
+            rendering_info =
+                rendering_info.stencil_attachment(&depth_attachment);
+        }
+        let rendering_info = rendering_info.build();
 
         // Begin a render pass connected to the draw image
         unsafe {
             ctx.context.device.cmd_begin_rendering(cmd, &rendering_info);
         }
+
+        self.set_viewport_scissor(
+            cmd,
+            &ctx.context.device,
+            extent.width,
+            extent.height,
+        );
     }
 
-    fn end_renderpass(
-        &self,
-        swapchain_image_index: u32,
-        cmd: vk::CommandBuffer,
-        ctx: &DrawContext,
-    ) {
+    fn end_renderpass(&self, cmd: vk::CommandBuffer, ctx: &DrawContext) {
         unsafe {
             ctx.context.device.cmd_end_rendering(cmd);
         }
-        vkutils::transition_image_layout(
-            cmd,
-            ctx.swapchain.images[swapchain_image_index as usize],
-            vk::ImageAspectFlags::COLOR,
-            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-            vk::ImageLayout::PRESENT_SRC_KHR,
-            &ctx.context.device,
-        );
     }
 
     pub fn cleanup(self, device: &ash::Device, allocator: &mut Allocator) {
@@ -422,7 +750,7 @@ impl Frame {
             device.destroy_semaphore(self.render_semaphore, None);
             device.destroy_semaphore(self.present_semaphore, None);
             device.destroy_fence(self.render_fence, None);
-            self.desc_allocator.cleanup(device);
+            self.desc_cache.cleanup(device);
         }
     }
 
@@ -430,6 +758,10 @@ impl Frame {
         self.render_fence
     }
 
+    pub fn desc_stats(&self) -> DescriptorAllocatorStats {
+        self.desc_cache.stats()
+    }
+
     /// Helper function that copies the background texture to the specified swapchain image
     fn copy_background_texture_to_swapchain(
         &mut self,
@@ -1,8 +1,56 @@
-// This file contains data structures sent to the GPU
+// This file contains data structures sent to the GPU.
+// Their GLSL declarations in shaders/gpu_data.glsl are generated from the
+// `glsl_struct!` lists by test_gpu_data_glsl_up_to_date,
+// regenerate it with `UPDATE_GPU_DATA_GLSL=1 cargo test gpu_data_glsl`.
 
 use ash::vk;
+use bytemuck::{Pod, Zeroable};
 use glam::{Mat4, Vec3, Vec4};
 
+/// Lists the fields of a struct that shaders see, in declaration order.
+/// Fields that aren't listed are Rust-only padding,
+/// `..field` declares the members of a nested struct in place and
+/// `field as Type` declares a device address as a GLSL buffer reference.
+macro_rules! glsl_struct {
+    ($name:ident { $($members:tt)* }) => {
+        #[cfg(test)]
+        impl glsl::GlslStruct for $name {
+            const NAME: &'static str = stringify!($name);
+
+            fn glsl_members() -> Vec<glsl::GlslMember> {
+                let mut members = Vec::new();
+                glsl_struct!(@members members, $name, $($members)*);
+                members
+            }
+        }
+    };
+    (@members $members:ident, $name:ident, $(,)?) => {};
+    (@members $members:ident, $name:ident, ..$field:ident $(, $($rest:tt)*)?) => {
+        $members.extend(glsl::flatten(
+            std::mem::offset_of!($name, $field),
+            |s: &$name| &s.$field,
+        ));
+        glsl_struct!(@members $members, $name, $($($rest)*)?);
+    };
+    (@members $members:ident, $name:ident, $field:ident as $ty:ident $(, $($rest:tt)*)?) => {
+        $members.push(glsl::buffer_reference(
+            stringify!($field),
+            stringify!($ty),
+            std::mem::offset_of!($name, $field),
+            |s: &$name| &s.$field,
+        ));
+        glsl_struct!(@members $members, $name, $($($rest)*)?);
+    };
+    (@members $members:ident, $name:ident, $field:ident $(, $($rest:tt)*)?) => {
+        $members.push(glsl::member(
+            stringify!($field),
+            std::mem::offset_of!($name, $field),
+            |s: &$name| &s.$field,
+        ));
+        glsl_struct!(@members $members, $name, $($($rest)*)?);
+    };
+}
+
 #[derive(Default, Copy, Clone)]
 #[repr(C)]
 pub struct GpuVertexData {
@@ -13,15 +61,31 @@ pub struct GpuVertexData {
     pub color: Vec4,
 }
 
+glsl_struct!(GpuVertexData {
+    position,
+    uv_x,
+    normal,
+    uv_y,
+    color,
+});
+
 #[derive(Default, Copy, Clone)]
 #[repr(C)]
 pub struct GpuSceneData {
     pub cam_data: GpuCameraData,
     pub ambient_color: Vec4,
+    /// w for sun power
     pub sunlight_direction: Vec4,
     pub sunlight_color: Vec4,
 }
 
+glsl_struct!(GpuSceneData {
+    ..cam_data,
+    ambient_color,
+    sunlight_direction,
+    sunlight_color,
+});
+
 #[derive(Default, Copy, Clone)]
 #[repr(C)]
 pub struct GpuCameraData {
@@ -30,9 +94,441 @@ pub struct GpuCameraData {
     pub far: f32,
 }
 
-#[repr(C)]
+glsl_struct!(GpuCameraData {
+    viewproj,
+    near,
+    far,
+});
+
 /// Push constants for mesh object draws
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
 pub struct GpuDrawPushConstants {
-    world_matrix: Mat4,
-    vertex_buffer: vk::DeviceAddress,
+    pub world_matrix: Mat4,
+    /// Device address of the GpuVertexData buffer
+    pub vertex_buffer: vk::DeviceAddress,
+    padding: u64, // Mat4 is 16-byte aligned
+}
+
+glsl_struct!(GpuDrawPushConstants {
+    world_matrix,
+    vertex_buffer as VertexBuffer,
+});
+
+impl GpuDrawPushConstants {
+    /// Size of the push constant block in the shaders, without the padding
+    pub const SIZE: u32 = (std::mem::size_of::<Mat4>()
+        + std::mem::size_of::<vk::DeviceAddress>())
+        as u32;
+
+    pub fn new(world_matrix: Mat4, vertex_buffer: vk::DeviceAddress) -> Self {
+        Self {
+            world_matrix,
+            vertex_buffer,
+            padding: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &bytemuck::bytes_of(self)[..Self::SIZE as usize]
+    }
+}
+
+/// Push constants for draws with the textured material
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct GpuTexturedPushConstants {
+    pub world_matrix: Mat4,
+    /// Index into the bindless texture array
+    pub texture_index: u32,
+    padding: [u32; 3], // Mat4 is 16-byte aligned
+}
+
+glsl_struct!(GpuTexturedPushConstants {
+    world_matrix,
+    texture_index,
+});
+
+impl GpuTexturedPushConstants {
+    /// Size of the push constant block in the shaders, without the padding
+    pub const SIZE: u32 =
+        (std::mem::size_of::<Mat4>() + std::mem::size_of::<u32>()) as u32;
+
+    /// The texture index comes from `RenderResources::texture_index`
+    pub fn new(world_matrix: Mat4, texture_index: u32) -> Self {
+        Self {
+            world_matrix,
+            texture_index,
+            padding: [0; 3],
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &bytemuck::bytes_of(self)[..Self::SIZE as usize]
+    }
+}
+
+/// To be written into uniform buffers
+#[derive(Default, Copy, Clone)]
+#[repr(C)]
+pub struct MaterialConstants {
+    pub color_factors: Vec4,
+    pub metal_rough_factors: Vec4,
+    /// Index into the bindless texture array
+    pub color_texture: u32,
+    /// Index into the bindless texture array
+    pub metal_rough_texture: u32,
+    padding: [u32; 2],
+    padding2: [Vec4; 13], // Padding to 256 bytes
+}
+
+glsl_struct!(MaterialConstants {
+    color_factors,
+    metal_rough_factors,
+    color_texture,
+    metal_rough_texture,
+});
+
+impl MaterialConstants {
+    /// Texture indices come from `RenderResources::texture_index`
+    pub fn new(
+        color_factors: Vec4,
+        metal_rough_factors: Vec4,
+        color_texture: u32,
+        metal_rough_texture: u32,
+    ) -> Self {
+        Self {
+            color_factors,
+            metal_rough_factors,
+            color_texture,
+            metal_rough_texture,
+            ..Default::default()
+        }
+    }
+}
+
+/// GLSL declarations of the structs shared with shaders
+#[cfg(test)]
+mod glsl {
+    use std::fmt::Write;
+
+    use ash::vk;
+    use glam::{Mat4, Vec2, Vec3, Vec4};
+
+    /// Prefix of the members that match padding of the Rust struct
+    pub const PADDING_PREFIX: &str = "_pad";
+
+    /// A member of a GLSL struct and the offset of its Rust field
+    #[derive(Debug, Clone)]
+    pub struct GlslMember {
+        pub name: &'static str,
+        pub ty: &'static str,
+        pub align: usize,
+        pub size: usize,
+        pub offset: usize,
+    }
+
+    /// GLSL type of a Rust field, with its alignment and size
+    pub trait GlslType {
+        const NAME: &'static str;
+        const ALIGN: usize;
+        const SIZE: usize;
+    }
+
+    macro_rules! glsl_type {
+        ($ty:ty, $name:literal, $align:literal, $size:literal) => {
+            impl GlslType for $ty {
+                const NAME: &'static str = $name;
+                const ALIGN: usize = $align;
+                const SIZE: usize = $size;
+            }
+        };
+    }
+
+    glsl_type!(f32, "float", 4, 4);
+    glsl_type!(u32, "uint", 4, 4);
+    glsl_type!(i32, "int", 4, 4);
+    glsl_type!(Vec2, "vec2", 8, 8);
+    glsl_type!(Vec3, "vec3", 16, 12);
+    glsl_type!(Vec4, "vec4", 16, 16);
+    glsl_type!(Mat4, "mat4", 16, 64);
+
+    pub trait GlslStruct {
+        const NAME: &'static str;
+
+        fn glsl_members() -> Vec<GlslMember>;
+    }
+
+    // The accessors only exist to infer the field types
+
+    pub fn member<S, T: GlslType>(
+        name: &'static str,
+        offset: usize,
+        _field: fn(&S) -> &T,
+    ) -> GlslMember {
+        GlslMember {
+            name,
+            ty: T::NAME,
+            align: T::ALIGN,
+            size: T::SIZE,
+            offset,
+        }
+    }
+
+    pub fn flatten<S, T: GlslStruct>(
+        offset: usize,
+        _field: fn(&S) -> &T,
+    ) -> Vec<GlslMember> {
+        T::glsl_members()
+            .into_iter()
+            .map(|member| GlslMember {
+                offset: offset + member.offset,
+                ..member
+            })
+            .collect()
+    }
+
+    pub fn buffer_reference<S>(
+        name: &'static str,
+        ty: &'static str,
+        offset: usize,
+        _field: fn(&S) -> &vk::DeviceAddress,
+    ) -> GlslMember {
+        GlslMember {
+            name,
+            ty,
+            align: 8,
+            size: 8,
+            offset,
+        }
+    }
+
+    /// Declaration of the struct and its size under std140 rules,
+    /// which agree with std430 for structs without arrays.
+    /// Padding members are added wherever the Rust struct has padding
+    /// that GLSL wouldn't add by itself.
+    pub fn declaration<S: GlslStruct>() -> (String, usize) {
+        let mut declaration = format!("struct {} {{\n", S::NAME);
+        let mut offset = 0usize;
+        let mut padding = 0;
+        for member in S::glsl_members() {
+            while offset.next_multiple_of(member.align) < member.offset {
+                writeln!(
+                    declaration,
+                    "    uint {}{};",
+                    PADDING_PREFIX, padding
+                )
+                .unwrap();
+                padding += 1;
+                offset += 4;
+            }
+            let glsl_offset = offset.next_multiple_of(member.align);
+            assert_eq!(
+                glsl_offset,
+                member.offset,
+                "{}.{} is at offset {} in Rust but can't be placed before {} in GLSL",
+                S::NAME,
+                member.name,
+                member.offset,
+                glsl_offset
+            );
+            writeln!(declaration, "    {} {};", member.ty, member.name)
+                .unwrap();
+            offset = glsl_offset + member.size;
+        }
+        declaration.push_str("};\n");
+        (declaration, offset.next_multiple_of(16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        fs,
+        mem::size_of,
+        path::{Path, PathBuf},
+    };
+
+    use super::{
+        glsl::{declaration, GlslStruct, PADDING_PREFIX},
+        *,
+    };
+    use crate::renderer::{
+        reflection::{struct_layouts, StructLayout},
+        shader_compiler::{
+            ShaderCompiler, COMBINED_SHADER_EXT, COMP_SHADER_EXT,
+        },
+    };
+
+    /// A Rust struct written into the GLSL struct of the same name
+    struct SharedStruct {
+        name: &'static str,
+        /// Rust offset of each member by its GLSL name
+        members: Vec<(&'static str, usize)>,
+        /// Bytes the Rust side writes, the GLSL struct can't be larger
+        size: usize,
+    }
+
+    impl SharedStruct {
+        fn new<S: GlslStruct>(size: usize) -> Self {
+            Self {
+                name: S::NAME,
+                members: S::glsl_members()
+                    .into_iter()
+                    .map(|member| (member.name, member.offset))
+                    .collect(),
+                size,
+            }
+        }
+    }
+
+    fn shared_structs() -> Vec<SharedStruct> {
+        vec![
+            SharedStruct::new::<GpuSceneData>(size_of::<GpuSceneData>()),
+            SharedStruct::new::<GpuVertexData>(size_of::<GpuVertexData>()),
+            SharedStruct::new::<GpuDrawPushConstants>(
+                GpuDrawPushConstants::SIZE as usize,
+            ),
+            SharedStruct::new::<GpuTexturedPushConstants>(
+                GpuTexturedPushConstants::SIZE as usize,
+            ),
+            SharedStruct::new::<MaterialConstants>(
+                size_of::<MaterialConstants>(),
+            ),
+        ]
+    }
+
+    fn check_layout(
+        expected: &SharedStruct,
+        layout: &StructLayout,
+        shader: &str,
+    ) {
+        for (member, offset) in &layout.members {
+            if member.starts_with(PADDING_PREFIX) {
+                continue;
+            }
+            let rust_offset = expected
+                .members
+                .iter()
+                .find(|(name, _)| name == member)
+                .map(|(_, offset)| *offset)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "{}.{} in {} has no Rust field",
+                        layout.name, member, shader
+                    )
+                });
+            assert_eq!(
+                *offset as usize, rust_offset,
+                "{}.{} is at offset {} in {} but at {} in Rust",
+                layout.name, member, offset, shader, rust_offset
+            );
+        }
+        assert!(
+            layout.size as usize <= expected.size,
+            "{} is {} bytes in {} but only {} in Rust",
+            layout.name,
+            layout.size,
+            shader,
+            expected.size
+        );
+    }
+
+    fn shaders_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders")
+    }
+
+    /// Contents of shaders/gpu_data.glsl
+    fn gpu_data_glsl() -> String {
+        let (vertex, vertex_size) = declaration::<GpuVertexData>();
+        assert_eq!(
+            vertex_size,
+            size_of::<GpuVertexData>(),
+            "The GLSL array stride of GpuVertexData must match its Rust size"
+        );
+
+        [
+            "// Generated from src/renderer/gpu_data.rs, do not edit.\n\
+             // Regenerate with `UPDATE_GPU_DATA_GLSL=1 cargo test gpu_data_glsl`\n\
+             \n\
+             #ifndef GPU_DATA_GLSL\n\
+             #define GPU_DATA_GLSL\n\
+             \n\
+             #extension GL_EXT_buffer_reference : require\n"
+                .to_string(),
+            declaration::<GpuSceneData>().0,
+            vertex,
+            "layout (buffer_reference, std430) readonly buffer VertexBuffer {\n    \
+             GpuVertexData vertices[];\n\
+             };\n"
+                .to_string(),
+            declaration::<GpuDrawPushConstants>().0,
+            declaration::<GpuTexturedPushConstants>().0,
+            declaration::<MaterialConstants>().0,
+            "#endif\n".to_string(),
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn test_gpu_data_glsl_up_to_date() {
+        let filepath = shaders_dir().join("gpu_data.glsl");
+        let generated = gpu_data_glsl();
+        if std::env::var_os("UPDATE_GPU_DATA_GLSL").is_some() {
+            fs::write(&filepath, &generated).unwrap();
+            return;
+        }
+        let current = fs::read_to_string(&filepath).unwrap_or_default();
+        assert!(
+            current == generated,
+            "shaders/gpu_data.glsl is out of date, regenerate it with \
+             `UPDATE_GPU_DATA_GLSL=1 cargo test gpu_data_glsl`"
+        );
+    }
+
+    #[test]
+    fn test_gpu_data_layouts_match_shaders() {
+        let shaders_dir = shaders_dir();
+        let compiler = ShaderCompiler::new().unwrap().include_dir(&shaders_dir);
+        let shared = shared_structs();
+        let mut checked = HashSet::new();
+
+        for entry in fs::read_dir(&shaders_dir).unwrap() {
+            let filepath = entry.unwrap().path();
+            let is_shader = filepath.extension().is_some_and(|ext| {
+                ext == COMBINED_SHADER_EXT || ext == COMP_SHADER_EXT
+            });
+            if !is_shader {
+                continue;
+            }
+
+            for shader in compiler.compile_file(&filepath).unwrap() {
+                for (stage, spv) in &shader.stages {
+                    let name = format!(
+                        "{}-{} {:?}",
+                        shader.name, stage, shader.keywords
+                    );
+                    for layout in struct_layouts(spv).unwrap() {
+                        let Some(expected) =
+                            shared.iter().find(|s| s.name == layout.name)
+                        else {
+                            continue;
+                        };
+                        check_layout(expected, &layout, &name);
+                        checked.insert(expected.name);
+                    }
+                }
+            }
+        }
+
+        // A renamed GLSL struct would otherwise silently go unchecked
+        for expected in &shared {
+            assert!(
+                checked.contains(expected.name),
+                "No shader declares {}",
+                expected.name
+            );
+        }
+    }
 }
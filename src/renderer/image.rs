@@ -12,6 +12,7 @@ struct AllocatedImageCreateInfo {
     pub extent: vk::Extent3D,
     pub usage_flags: vk::ImageUsageFlags,
     pub aspect_flags: vk::ImageAspectFlags,
+    pub samples: vk::SampleCountFlags,
     pub name: String,
 }
 
@@ -22,6 +23,7 @@ pub struct AllocatedImage {
     pub format: vk::Format,
     pub extent: vk::Extent3D,
     pub aspect: vk::ImageAspectFlags,
+    pub samples: vk::SampleCountFlags,
     pub allocation: Allocation, // GPU-only memory block
 }
 
@@ -36,11 +38,12 @@ impl AllocatedImage {
         allocator: &mut Allocator,
     ) -> Result<Self> {
         let image = {
-            let info = vkinit::image_create_info(
+            let mut info = vkinit::image_create_info(
                 create_info.format,
                 create_info.usage_flags,
                 create_info.extent,
             );
+            info.samples = create_info.samples;
             unsafe { device.create_image(&info, None)? }
         };
         let reqs = unsafe { device.get_image_memory_requirements(image) };
@@ -69,6 +72,7 @@ impl AllocatedImage {
             format: create_info.format,
             extent: create_info.extent,
             aspect: create_info.aspect_flags,
+            samples: create_info.samples,
             allocation,
         })
     }
@@ -92,6 +96,7 @@ impl AllocatedImage {
                 usage_flags: vk::ImageUsageFlags::SAMPLED
                     | vk::ImageUsageFlags::TRANSFER_DST,
                 aspect_flags: vk::ImageAspectFlags::COLOR,
+                samples: vk::SampleCountFlags::TYPE_1,
                 name: "Color Image".into(),
             };
             let mut image = Self::new(&create_info, &ctx.device, allocator)?;
@@ -103,26 +108,86 @@ impl AllocatedImage {
     }
 
     /// Create a special type of image used for depth buffer
+    /// The sample count must match the color attachment it is rendered with.
+    /// Formats with stencil get a view of both the depth and stencil aspect.
     pub fn new_depth_image(
         width: u32,
         height: u32,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
         device: &ash::Device,
         allocator: &mut Allocator,
     ) -> Result<Self> {
         let create_info = AllocatedImageCreateInfo {
-            format: vk::Format::D32_SFLOAT,
+            format,
             extent: vk::Extent3D {
                 width,
                 height,
                 depth: 1,
             },
             usage_flags: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-            aspect_flags: vk::ImageAspectFlags::DEPTH,
+            aspect_flags: if vkutils::format_has_stencil(format) {
+                vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+            } else {
+                vk::ImageAspectFlags::DEPTH
+            },
+            samples,
             name: "Depth Image".into(),
         };
         Self::new(&create_info, device, allocator)
     }
 
+    /// Create a multisampled color attachment that is only rendered into
+    /// and resolved, so its contents never need to leave the GPU
+    pub fn new_msaa_color_image(
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<Self> {
+        let create_info = AllocatedImageCreateInfo {
+            format,
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            },
+            usage_flags: vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+            aspect_flags: vk::ImageAspectFlags::COLOR,
+            samples,
+            name: "MSAA Color Image".into(),
+        };
+        Self::new(&create_info, device, allocator)
+    }
+
+    /// Create an image that can be rendered into and then sampled by shaders
+    pub fn new_render_target_image(
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<Self> {
+        let create_info = AllocatedImageCreateInfo {
+            format,
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            },
+            usage_flags: vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::TRANSFER_DST,
+            aspect_flags: vk::ImageAspectFlags::COLOR,
+            samples: vk::SampleCountFlags::TYPE_1,
+            name: "Render Target Image".into(),
+        };
+        Self::new(&create_info, device, allocator)
+    }
+
     /// Create a special type of image used by compute shaders
     pub fn new_storage_image(
         width: u32,
@@ -144,6 +209,7 @@ impl AllocatedImage {
                 extent,
                 usage_flags,
                 aspect_flags: vk::ImageAspectFlags::COLOR,
+                samples: vk::SampleCountFlags::TYPE_1,
                 name: "Storage Image".into(),
             };
             AllocatedImage::new(&create_info, device, allocator)?
@@ -1,27 +1,39 @@
-use bevy::log;
+use bevy::{ecs::entity::Entity, log};
 use gpu_allocator::{
     vulkan::{Allocator, AllocatorCreateDesc},
     AllocatorDebugSettings,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     mem::ManuallyDrop,
     sync::{Arc, Mutex, MutexGuard},
 };
 
 use ash::vk;
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{eyre, Context as _, OptionExt, Result};
+use glam::Vec4;
+use image::{ImageBuffer, Rgba};
 
 use super::{
-    camera::Camera,
+    attachments::{Msaa, RenderAttachments},
+    buffer::AllocatedBuffer,
+    camera::{Camera, RenderTarget},
     context::Context,
-    descriptors::DescriptorSetLayoutBuilder,
-    frame::Frame,
-    material::Material,
+    debug_view::DebugView,
+    descriptors::{DescriptorAllocator, DescriptorAllocatorStats},
+    frame::{BACKGROUND_MATERIAL, BACKGROUND_WORKGROUP_SIZE},
+    gpu_data::MaterialConstants,
+    material::{
+        GltfMetallicRoughness, Material, MaterialPass, MaterialResources,
+    },
+    material_def::{MaterialDef, PolygonMode},
     mesh::Mesh,
     model::Model,
     render_resources::RenderResources,
-    shader::GraphicsShader,
+    render_texture::RenderTexture,
+    render_window::RenderWindow,
+    shader::ComputeShader,
+    specialization::SpecializationConstants,
     swapchain::Swapchain,
     texture::{Texture, TextureAssetData},
     AssetData,
@@ -29,35 +41,76 @@ use super::{
 
 pub const FRAME_OVERLAP: u32 = 2;
 pub const MAX_OBJECTS: u32 = 10000; // Max objects per frame
+/// Compute shader of the background material
+const BACKGROUND_SHADER: &str = "gradient";
 
 pub struct DrawContext<'a> {
     pub context: Arc<Context>,
-    pub swapchain: Arc<Swapchain>,
+    pub target: DrawTarget,
     pub resources: Arc<Mutex<RenderResources>>,
 
     pub frame_number: u32,
     pub camera: &'a Camera,
-    pub background_texture: Arc<Mutex<Texture>>,
+    pub debug_view: DebugView,
+    /// Names of the models to outline
+    pub selected_models: &'a HashSet<String>,
+}
+
+/// What a frame draws into
+#[derive(Clone)]
+pub enum DrawTarget {
+    Window {
+        swapchain: Arc<Swapchain>,
+        background_texture: Arc<Mutex<Texture>>,
+    },
+    /// The color texture is looked up in the render resources by name
+    Texture {
+        name: String,
+        extent: vk::Extent2D,
+        attachments: Arc<RenderAttachments>,
+    },
+}
+
+impl DrawTarget {
+    pub fn extent(&self) -> vk::Extent2D {
+        match self {
+            Self::Window { swapchain, .. } => swapchain.image_extent,
+            Self::Texture { extent, .. } => *extent,
+        }
+    }
 }
 
 pub struct RendererInner {
     context: Arc<Context>,
-    swapchain: Arc<Swapchain>,
     allocator: ManuallyDrop<Arc<Mutex<Allocator>>>,
     resources: Arc<Mutex<RenderResources>>,
-
-    frame_number: u32,
-    frames: Vec<Frame>,
     command_pool: vk::CommandPool,
+    /// Allocates descriptor sets that live as long as the renderer,
+    /// such as those of material instances
+    desc_allocator: DescriptorAllocator,
 
-    background_texture: Arc<Mutex<Texture>>,
+    primary_window: Entity,
+    windows: HashMap<Entity, RenderWindow>,
+    render_textures: HashMap<String, RenderTexture>,
+
+    /// Definitions of the materials in `RenderResources::materials`,
+    /// kept around to rebuild materials when their shader changes
+    material_defs: HashMap<String, MaterialDef>,
+    gltf_material: Option<GltfMetallicRoughness>,
+    debug_view: DebugView,
+    /// Multisampling of every window and render texture
+    /// and of the materials drawn into them
+    msaa: Msaa,
 }
 
 impl RendererInner {
-    pub fn new(window: &winit::window::Window) -> Result<Self> {
+    pub fn new(
+        window_entity: Entity,
+        window: &winit::window::Window,
+    ) -> Result<Self> {
         log::info!("Initializing renderer ...");
 
-        let mut ctx = Context::new(window)?;
+        let (ctx, surface) = Context::new(window)?;
         let mut allocator = Allocator::new(&AllocatorCreateDesc {
             instance: ctx.instance.clone(),
             device: ctx.device.clone(),
@@ -73,46 +126,41 @@ impl RendererInner {
             buffer_device_address: true,
             allocation_sizes: Default::default(),
         })?;
-        let swapchain = Swapchain::new(&ctx, &mut allocator, window)?;
 
         let mut resources = RenderResources::default();
-        Self::init_desc_set_layouts(
-            &ctx.device,
-            &mut resources.desc_set_layouts,
-        )?;
+        resources
+            .init_bindless_textures(&ctx.device, ctx.max_bindless_textures)?;
 
         let command_pool =
             Self::create_command_pool(&ctx.device, ctx.graphics_queue_family)?;
+        // The default ratios are raised for the layouts
+        // registered once the materials exist
+        let desc_allocator = DescriptorAllocator::new(100);
 
-        let frames = {
-            let mut frames = Vec::with_capacity(FRAME_OVERLAP as usize);
-            for _ in 0..FRAME_OVERLAP {
-                // Call Frame constructor
-                frames.push(Frame::new(
-                    &mut ctx,
-                    &mut allocator,
-                    &command_pool,
-                )?);
-            }
-            frames
-        };
-
-        let background_texture = Texture::new_compute_texture(
-            swapchain.image_extent.width,
-            swapchain.image_extent.height,
-            &ctx.device,
+        let msaa = Msaa::default();
+        let primary = RenderWindow::new(
+            surface,
+            window,
+            &ctx,
             &mut allocator,
+            &command_pool,
+            msaa.samples,
         )?;
+        let windows = HashMap::from([(window_entity, primary)]);
 
         Ok(Self {
             context: Arc::new(ctx),
-            swapchain: Arc::new(swapchain),
             allocator: ManuallyDrop::new(Arc::new(Mutex::new(allocator))),
-            frame_number: 0,
-            frames,
-            command_pool,
             resources: Arc::new(Mutex::new(resources)),
-            background_texture: Arc::new(Mutex::new(background_texture)),
+            command_pool,
+            desc_allocator,
+            primary_window: window_entity,
+            windows,
+            render_textures: HashMap::new(),
+            material_defs: HashMap::new(),
+            gltf_material: None,
+            debug_view: DebugView::default(),
+            msaa,
         })
     }
 
@@ -120,40 +168,386 @@ impl RendererInner {
         self.init_models(&mut assets.models)?;
         self.init_textures(&mut assets.textures)?;
         self.init_materials()?;
+        self.init_gltf_materials()?;
+        self.init_compute_materials()?;
 
         Ok(())
     }
 
-    pub fn draw_frame(&mut self, camera: &Camera) -> Result<()> {
-        let ctx = DrawContext {
-            context: self.context.clone(),
-            swapchain: self.swapchain.clone(),
-            resources: self.resources.clone(),
-            frame_number: self.frame_number,
-            camera,
-            background_texture: self.background_texture.clone(),
+    pub fn add_window(
+        &mut self,
+        window_entity: Entity,
+        window: &winit::window::Window,
+    ) -> Result<()> {
+        if self.windows.contains_key(&window_entity) {
+            return Err(eyre!(
+                "Window {:?} already has a renderer",
+                window_entity
+            ));
+        }
+
+        let surface = self.context.create_window_surface(window)?;
+        let mut allocator = self.get_allocator()?;
+        let render_window = match RenderWindow::new(
+            surface,
+            window,
+            &self.context,
+            &mut allocator,
+            &self.command_pool,
+            self.msaa.samples,
+        ) {
+            Ok(render_window) => render_window,
+            Err(err) => {
+                self.context.destroy_surface(surface);
+                return Err(err);
+            }
         };
-        self.get_current_frame().draw(ctx)?;
-        self.frame_number += 1;
 
+        // Materials are only built for the formats of the primary window
+        let format = render_window.swapchain().image_format;
+        let primary_format = self.primary_swapchain().image_format;
+        if format != primary_format {
+            render_window.cleanup(&self.context, &mut allocator);
+            return Err(eyre!(
+                "Window {:?} has surface format {:?}, but materials are built for {:?}",
+                window_entity,
+                format,
+                primary_format
+            ));
+        }
+        drop(allocator);
+
+        log::info!("Added window {:?} to renderer", window_entity);
+        self.windows.insert(window_entity, render_window);
         Ok(())
     }
 
-    pub fn cleanup(mut self) {
-        // Wait until all frames have finished rendering
-        for frame in &self.frames {
-            unsafe {
-                self.context
-                    .device
-                    .wait_for_fences(&[frame.render_fence()], true, 1000000000)
-                    .unwrap();
+    pub fn remove_window(&mut self, window_entity: Entity) -> Result<()> {
+        if window_entity == self.primary_window {
+            return Err(eyre!("Cannot remove the primary window"));
+        }
+
+        let render_window = self
+            .windows
+            .remove(&window_entity)
+            .ok_or_eyre("Window has no renderer")?;
+        render_window.cleanup(&self.context, &mut *self.get_allocator()?);
+        log::info!("Removed window {:?} from renderer", window_entity);
+
+        Ok(())
+    }
+
+    pub fn has_window(&self, window_entity: Entity) -> bool {
+        self.windows.contains_key(&window_entity)
+    }
+
+    pub fn is_primary_window(&self, window_entity: Entity) -> bool {
+        window_entity == self.primary_window
+    }
+
+    /// Cameras that render into textures are drawn before cameras that
+    /// render into windows, so their textures are up to date when sampled.
+    /// Each target is drawn at most once per frame.
+    pub fn draw_frame(
+        &mut self,
+        cameras: &[&Camera],
+        selected_models: &HashSet<String>,
+    ) -> Result<()> {
+        let mut drawn_textures = HashSet::new();
+        for camera in cameras {
+            let RenderTarget::Texture {
+                name,
+                width,
+                height,
+            } = camera.target()
+            else {
+                continue;
+            };
+            if !drawn_textures.insert(name) {
+                continue;
+            }
+            let extent = vk::Extent2D {
+                width: *width,
+                height: *height,
+            };
+            self.prepare_render_texture(name, extent)?;
+            self.render_textures.get_mut(name).unwrap().draw_frame(
+                self.context.clone(),
+                self.resources.clone(),
+                camera,
+                self.debug_view,
+                selected_models,
+            )?;
+        }
+
+        let mut drawn_windows = HashSet::new();
+        for camera in cameras {
+            let Some(window_entity) =
+                camera.target().window_entity(self.primary_window)
+            else {
+                continue;
+            };
+            // Windows that haven't been added yet are skipped
+            let Some(render_window) = self.windows.get_mut(&window_entity)
+            else {
+                continue;
+            };
+            if !drawn_windows.insert(window_entity) {
+                continue;
+            }
+            render_window.draw_frame(
+                self.context.clone(),
+                self.resources.clone(),
+                camera,
+                self.debug_view,
+                selected_models,
+            )?;
+        }
+
+        if let Some(bindless) = &mut self.get_resources()?.bindless_textures {
+            bindless.end_frame();
+        }
+
+        Ok(())
+    }
+
+    /// Switch to the next debug view, skipping views whose material
+    /// isn't loaded, e.g. wireframe on GPUs without wireframe support
+    pub fn cycle_debug_view(&mut self) -> DebugView {
+        let resources = self.resources.lock().unwrap();
+        let mut view = self.debug_view.next();
+        while let Some(name) = view.material_name() {
+            if resources.materials.contains_key(name) {
+                break;
             }
+            view = view.next();
         }
+        drop(resources);
+
+        self.debug_view = view;
+        view
+    }
+
+    pub fn msaa(&self) -> Msaa {
+        self.msaa
+    }
+
+    /// Statistics of every descriptor allocator by what it allocates for
+    pub fn desc_stats(&self) -> Vec<(String, DescriptorAllocatorStats)> {
+        let mut stats = vec![("materials".into(), self.desc_allocator.stats())];
+        for (entity, render_window) in &self.windows {
+            for (index, frame) in
+                render_window.desc_stats().into_iter().enumerate()
+            {
+                stats.push((
+                    format!("window {:?} frame {}", entity, index),
+                    frame,
+                ));
+            }
+        }
+        for (name, render_texture) in &self.render_textures {
+            for (index, frame) in
+                render_texture.desc_stats().into_iter().enumerate()
+            {
+                stats.push((
+                    format!("render texture {} frame {}", name, index),
+                    frame,
+                ));
+            }
+        }
+        stats
+    }
+
+    /// Switch every window and render texture to the highest supported
+    /// sample count up to `sample_count` and rebuild all materials for it.
+    /// Sample shading is ignored if the GPU doesn't support it.
+    /// On error the previous sample count stays in use everywhere.
+    pub fn set_msaa(
+        &mut self,
+        sample_count: u32,
+        min_sample_shading: Option<f32>,
+    ) -> Result<Msaa> {
+        let mut msaa =
+            Msaa::new(sample_count, self.context.supported_sample_counts())?;
+        if msaa.sample_count() != sample_count {
+            log::warn!(
+                "GPU does not support {}x MSAA, using {}x instead",
+                sample_count,
+                msaa.sample_count()
+            );
+        }
+        if let Some(min_sample_shading) = min_sample_shading {
+            if self.context.enabled_features.sample_rate_shading == vk::TRUE {
+                msaa = msaa.with_sample_shading(min_sample_shading);
+            } else {
+                log::warn!("Skipped sample shading because the GPU does not support it");
+            }
+        }
+        if msaa == self.msaa {
+            return Ok(msaa);
+        }
+
+        // Frames in flight may still be using the old attachments
+        self.wait_idle()?;
+
+        // Nothing has changed yet if the materials fail to build
+        let materials = self.build_materials(msaa)?;
+        if let Err(err) = self.set_samples(msaa.samples) {
+            // Attachments that already switched are switched back
+            if let Err(err) = self.set_samples(self.msaa.samples) {
+                log::error!(
+                    "Failed to restore {}x MSAA: {:?}",
+                    self.msaa.sample_count(),
+                    err
+                );
+            }
+            for (_, material) in materials {
+                material.cleanup(&self.context.device);
+            }
+            return Err(err);
+        }
+        self.msaa = msaa;
+        self.get_resources()?
+            .replace_materials(materials, &self.context.device);
+
+        if msaa.is_enabled() {
+            log::info!("Enabled {}x MSAA", msaa.sample_count());
+        } else {
+            log::info!("Disabled MSAA");
+        }
+        Ok(msaa)
+    }
+
+    /// Rebuild every material that uses one of the given shaders.
+    /// A material that fails to build keeps its old pipeline.
+    #[cfg(feature = "hot-reload")]
+    pub fn reload_shaders(
+        &mut self,
+        shader_names: &HashSet<String>,
+    ) -> Result<()> {
+        let affected = self
+            .material_defs
+            .iter()
+            .filter(|(_, def)| shader_names.contains(&def.shader))
+            .collect::<Vec<_>>();
+        let gltf_affected = self.gltf_material.is_some()
+            && shader_names.contains(GltfMetallicRoughness::SHADER);
+        let background_affected = shader_names.contains(BACKGROUND_SHADER);
+        if affected.is_empty() && !gltf_affected && !background_affected {
+            return Ok(());
+        }
+
+        // Frames in flight may still be using the old pipelines
+        self.wait_idle()?;
+
+        let device = &self.context.device;
+        let mut resources = self.get_resources()?;
+        let swapchain = self.primary_swapchain();
+        for (name, def) in affected {
+            let material = def.build(
+                &self.context,
+                &mut resources,
+                swapchain.image_format,
+                swapchain.attachments.depth_image.format,
+                self.msaa,
+            );
+            match material {
+                Ok(material) => {
+                    if let Some(old) =
+                        resources.materials.insert(name.clone(), material)
+                    {
+                        old.cleanup(device);
+                    }
+                    log::info!("Reloaded material {}", name);
+                }
+                Err(err) => {
+                    log::error!("Failed to reload material {}: {:?}", name, err)
+                }
+            }
+        }
+        if gltf_affected {
+            // Keeps the old materials if either fails to build.
+            // Material instances were written with the old material layout,
+            // so the shader must not change it.
+            let old_layout = GltfMetallicRoughness::material_layout(&resources)
+                .map(|(layout, _)| layout)
+                .ok();
+            match GltfMetallicRoughness::build_materials(
+                &self.context,
+                &mut resources,
+                swapchain.image_format,
+                swapchain.attachments.depth_image.format,
+                self.msaa,
+            ) {
+                Ok(materials)
+                    if materials.iter().any(|(_, material)| {
+                        material.desc_set_layout(
+                            GltfMetallicRoughness::MATERIAL_SET,
+                        ) != old_layout
+                    }) =>
+                {
+                    for (_, material) in materials {
+                        material.cleanup(device);
+                    }
+                    log::error!(
+                        "Failed to reload glTF materials: the material set of shader {} changed",
+                        GltfMetallicRoughness::SHADER
+                    );
+                }
+                Ok(materials) => {
+                    resources.replace_materials(materials, device);
+                    log::info!("Reloaded glTF materials")
+                }
+                Err(err) => {
+                    log::error!("Failed to reload glTF materials: {:?}", err)
+                }
+            }
+        }
+        if background_affected {
+            let material =
+                Self::build_background_material(&self.context, &mut resources);
+            match material {
+                Ok(material) => {
+                    resources.replace_materials(
+                        vec![(BACKGROUND_MATERIAL.into(), material)],
+                        device,
+                    );
+                    log::info!("Reloaded material {}", BACKGROUND_MATERIAL);
+                }
+                Err(err) => log::error!(
+                    "Failed to reload material {}: {:?}",
+                    BACKGROUND_MATERIAL,
+                    err
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn cleanup(mut self) {
+        // Wait until all frames of every window have finished rendering
+        self.wait_idle().unwrap();
 
         {
             let device = &self.context.device;
             let mut allocator = self.allocator.lock().unwrap();
 
+            // Clean up render textures (frames, depth and color images)
+            {
+                let mut resources = self.resources.lock().unwrap();
+                if let Some(gltf_material) = self.gltf_material.take() {
+                    gltf_material.clear_resources(device, &mut resources);
+                }
+                for (_, render_texture) in self.render_textures.drain() {
+                    render_texture.cleanup(
+                        device,
+                        &mut resources,
+                        &mut allocator,
+                    );
+                }
+            }
+
             match Arc::try_unwrap(self.resources) {
                 Ok(resources) => {
                     resources
@@ -166,38 +560,17 @@ impl RendererInner {
             }
             .unwrap();
 
-            // Destroy command pool
-            unsafe {
-                device.destroy_command_pool(self.command_pool, None);
+            // Clean up all windows (frames, swapchains and surfaces)
+            for (_, render_window) in self.windows.drain() {
+                render_window.cleanup(&self.context, &mut allocator);
             }
 
-            // Clean up all frames
-            for frame in self.frames.drain(..) {
-                frame.cleanup(device, &mut allocator);
-            }
+            self.desc_allocator.destroy_pools(device);
 
-            // Clean up background texture
-            match Arc::try_unwrap(self.background_texture) {
-                Ok(texture) => {
-                    texture
-                        .into_inner()
-                        .unwrap()
-                        .cleanup(device, &mut allocator);
-                    Ok(())
-                }
-                Err(_) => Err(eyre!("Failed to cleanup background texture")),
-            }
-            .unwrap();
-
-            // Clean up swapchain
-            match Arc::try_unwrap(self.swapchain) {
-                Ok(swapchain) => {
-                    swapchain.cleanup(device, &mut allocator);
-                    Ok(())
-                }
-                Err(_) => Err(eyre!("Failed to cleanup swapchain")),
+            // Destroy command pool
+            unsafe {
+                device.destroy_command_pool(self.command_pool, None);
             }
-            .unwrap()
         }
         // We need to do this because the allocator doesn't destroy all
         // memory blocks (VkDeviceMemory) until it is dropped.
@@ -210,8 +583,132 @@ impl RendererInner {
         }
     }
 
-    fn get_current_frame(&mut self) -> &mut Frame {
-        &mut self.frames[(self.frame_number % FRAME_OVERLAP) as usize]
+    /// Block until all frames of every window and render texture
+    /// have finished rendering
+    fn wait_idle(&self) -> Result<()> {
+        for render_window in self.windows.values() {
+            render_window.wait_idle(&self.context.device)?;
+        }
+        for render_texture in self.render_textures.values() {
+            render_texture.wait_idle(&self.context.device)?;
+        }
+        Ok(())
+    }
+
+    /// Swapchain of the primary window.
+    /// `add_window` rejects windows with another surface format
+    /// and all windows share the depth format,
+    /// so materials built against it can draw into any window.
+    fn primary_swapchain(&self) -> &Swapchain {
+        self.windows[&self.primary_window].swapchain()
+    }
+
+    /// Create the render texture if it doesn't exist yet,
+    /// or recreate it if the requested size has changed
+    fn prepare_render_texture(
+        &mut self,
+        name: &str,
+        extent: vk::Extent2D,
+    ) -> Result<()> {
+        if let Some(render_texture) = self.render_textures.get(name) {
+            if render_texture.extent() == extent {
+                return Ok(());
+            }
+            // Window frames may still be sampling the old texture
+            unsafe {
+                self.context.device.device_wait_idle()?;
+            }
+            let render_texture = self.render_textures.remove(name).unwrap();
+            render_texture.cleanup(
+                &self.context.device,
+                &mut *self.get_resources()?,
+                &mut *self.get_allocator()?,
+            );
+        }
+
+        let color_format = self.primary_swapchain().image_format;
+        let render_texture = RenderTexture::new(
+            name,
+            extent,
+            color_format,
+            &self.context,
+            &mut *self.get_resources()?,
+            &mut *self.get_allocator()?,
+            &self.command_pool,
+            self.msaa.samples,
+        )?;
+        log::info!("Added render texture {} to renderer", name);
+        self.render_textures.insert(name.into(), render_texture);
+
+        Ok(())
+    }
+
+    /// Switch the attachments of every window and render texture.
+    /// Frames must not be using the old attachments anymore.
+    fn set_samples(&mut self, samples: vk::SampleCountFlags) -> Result<()> {
+        let device = &self.context.device;
+        let mut allocator = self.allocator.lock().unwrap();
+        for render_window in self.windows.values_mut() {
+            render_window.set_samples(samples, device, &mut allocator)?;
+        }
+        for render_texture in self.render_textures.values_mut() {
+            render_texture.set_samples(samples, device, &mut allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Build every material against the current attachment formats,
+    /// e.g. for a new sample count, without replacing the current ones.
+    /// Nothing is kept if one of them fails to build.
+    fn build_materials(&self, msaa: Msaa) -> Result<Vec<(String, Material)>> {
+        let device = &self.context.device;
+        let mut resources = self.get_resources()?;
+        let swapchain = self.primary_swapchain();
+        let color_format = swapchain.image_format;
+        let depth_format = swapchain.attachments.depth_image.format;
+
+        let mut materials = Vec::with_capacity(self.material_defs.len() + 2);
+        for (name, def) in &self.material_defs {
+            let material = def
+                .build(
+                    &self.context,
+                    &mut resources,
+                    color_format,
+                    depth_format,
+                    msaa,
+                )
+                .with_context(|| {
+                    format!("Failed to rebuild material {}", name)
+                });
+            match material {
+                Ok(material) => materials.push((name.clone(), material)),
+                Err(err) => {
+                    for (_, material) in materials {
+                        material.cleanup(device);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        if self.gltf_material.is_some() {
+            match GltfMetallicRoughness::build_materials(
+                &self.context,
+                &mut resources,
+                color_format,
+                depth_format,
+                msaa,
+            ) {
+                Ok(gltf_materials) => materials.extend(gltf_materials),
+                Err(err) => {
+                    for (_, material) in materials {
+                        material.cleanup(device);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(materials)
     }
 
     fn get_allocator(&self) -> Result<MutexGuard<Allocator>> {
@@ -244,42 +741,6 @@ impl RendererInner {
         Ok(command_pool)
     }
 
-    fn init_desc_set_layouts(
-        device: &ash::Device,
-        desc_set_layouts: &mut HashMap<String, vk::DescriptorSetLayout>,
-    ) -> Result<()> {
-        let compute_texture_layout = DescriptorSetLayoutBuilder::new()
-            .add_binding(
-                0,
-                vk::DescriptorType::STORAGE_IMAGE,
-                vk::ShaderStageFlags::COMPUTE,
-            )
-            .build(device)?;
-        desc_set_layouts
-            .insert("compute texture".into(), compute_texture_layout);
-
-        let graphics_texture_layout = DescriptorSetLayoutBuilder::new()
-            .add_binding(
-                0,
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                vk::ShaderStageFlags::FRAGMENT,
-            )
-            .build(device)?;
-        desc_set_layouts
-            .insert("graphics texture".into(), graphics_texture_layout);
-
-        let scene_layout = DescriptorSetLayoutBuilder::new()
-            .add_binding(
-                0,
-                vk::DescriptorType::UNIFORM_BUFFER,
-                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
-            )
-            .build(device)?;
-        desc_set_layouts.insert("scene buffer".into(), scene_layout);
-
-        Ok(())
-    }
-
     /// Upload all models to the GPU
     fn init_models(
         &mut self,
@@ -312,85 +773,189 @@ impl RendererInner {
                 resources.create_sampler(data.filter, &self.context.device)?;
             }
             let sampler = resources.samplers[&data.filter];
+            let mut allocator = self.get_allocator()?;
             let texture = Texture::new_graphics_texture(
                 data,
                 sampler,
                 &self.context,
-                &mut *self.get_allocator()?,
+                &mut allocator,
+            )?;
+            resources.add_texture(
+                &name,
+                texture,
+                &self.context.device,
+                &mut allocator,
             )?;
-            resources.textures.insert(name, texture);
         }
 
+        // Default for materials that don't have a texture
+        if !resources.samplers.contains_key(&vk::Filter::NEAREST) {
+            resources
+                .create_sampler(vk::Filter::NEAREST, &self.context.device)?;
+        }
+        let mut allocator = self.get_allocator()?;
+        let white = Texture::new_graphics_texture(
+            TextureAssetData {
+                data: Some(ImageBuffer::from_pixel(1, 1, Rgba([255; 4]))),
+                ..Default::default()
+            },
+            resources.samplers[&vk::Filter::NEAREST],
+            &self.context,
+            &mut allocator,
+        )?;
+        resources.add_texture(
+            "white",
+            white,
+            &self.context.device,
+            &mut allocator,
+        )?;
+
         Ok(())
     }
 
-    /// Create materials and insert them into RenderResources
+    /// Build every material defined in the materials directory of the assets
+    /// and insert them into RenderResources
     fn init_materials(&mut self) -> Result<()> {
+        let mut defs = MaterialDef::load_all()?;
+        let wireframe_supported =
+            self.context.enabled_features.fill_mode_non_solid == vk::TRUE;
+        defs.retain(|name, def| {
+            let supported =
+                def.polygon_mode == PolygonMode::Fill || wireframe_supported;
+            if !supported {
+                log::warn!(
+                    "Skipped material {} because the GPU does not support its polygon mode",
+                    name
+                );
+            }
+            supported
+        });
+        {
+            let mut resources = self.get_resources()?;
+            let swapchain = self.primary_swapchain();
+
+            for (name, def) in &defs {
+                let material = def
+                    .build(
+                        &self.context,
+                        &mut resources,
+                        swapchain.image_format,
+                        swapchain.attachments.depth_image.format,
+                        self.msaa,
+                    )
+                    .with_context(|| {
+                        format!("Failed to build material {}", name)
+                    })?;
+                log::info!("Loaded material {}", name);
+                resources.materials.insert(name.clone(), material);
+            }
+            log::info!(
+                "{} materials share {} pipelines",
+                resources.materials.len(),
+                resources.pipeline_cache.len()
+            );
+        }
+        self.material_defs = defs;
+
+        Ok(())
+    }
+
+    /// Build the compute materials, which don't depend on the attachments
+    fn init_compute_materials(&mut self) -> Result<()> {
         let mut resources = self.get_resources()?;
+        let material =
+            Self::build_background_material(&self.context, &mut resources)?;
+        resources.replace_materials(
+            vec![(BACKGROUND_MATERIAL.into(), material)],
+            &self.context.device,
+        );
 
-        let scene_buffer_layout = resources.desc_set_layouts["scene buffer"];
-        let graphics_texture_layout =
-            resources.desc_set_layouts["graphics texture"];
-        #[allow(unused_variables)]
-        let compute_texture_layout =
-            resources.desc_set_layouts["compute texture"];
+        Ok(())
+    }
 
-        let default_mat = {
-            let set_layouts = [scene_buffer_layout];
-            let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-                .set_layouts(&set_layouts)
-                .build();
-            let pipeline_layout = unsafe {
-                self.context
-                    .device
-                    .create_pipeline_layout(&pipeline_layout_info, None)?
-            };
-            Material::builder_graphics(&self.context.device)
-                .pipeline_layout(pipeline_layout)
-                .shader(GraphicsShader::new("default", &self.context.device)?)
-                .color_attachment_format(self.swapchain.image_format)
-                .depth_attachment_format(self.swapchain.depth_image.format)
-                .build()?
-        };
-        resources.materials.insert("default".into(), default_mat);
+    /// The workgroup size is specialized to the one its dispatches assume
+    fn build_background_material(
+        ctx: &Context,
+        resources: &mut RenderResources,
+    ) -> Result<Material> {
+        let device = &ctx.device;
+        let [group_width, group_height] = BACKGROUND_WORKGROUP_SIZE;
+        Material::builder_compute(device)
+            .shader(ComputeShader::new(BACKGROUND_SHADER, device)?)
+            .push_descriptor_set(ctx.push_descriptor.is_some().then_some(0))
+            .specialization(
+                SpecializationConstants::new()
+                    .set(0, group_width)
+                    .set(1, group_height),
+            )
+            .build(
+                &mut resources.pipeline_cache,
+                &mut resources.desc_set_layout_cache,
+            )
+            .context("Failed to build background material")
+    }
 
-        let grid_mat = {
-            let set_layouts = [scene_buffer_layout];
-            let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-                .set_layouts(&set_layouts)
-                .build();
-            let pipeline_layout = unsafe {
-                self.context
-                    .device
-                    .create_pipeline_layout(&pipeline_layout_info, None)?
-            };
-            Material::builder_graphics(&self.context.device)
-                .pipeline_layout(pipeline_layout)
-                .shader(GraphicsShader::new("grid", &self.context.device)?)
-                .color_attachment_format(self.swapchain.image_format)
-                .depth_attachment_format(self.swapchain.depth_image.format)
-                .build()?
+    /// Create the glTF metallic-roughness materials
+    /// and the material instance the backpack is drawn with
+    fn init_gltf_materials(&mut self) -> Result<()> {
+        let (color_format, depth_format) = {
+            let swapchain = self.primary_swapchain();
+            (
+                swapchain.image_format,
+                swapchain.attachments.depth_image.format,
+            )
         };
-        resources.materials.insert("grid".into(), grid_mat);
+        let device = &self.context.device;
+        let mut resources = self.resources.lock().unwrap();
+        let mut allocator = self.allocator.lock().unwrap();
 
-        let textured_mat = {
-            let set_layouts = [scene_buffer_layout, graphics_texture_layout];
-            let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-                .set_layouts(&set_layouts)
-                .build();
-            let pipeline_layout = unsafe {
-                self.context
-                    .device
-                    .create_pipeline_layout(&pipeline_layout_info, None)?
-            };
-            Material::builder_graphics(&self.context.device)
-                .pipeline_layout(pipeline_layout)
-                .shader(GraphicsShader::new("textured", &self.context.device)?)
-                .color_attachment_format(self.swapchain.image_format)
-                .depth_attachment_format(self.swapchain.depth_image.format)
-                .build()?
-        };
-        resources.materials.insert("textured".into(), textured_mat);
+        let mut gltf_material = GltfMetallicRoughness::new(
+            &self.context,
+            &mut resources,
+            color_format,
+            depth_format,
+            self.msaa,
+        )?;
+        let (_, material_bindings) =
+            GltfMetallicRoughness::material_layout(&resources)?;
+        self.desc_allocator.register_layout(&material_bindings);
+
+        let mut constants_buffer = AllocatedBuffer::new(
+            device,
+            &mut allocator,
+            std::mem::size_of::<MaterialConstants>() as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            "Backpack material constants",
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        constants_buffer.write(
+            &[MaterialConstants::new(
+                Vec4::ONE,
+                Vec4::new(1.0, 0.5, 0.0, 0.0),
+                resources.texture_index("backpack")?,
+                resources.texture_index("white")?,
+            )],
+            0,
+        )?;
+
+        let instance = gltf_material.write_material(
+            device,
+            MaterialPass::Opaque,
+            &MaterialResources {
+                data_buffer: constants_buffer.buffer,
+                data_buffer_offset: 0,
+            },
+            &resources,
+            &mut self.desc_allocator,
+        )?;
+
+        resources
+            .material_instances
+            .insert("backpack".into(), instance);
+        resources
+            .buffers
+            .insert("backpack material constants".into(), constants_buffer);
+        self.gltf_material = Some(gltf_material);
 
         Ok(())
     }
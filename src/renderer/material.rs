@@ -1,40 +1,117 @@
 use bevy::log;
-use color_eyre::eyre::{eyre, OptionExt, Result};
-use glam::Vec4;
-use std::{collections::HashMap, ffi::CString, sync::Arc};
+use color_eyre::eyre::{eyre, Context as _, OptionExt, Result};
+use std::{collections::BTreeMap, ffi::CString, sync::Arc};
 
 use ash::vk;
 
 use super::{
-    context::Context,
-    descriptors::{DescriptorSetLayoutBuilder, DescriptorWriter},
-    gpu_data::GpuDrawPushConstants,
-    image::AllocatedImage,
+    attachments::Msaa,
+    context::{Context, DynamicStateSupport},
+    descriptors::{
+        DescriptorAllocator, DescriptorBinding, DescriptorSetCache,
+        DescriptorSetLayoutCache, DescriptorWriter,
+    },
+    gpu_data::{GpuDrawPushConstants, MaterialConstants},
+    pipeline_cache::{
+        BlendKey, GraphicsStateKey, Pipeline, PipelineCache, PipelineKey,
+        PipelineLayoutKey, VertexInputKey,
+    },
+    reflection::ShaderReflection,
+    render_resources::RenderResources,
     shader::{ComputeShader, GraphicsShader},
-    swapchain::Swapchain,
+    specialization::{SpecializationConstants, SpecializationData},
     vertex::VertexInputDescription,
+    vkutils,
 };
 
+/// Layouts supplied to a builder with their bindings, by set
+type SuppliedSetLayouts =
+    BTreeMap<u32, (vk::DescriptorSetLayout, Vec<DescriptorBinding>)>;
+
 pub struct MaterialInstance {
     pub material_name: String,
     pub desc_set: vk::DescriptorSet,
     pub pass: MaterialPass,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MaterialPass {
     Opaque,
     Transparent,
     Other,
 }
 
+/// Materials built from identical state share their pipeline
 #[derive(PartialEq, Clone)]
 pub struct Material {
     pub pipeline: vk::Pipeline,
     pub pipeline_layout: vk::PipelineLayout,
     pipeline_bind_point: vk::PipelineBindPoint,
+    shared: Arc<Pipeline>,
+    dynamic_state: Option<DynamicGraphicsState>,
+}
+
+/// Graphics state that is set when a material is bound
+/// instead of being baked into its pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicGraphicsState {
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    /// Must stay in the topology class the pipeline was built with,
+    /// e.g. a triangle list can become a strip but not a line list
+    pub topology: vk::PrimitiveTopology,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare_op: vk::CompareOp,
+    /// None if blending is baked into the pipeline
+    pub blend: Option<BlendKey>,
+}
+
+impl DynamicGraphicsState {
+    fn apply(&self, cmd: vk::CommandBuffer, ctx: &Context) {
+        let device = &ctx.device;
+        unsafe {
+            device.cmd_set_cull_mode(cmd, self.cull_mode);
+            device.cmd_set_front_face(cmd, self.front_face);
+            device.cmd_set_primitive_topology(cmd, self.topology);
+            device.cmd_set_depth_test_enable(cmd, self.depth_test);
+            device.cmd_set_depth_write_enable(cmd, self.depth_write);
+            device.cmd_set_depth_compare_op(cmd, self.depth_compare_op);
+        }
+
+        if let (Some(blend), Some(ext)) = (&self.blend, &ctx.ext_dynamic_state3)
+        {
+            let equation = vk::ColorBlendEquationEXT {
+                src_color_blend_factor: blend.src_color,
+                dst_color_blend_factor: blend.dst_color,
+                color_blend_op: blend.color_op,
+                src_alpha_blend_factor: blend.src_alpha,
+                dst_alpha_blend_factor: blend.dst_alpha,
+                alpha_blend_op: blend.alpha_op,
+            };
+            unsafe {
+                ext.cmd_set_color_blend_enable(cmd, 0, &[blend.enable]);
+                ext.cmd_set_color_blend_equation(cmd, 0, &[equation]);
+                ext.cmd_set_color_write_mask(cmd, 0, &[blend.write_mask]);
+            }
+        }
+    }
 }
 
 impl Material {
+    fn new(
+        shared: Arc<Pipeline>,
+        dynamic_state: Option<DynamicGraphicsState>,
+    ) -> Self {
+        Self {
+            pipeline: shared.pipeline,
+            pipeline_layout: shared.layout,
+            pipeline_bind_point: shared.bind_point,
+            shared,
+            dynamic_state,
+        }
+    }
+
     pub fn builder_graphics(
         device: &ash::Device,
     ) -> GraphicsMaterialBuilder<'_> {
@@ -45,11 +122,12 @@ impl Material {
         ComputeMaterialBuilder::new(device)
     }
 
+    /// The pipeline is only destroyed once no other material uses it
     pub fn cleanup(self, device: &ash::Device) {
-        log::info!("Cleaning up pipeline ...");
-        unsafe {
-            device.destroy_pipeline_layout(self.pipeline_layout, None);
-            device.destroy_pipeline(self.pipeline, None);
+        if let Some(pipeline) = Arc::into_inner(self.shared) {
+            pipeline.cleanup(device);
+        } else {
+            log::info!("Pipeline still in use by other materials");
         }
     }
 
@@ -71,14 +149,34 @@ impl Material {
         }
     }
 
-    pub fn bind_pipeline(&self, cmd: vk::CommandBuffer, device: &ash::Device) {
+    /// Also sets the dynamic state of the material
+    pub fn bind_pipeline(&self, cmd: vk::CommandBuffer, ctx: &Context) {
         unsafe {
-            device.cmd_bind_pipeline(
+            ctx.device.cmd_bind_pipeline(
                 cmd,
                 self.pipeline_bind_point,
                 self.pipeline,
             );
         }
+        if let Some(state) = &self.dynamic_state {
+            state.apply(cmd, ctx);
+        }
+    }
+
+    /// Change state that is set at bind time,
+    /// fails if the state is baked into the pipeline
+    pub fn dynamic_state_mut(&mut self) -> Result<&mut DynamicGraphicsState> {
+        self.dynamic_state
+            .as_mut()
+            .ok_or_eyre("Material has no dynamic state")
+    }
+
+    /// A clone of this material can change its blending
+    /// without needing another pipeline
+    pub fn has_dynamic_blending(&self) -> bool {
+        self.dynamic_state
+            .as_ref()
+            .is_some_and(|state| state.blend.is_some())
     }
 
     pub fn bind_desc_sets(
@@ -100,14 +198,52 @@ impl Material {
             );
         }
     }
+
+    /// Layout of a descriptor set of the pipeline,
+    /// e.g. to allocate sets of material instances
+    pub fn desc_set_layout(&self, set: u32) -> Option<vk::DescriptorSetLayout> {
+        self.shared.set_layouts.get(set as usize).copied()
+    }
+
+    /// Push the writer's descriptors into `set`, whose layout must be built
+    /// for push descriptors if the device supports them,
+    /// see `GraphicsMaterialBuilder::push_descriptor_set`.
+    /// Otherwise they are written into a set from `desc_cache`,
+    /// which is bound instead.
+    pub fn push_desc_set(
+        &self,
+        cmd: vk::CommandBuffer,
+        ctx: &Context,
+        set: u32,
+        writer: &mut DescriptorWriter,
+        desc_cache: &mut DescriptorSetCache,
+    ) -> Result<()> {
+        if let Some(push_descriptor) = &ctx.push_descriptor {
+            writer.push_set(
+                push_descriptor,
+                cmd,
+                self.pipeline_bind_point,
+                self.pipeline_layout,
+                set,
+            );
+        } else {
+            let layout = self.desc_set_layout(set).ok_or_else(|| {
+                eyre!("Material has no descriptor set {}", set)
+            })?;
+            let desc_set =
+                desc_cache.get_or_write(&ctx.device, layout, writer)?;
+            self.bind_desc_sets(cmd, &ctx.device, set, &[desc_set], &[]);
+        }
+        Ok(())
+    }
 }
 
 pub struct GraphicsMaterialBuilder<'a> {
     device: &'a ash::Device,
 
     vertex_input_desc: VertexInputDescription,
-    vertex_input: vk::PipelineVertexInputStateCreateInfo,
     input_assembly: vk::PipelineInputAssemblyStateCreateInfo,
+    tessellation: vk::PipelineTessellationStateCreateInfo,
     rasterization: vk::PipelineRasterizationStateCreateInfo,
     color_blend_attachment: vk::PipelineColorBlendAttachmentState,
     multisample: vk::PipelineMultisampleStateCreateInfo,
@@ -116,6 +252,12 @@ pub struct GraphicsMaterialBuilder<'a> {
     rendering_info: vk::PipelineRenderingCreateInfo,
     shader: Option<GraphicsShader>,
     pipeline_layout: Option<vk::PipelineLayout>,
+    desc_set_layouts: SuppliedSetLayouts,
+    push_descriptor_set: Option<u32>,
+    push_constant_ranges: Option<Vec<vk::PushConstantRange>>,
+    spec_constants: BTreeMap<vk::ShaderStageFlags, SpecializationConstants>,
+    dynamic_state: DynamicStateSupport,
+    enabled_features: vk::PhysicalDeviceFeatures,
 
     desc_sets: Vec<vk::DescriptorSet>,
 }
@@ -123,12 +265,8 @@ pub struct GraphicsMaterialBuilder<'a> {
 impl<'a> GraphicsMaterialBuilder<'a> {
     fn new(device: &'a ash::Device) -> Self {
         let vertex_input_desc = VertexInputDescription::default();
-        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_attribute_descriptions(&vertex_input_desc.attributes)
-            .vertex_binding_descriptions(&vertex_input_desc.bindings)
-            .flags(vertex_input_desc.flags)
-            .build();
         let input_assembly = Self::default_input_assembly_info();
+        let tessellation = Self::default_tessellation_info();
         let rasterization = Self::default_rasterization_info();
         let color_blend_attachment = Self::default_color_blend_state();
         let multisample = Self::default_multisample_info();
@@ -142,8 +280,8 @@ impl<'a> GraphicsMaterialBuilder<'a> {
             device,
 
             vertex_input_desc,
-            vertex_input,
             input_assembly,
+            tessellation,
             rasterization,
             color_blend_attachment,
             multisample,
@@ -152,6 +290,12 @@ impl<'a> GraphicsMaterialBuilder<'a> {
             rendering_info,
             shader,
             pipeline_layout,
+            desc_set_layouts: BTreeMap::new(),
+            push_descriptor_set: None,
+            push_constant_ranges: None,
+            spec_constants: BTreeMap::new(),
+            dynamic_state: DynamicStateSupport::default(),
+            enabled_features: vk::PhysicalDeviceFeatures::default(),
 
             desc_sets: Vec::new(),
         }
@@ -165,13 +309,13 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         self
     }
 
+    /// Use this layout instead of creating one.
+    /// `desc_set_layout` of every set and `push_constant_ranges` have to
+    /// describe it, since they are what gets checked against the shader.
+    /// The layout stays owned by the caller, who has to destroy it
+    /// after every material built with it.
     pub fn pipeline_layout(mut self, layout: vk::PipelineLayout) -> Self {
-        let old_layout = self.pipeline_layout.replace(layout);
-        if let Some(layout) = old_layout {
-            unsafe {
-                self.device.destroy_pipeline_layout(layout, None);
-            }
-        }
+        self.pipeline_layout = Some(layout);
         self
     }
 
@@ -181,6 +325,14 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         self
     }
 
+    /// Shaders with tessellation stages draw patches of this many vertices
+    pub fn patch_control_points(mut self, count: u32) -> Self {
+        self.input_assembly.topology = vk::PrimitiveTopology::PATCH_LIST;
+        self.input_assembly.primitive_restart_enable = vk::FALSE;
+        self.tessellation.patch_control_points = count;
+        self
+    }
+
     pub fn polygon_mode(mut self, mode: vk::PolygonMode) -> Self {
         self.rasterization.polygon_mode = mode;
         self.rasterization.line_width = 1.0;
@@ -197,11 +349,23 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         self
     }
 
-    pub fn disable_multisampling(mut self) -> Self {
-        self.multisample.sample_shading_enable = vk::FALSE;
-        // 1 sample per pixel means no multisampling
-        self.multisample.rasterization_samples = vk::SampleCountFlags::TYPE_1;
-        self.multisample.min_sample_shading = 1.0;
+    /// Must match the sample count of the attachments drawn into.
+    /// 1 sample per pixel means no multisampling.
+    /// With `min_sample_shading` at least that fraction of the samples
+    /// is shaded individually, which needs the sampleRateShading feature.
+    pub fn multisampling(
+        mut self,
+        samples: vk::SampleCountFlags,
+        min_sample_shading: Option<f32>,
+    ) -> Self {
+        self.multisample.rasterization_samples = samples;
+        self.multisample.sample_shading_enable = if min_sample_shading.is_some()
+        {
+            vk::TRUE
+        } else {
+            vk::FALSE
+        };
+        self.multisample.min_sample_shading = min_sample_shading.unwrap_or(1.0);
         self.multisample.p_sample_mask = std::ptr::null();
         self.multisample.alpha_to_coverage_enable = vk::FALSE;
         self.multisample.alpha_to_one_enable = vk::FALSE;
@@ -232,15 +396,7 @@ impl<'a> GraphicsMaterialBuilder<'a> {
     }
 
     pub fn enable_additive_blending(mut self) -> Self {
-        let blend = &mut self.color_blend_attachment;
-        blend.color_write_mask = vk::ColorComponentFlags::RGBA;
-        blend.blend_enable = vk::TRUE;
-        blend.src_color_blend_factor = vk::BlendFactor::ONE;
-        blend.dst_color_blend_factor = vk::BlendFactor::DST_ALPHA;
-        blend.color_blend_op = vk::BlendOp::ADD;
-        blend.src_alpha_blend_factor = vk::BlendFactor::ONE;
-        blend.dst_alpha_blend_factor = vk::BlendFactor::ZERO;
-        blend.alpha_blend_op = vk::BlendOp::ADD;
+        self.color_blend_attachment = additive_blend_attachment();
         self
     }
 
@@ -253,8 +409,15 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         self
     }
 
+    /// Also the stencil attachment format if the format has stencil
     pub fn depth_attachment_format(mut self, format: vk::Format) -> Self {
         self.rendering_info.depth_attachment_format = format;
+        self.rendering_info.stencil_attachment_format =
+            if vkutils::format_has_stencil(format) {
+                format
+            } else {
+                vk::Format::UNDEFINED
+            };
         self
     }
 
@@ -281,13 +444,105 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         self
     }
 
+    /// Enable stencil testing with separate ops for front and back faces.
+    /// Without a stencil attachment the test always passes.
+    pub fn stencil_test(
+        mut self,
+        front: vk::StencilOpState,
+        back: vk::StencilOpState,
+    ) -> Self {
+        self.depth_stencil.stencil_test_enable = vk::TRUE;
+        self.depth_stencil.front = front;
+        self.depth_stencil.back = back;
+        self
+    }
+
+    /// Only write depth and stencil, e.g. to mask out an area.
+    /// Call this after setting the blend mode.
+    pub fn disable_color_writes(mut self) -> Self {
+        self.color_blend_attachment.color_write_mask =
+            vk::ColorComponentFlags::empty();
+        self
+    }
+
     pub fn vertex_input(mut self, desc: VertexInputDescription) -> Self {
         self.vertex_input_desc = desc;
-        self.vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_attribute_descriptions(&self.vertex_input_desc.attributes)
-            .vertex_binding_descriptions(&self.vertex_input_desc.bindings)
-            .flags(self.vertex_input_desc.flags)
-            .build();
+        self
+    }
+
+    /// Layout of `set` together with its bindings, for sets whose layout
+    /// can't be generated from the shader, e.g. bindless arrays.
+    /// It is checked against the shader when the material is built,
+    /// and every other set the shader uses gets a generated layout,
+    /// unless a pipeline layout is provided.
+    pub fn desc_set_layout(
+        mut self,
+        set: u32,
+        layout: vk::DescriptorSetLayout,
+        bindings: Vec<DescriptorBinding>,
+    ) -> Self {
+        self.desc_set_layouts.insert(set, (layout, bindings));
+        self
+    }
+
+    /// Generate the layout of `set` for push descriptors,
+    /// so that `Material::push_desc_set` pushes instead of binding a set.
+    /// None if the device doesn't support push descriptors.
+    pub fn push_descriptor_set(mut self, set: Option<u32>) -> Self {
+        self.push_descriptor_set = set;
+        self
+    }
+
+    /// Generated from the shader if not provided,
+    /// or none if a pipeline layout is provided.
+    pub fn push_constant_ranges(
+        mut self,
+        ranges: Vec<vk::PushConstantRange>,
+    ) -> Self {
+        self.push_constant_ranges = Some(ranges);
+        self
+    }
+
+    /// Specialization constants of one shader stage.
+    /// They are checked against the constants the stage declares
+    /// when the material is built.
+    /// Empty constants remove the ones set before, so that they share
+    /// a pipeline with materials that never set any.
+    pub fn specialization(
+        mut self,
+        stage: vk::ShaderStageFlags,
+        constants: SpecializationConstants,
+    ) -> Self {
+        if constants.is_empty() {
+            self.spec_constants.remove(&stage);
+        } else {
+            self.spec_constants.insert(stage, constants);
+        }
+        self
+    }
+
+    /// Cull mode, front face, topology and depth testing are always set
+    /// when the material is bound instead of being baked into the pipeline,
+    /// color blending too if the device supports it,
+    /// so materials that only differ in that state share a pipeline.
+    /// The other state set on this builder becomes the material's
+    /// initial dynamic state.
+    pub fn extended_dynamic_state(
+        mut self,
+        support: DynamicStateSupport,
+    ) -> Self {
+        self.dynamic_state = support;
+        self
+    }
+
+    /// Device features the shader stages may need.
+    /// Shaders with geometry or tessellation stages fail to build
+    /// unless the matching feature is enabled.
+    pub fn enabled_features(
+        mut self,
+        features: vk::PhysicalDeviceFeatures,
+    ) -> Self {
+        self.enabled_features = features;
         self
     }
 
@@ -296,30 +551,226 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         self
     }
 
-    pub fn build(mut self) -> Result<Material> {
-        let device = self.device;
+    /// Reuse a pipeline from the cache if one was built from the same state,
+    /// otherwise create it and add it to the cache.
+    /// Layouts of sets the material doesn't supply come from `layout_cache`.
+    pub fn build(
+        self,
+        cache: &mut PipelineCache,
+        layout_cache: &mut DescriptorSetLayoutCache,
+    ) -> Result<Material> {
+        let key = self.pipeline_key()?;
+        let dynamic_state = self.dynamic_graphics_state();
+        if let Some(pipeline) = cache.get(&key) {
+            return Ok(Material::new(pipeline, Some(dynamic_state)));
+        }
+
+        let pipeline = self.create_pipeline(layout_cache)?;
+        Ok(Material::new(
+            cache.insert(key, pipeline),
+            Some(dynamic_state),
+        ))
+    }
+
+    fn dynamic_graphics_state(&self) -> DynamicGraphicsState {
+        DynamicGraphicsState {
+            cull_mode: self.rasterization.cull_mode,
+            front_face: self.rasterization.front_face,
+            topology: self.input_assembly.topology,
+            depth_test: self.depth_stencil.depth_test_enable == vk::TRUE,
+            depth_write: self.depth_stencil.depth_write_enable == vk::TRUE,
+            depth_compare_op: self.depth_stencil.depth_compare_op,
+            blend: self
+                .dynamic_state
+                .color_blend
+                .then(|| (&self.color_blend_attachment).into()),
+        }
+    }
 
+    fn pipeline_key(&self) -> Result<PipelineKey> {
         let shader = self
             .shader
-            .take()
+            .as_ref()
+            .ok_or_eyre("No shader provided for GraphicsMaterialBuilder")?;
+        let color_format = if self.rendering_info.color_attachment_count > 0 {
+            Some(self.color_attachment_format)
+        } else {
+            None
+        };
+
+        let mut graphics = GraphicsStateKey {
+            vertex_input: VertexInputKey::from(&self.vertex_input_desc),
+            topology: self.input_assembly.topology,
+            primitive_restart: self.input_assembly.primitive_restart_enable,
+            patch_control_points: self.tessellation.patch_control_points,
+            polygon_mode: self.rasterization.polygon_mode,
+            cull_mode: self.rasterization.cull_mode,
+            front_face: self.rasterization.front_face,
+            line_width: self.rasterization.line_width.to_bits(),
+            samples: self.multisample.rasterization_samples,
+            sample_shading: self.multisample.sample_shading_enable,
+            min_sample_shading: self.multisample.min_sample_shading.to_bits(),
+            blend: (&self.color_blend_attachment).into(),
+            depth_test: self.depth_stencil.depth_test_enable,
+            depth_write: self.depth_stencil.depth_write_enable,
+            depth_compare_op: self.depth_stencil.depth_compare_op,
+            stencil_test: self.depth_stencil.stencil_test_enable,
+            stencil_front: (&self.depth_stencil.front).into(),
+            stencil_back: (&self.depth_stencil.back).into(),
+            color_format,
+            depth_format: self.rendering_info.depth_attachment_format,
+            stencil_format: self.rendering_info.stencil_attachment_format,
+            dynamic_state: self.dynamic_state,
+        };
+        // Dynamic state doesn't affect the pipeline,
+        // only the topology class has to match
+        graphics.cull_mode = vk::CullModeFlags::NONE;
+        graphics.front_face = vk::FrontFace::CLOCKWISE;
+        graphics.topology = topology_class(graphics.topology);
+        graphics.depth_test = vk::FALSE;
+        graphics.depth_write = vk::FALSE;
+        graphics.depth_compare_op = vk::CompareOp::ALWAYS;
+        if self.dynamic_state.color_blend {
+            graphics.blend =
+                (&vk::PipelineColorBlendAttachmentState::default()).into();
+        }
+
+        Ok(PipelineKey {
+            shaders: shader.stage_keys.clone(),
+            spec_constants: self.spec_constants.clone(),
+            layout: layout_key(
+                self.pipeline_layout,
+                &self.desc_set_layouts,
+                self.push_descriptor_set,
+                self.push_constant_ranges.as_deref(),
+            ),
+            graphics: Some(graphics),
+        })
+    }
+
+    fn create_pipeline(
+        mut self,
+        layout_cache: &mut DescriptorSetLayoutCache,
+    ) -> Result<Pipeline> {
+        let device = self.device;
+
+        let reflected = self
+            .shader
+            .as_ref()
             .ok_or_eyre("No shader provided for GraphicsMaterialBuilder")?;
+        // Tessellation stages consume patches and nothing else does
+        let patches =
+            self.input_assembly.topology == vk::PrimitiveTopology::PATCH_LIST;
+        if reflected.has_tessellation() && !patches {
+            return Err(eyre!(
+                "Shader {} has tessellation stages but the material does not set patch_control_points",
+                reflected.name
+            ));
+        } else if !reflected.has_tessellation() && patches {
+            return Err(eyre!(
+                "Material draws patches but shader {} has no tessellation stages",
+                reflected.name
+            ));
+        }
+        if reflected.has_tessellation()
+            && self.enabled_features.tessellation_shader != vk::TRUE
+        {
+            return Err(eyre!(
+                "Shader {} has tessellation stages but the tessellationShader feature is not enabled",
+                reflected.name
+            ));
+        }
+        if reflected.geom_shader_mod.is_some()
+            && self.enabled_features.geometry_shader != vk::TRUE
+        {
+            return Err(eyre!(
+                "Shader {} has a geometry stage but the geometryShader feature is not enabled",
+                reflected.name
+            ));
+        }
+        reflected
+            .reflection
+            .check_vertex_input(&self.vertex_input_desc)
+            .with_context(|| {
+                format!("Shader {} does not match material", reflected.name)
+            })?;
+        let vertex_input_desc = &self.vertex_input_desc;
+        let owns_layout = self.pipeline_layout.is_none();
+        let (pipeline_layout, set_layouts) = match self.pipeline_layout {
+            Some(pipeline_layout) => {
+                let set_layouts = check_supplied_pipeline_layout(
+                    &reflected.reflection,
+                    &self.desc_set_layouts,
+                    self.push_constant_ranges.as_deref(),
+                )
+                .with_context(|| {
+                    format!("Shader {} does not match material", reflected.name)
+                })?;
+                (pipeline_layout, set_layouts)
+            }
+            None => create_pipeline_layout(
+                device,
+                &reflected.reflection,
+                &self.desc_set_layouts,
+                self.push_descriptor_set,
+                self.push_constant_ranges.as_deref(),
+                layout_cache,
+            )
+            .with_context(|| {
+                format!("Shader {} does not match material", reflected.name)
+            })?,
+        };
+
+        let spec_data = match specialization_data(
+            &reflected.reflection,
+            &self.spec_constants,
+        ) {
+            Ok(spec_data) => spec_data,
+            Err(err) => {
+                if owns_layout {
+                    unsafe {
+                        device.destroy_pipeline_layout(pipeline_layout, None)
+                    };
+                }
+                return Err(err.wrap_err(format!(
+                    "Shader {} does not match material",
+                    reflected.name
+                )));
+            }
+        };
+        let spec_infos = spec_data
+            .iter()
+            .map(|(stage, data)| (*stage, data.info()))
+            .collect::<BTreeMap<_, _>>();
+
+        let shader = self.shader.take().unwrap();
         let shader_main_fn_name = CString::new("main").unwrap();
-        let shader_stages = vec![
-            vk::PipelineShaderStageCreateInfo::builder()
-                .stage(vk::ShaderStageFlags::VERTEX)
-                .module(shader.vert_shader_mod)
-                .name(&shader_main_fn_name)
-                .build(),
-            vk::PipelineShaderStageCreateInfo::builder()
-                .stage(vk::ShaderStageFlags::FRAGMENT)
-                .module(shader.frag_shader_mod)
-                .name(&shader_main_fn_name)
-                .build(),
-        ];
+        let shader_stages = shader
+            .stages()
+            .into_iter()
+            .map(|(stage, module)| {
+                let mut info = vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(stage)
+                    .module(module)
+                    .name(&shader_main_fn_name);
+                if let Some(spec_info) = spec_infos.get(&stage) {
+                    info = info.specialization_info(spec_info);
+                }
+                info.build()
+            })
+            .collect::<Vec<_>>();
 
-        let pipeline_layout = self.pipeline_layout.take().ok_or_eyre(
-            "No pipeline layout provided for GraphicsMaterialBuilder",
-        )?;
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_attribute_descriptions(&vertex_input_desc.attributes)
+            .vertex_binding_descriptions(&vertex_input_desc.bindings)
+            .flags(vertex_input_desc.flags)
+            .build();
+
+        // The builder may have moved since the format was set
+        if self.rendering_info.color_attachment_count > 0 {
+            self.rendering_info.p_color_attachment_formats =
+                &self.color_attachment_format;
+        }
 
         let viewport_state = vk::PipelineViewportStateCreateInfo {
             viewport_count: 1,
@@ -336,8 +787,24 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         };
 
         // Use dynamic state for viewport and scissor configuration
-        let dynamic_states =
-            [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        // and the extended dynamic state that is core in Vulkan 1.3
+        let mut dynamic_states = vec![
+            vk::DynamicState::VIEWPORT,
+            vk::DynamicState::SCISSOR,
+            vk::DynamicState::CULL_MODE,
+            vk::DynamicState::FRONT_FACE,
+            vk::DynamicState::PRIMITIVE_TOPOLOGY,
+            vk::DynamicState::DEPTH_TEST_ENABLE,
+            vk::DynamicState::DEPTH_WRITE_ENABLE,
+            vk::DynamicState::DEPTH_COMPARE_OP,
+        ];
+        if self.dynamic_state.color_blend {
+            dynamic_states.extend([
+                vk::DynamicState::COLOR_BLEND_ENABLE_EXT,
+                vk::DynamicState::COLOR_BLEND_EQUATION_EXT,
+                vk::DynamicState::COLOR_WRITE_MASK_EXT,
+            ]);
+        }
         let dynamic_info = vk::PipelineDynamicStateCreateInfo::builder()
             .dynamic_states(&dynamic_states)
             .build();
@@ -346,8 +813,9 @@ impl<'a> GraphicsMaterialBuilder<'a> {
             .push_next(&mut self.rendering_info)
             .stages(&shader_stages)
             .layout(pipeline_layout)
-            .vertex_input_state(&self.vertex_input)
+            .vertex_input_state(&vertex_input)
             .input_assembly_state(&self.input_assembly)
+            .tessellation_state(&self.tessellation)
             .viewport_state(&viewport_state)
             .rasterization_state(&self.rasterization)
             .multisample_state(&self.multisample)
@@ -368,10 +836,12 @@ impl<'a> GraphicsMaterialBuilder<'a> {
         }?[0];
         shader.cleanup(device);
 
-        Ok(Material {
+        Ok(Pipeline {
             pipeline,
-            pipeline_layout,
-            pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+            layout: pipeline_layout,
+            owns_layout,
+            set_layouts,
+            bind_point: vk::PipelineBindPoint::GRAPHICS,
         })
     }
 
@@ -383,6 +853,13 @@ impl<'a> GraphicsMaterialBuilder<'a> {
             .build()
     }
 
+    /// Only used by pipelines with tessellation stages
+    fn default_tessellation_info() -> vk::PipelineTessellationStateCreateInfo {
+        vk::PipelineTessellationStateCreateInfo::builder()
+            .patch_control_points(3)
+            .build()
+    }
+
     fn default_rasterization_info() -> vk::PipelineRasterizationStateCreateInfo
     {
         vk::PipelineRasterizationStateCreateInfo::builder()
@@ -442,13 +919,6 @@ impl<'a> GraphicsMaterialBuilder<'a> {
 
 impl<'a> Drop for GraphicsMaterialBuilder<'a> {
     fn drop(&mut self) {
-        // Destroy pipeline layout in case it was never used
-        if let Some(layout) = self.pipeline_layout.take() {
-            unsafe {
-                self.device.destroy_pipeline_layout(layout, None);
-            }
-        }
-
         // Destroy shader in case it was never used
         if let Some(shader) = self.shader.take() {
             shader.cleanup(self.device);
@@ -460,6 +930,10 @@ pub struct ComputeMaterialBuilder<'a> {
     device: &'a ash::Device,
     shader: Option<ComputeShader>,
     pipeline_layout: Option<vk::PipelineLayout>,
+    desc_set_layouts: SuppliedSetLayouts,
+    push_descriptor_set: Option<u32>,
+    push_constant_ranges: Option<Vec<vk::PushConstantRange>>,
+    spec_constants: SpecializationConstants,
 }
 
 impl<'a> ComputeMaterialBuilder<'a> {
@@ -468,6 +942,10 @@ impl<'a> ComputeMaterialBuilder<'a> {
             device,
             shader: None,
             pipeline_layout: None,
+            desc_set_layouts: BTreeMap::new(),
+            push_descriptor_set: None,
+            push_constant_ranges: None,
+            spec_constants: SpecializationConstants::new(),
         }
     }
 
@@ -479,30 +957,142 @@ impl<'a> ComputeMaterialBuilder<'a> {
         self
     }
 
+    /// Same as `GraphicsMaterialBuilder::pipeline_layout`
     pub fn pipeline_layout(mut self, layout: vk::PipelineLayout) -> Self {
-        let old_layout = self.pipeline_layout.replace(layout);
-        if let Some(layout) = old_layout {
-            unsafe {
-                self.device.destroy_pipeline_layout(layout, None);
-            }
-        }
+        self.pipeline_layout = Some(layout);
+        self
+    }
+
+    /// Same as `GraphicsMaterialBuilder::desc_set_layout`
+    pub fn desc_set_layout(
+        mut self,
+        set: u32,
+        layout: vk::DescriptorSetLayout,
+        bindings: Vec<DescriptorBinding>,
+    ) -> Self {
+        self.desc_set_layouts.insert(set, (layout, bindings));
+        self
+    }
+
+    /// Same as `GraphicsMaterialBuilder::push_descriptor_set`
+    pub fn push_descriptor_set(mut self, set: Option<u32>) -> Self {
+        self.push_descriptor_set = set;
+        self
+    }
+
+    /// Same as `GraphicsMaterialBuilder::push_constant_ranges`
+    pub fn push_constant_ranges(
+        mut self,
+        ranges: Vec<vk::PushConstantRange>,
+    ) -> Self {
+        self.push_constant_ranges = Some(ranges);
+        self
+    }
+
+    /// Specialization constants of the compute stage,
+    /// e.g. the workgroup size
+    pub fn specialization(
+        mut self,
+        constants: SpecializationConstants,
+    ) -> Self {
+        self.spec_constants = constants;
         self
     }
 
-    pub fn build(mut self) -> Result<Material> {
+    /// Same as `GraphicsMaterialBuilder::build`
+    pub fn build(
+        self,
+        cache: &mut PipelineCache,
+        layout_cache: &mut DescriptorSetLayoutCache,
+    ) -> Result<Material> {
         let shader = self
             .shader
-            .take()
+            .as_ref()
             .ok_or_eyre("No shader provided for ComputeMaterialBuilder")?;
-        let pipeline_layout = self.pipeline_layout.take().ok_or_eyre(
-            "No pipeline layout provided for ComputeMaterialBuilder",
-        )?;
+        let key = PipelineKey {
+            shaders: vec![shader.key.clone()],
+            spec_constants: if self.spec_constants.is_empty() {
+                BTreeMap::new()
+            } else {
+                BTreeMap::from([(
+                    vk::ShaderStageFlags::COMPUTE,
+                    self.spec_constants.clone(),
+                )])
+            },
+            layout: layout_key(
+                self.pipeline_layout,
+                &self.desc_set_layouts,
+                self.push_descriptor_set,
+                self.push_constant_ranges.as_deref(),
+            ),
+            graphics: None,
+        };
+        if let Some(pipeline) = cache.get(&key) {
+            return Ok(Material::new(pipeline, None));
+        }
+
+        let pipeline = self.create_pipeline(layout_cache)?;
+        Ok(Material::new(cache.insert(key, pipeline), None))
+    }
+
+    fn create_pipeline(
+        mut self,
+        layout_cache: &mut DescriptorSetLayoutCache,
+    ) -> Result<Pipeline> {
+        let reflected = self
+            .shader
+            .as_ref()
+            .ok_or_eyre("No shader provided for ComputeMaterialBuilder")?;
+        let owns_layout = self.pipeline_layout.is_none();
+        let (pipeline_layout, set_layouts) = match self.pipeline_layout {
+            Some(pipeline_layout) => {
+                let set_layouts = check_supplied_pipeline_layout(
+                    &reflected.reflection,
+                    &self.desc_set_layouts,
+                    self.push_constant_ranges.as_deref(),
+                )
+                .with_context(|| {
+                    format!("Shader {} does not match material", reflected.name)
+                })?;
+                (pipeline_layout, set_layouts)
+            }
+            None => create_pipeline_layout(
+                self.device,
+                &reflected.reflection,
+                &self.desc_set_layouts,
+                self.push_descriptor_set,
+                self.push_constant_ranges.as_deref(),
+                layout_cache,
+            )
+            .with_context(|| {
+                format!("Shader {} does not match material", reflected.name)
+            })?,
+        };
+        if let Err(err) = reflected.reflection.check_spec_constants(
+            vk::ShaderStageFlags::COMPUTE,
+            &self.spec_constants,
+        ) {
+            if owns_layout {
+                unsafe {
+                    self.device.destroy_pipeline_layout(pipeline_layout, None)
+                };
+            }
+            return Err(err.wrap_err(format!(
+                "Shader {} does not match material",
+                reflected.name
+            )));
+        }
+        let spec_data = self.spec_constants.pack();
+        let spec_info = spec_data.info();
+
+        let shader = self.shader.take().unwrap();
 
         let name = CString::new("main")?;
         let stage_info = vk::PipelineShaderStageCreateInfo::builder()
             .stage(vk::ShaderStageFlags::COMPUTE)
             .module(shader.shader_mod)
             .name(&name)
+            .specialization_info(&spec_info)
             .build();
 
         let pipeline_info = vk::ComputePipelineCreateInfo::builder()
@@ -521,23 +1111,18 @@ impl<'a> ComputeMaterialBuilder<'a> {
         }?[0];
         shader.cleanup(self.device);
 
-        Ok(Material {
+        Ok(Pipeline {
             pipeline,
-            pipeline_layout,
-            pipeline_bind_point: vk::PipelineBindPoint::COMPUTE,
+            layout: pipeline_layout,
+            owns_layout,
+            set_layouts,
+            bind_point: vk::PipelineBindPoint::COMPUTE,
         })
     }
 }
 
 impl<'a> Drop for ComputeMaterialBuilder<'a> {
     fn drop(&mut self) {
-        // Destroy pipeline layout in case it was never used
-        if let Some(layout) = self.pipeline_layout.take() {
-            unsafe {
-                self.device.destroy_pipeline_layout(layout, None);
-            }
-        }
-
         // Destroy shader in case it was never used
         if let Some(shader) = self.shader.take() {
             shader.cleanup(self.device);
@@ -545,103 +1130,429 @@ impl<'a> Drop for ComputeMaterialBuilder<'a> {
     }
 }
 
-/// To be written into uniform buffers
-struct MaterialConstants {
-    color_factors: Vec4,
-    metal_rough_factors: Vec4,
-    padding: [Vec4; 14], // Padding to 256 bytes
+fn additive_blend_attachment() -> vk::PipelineColorBlendAttachmentState {
+    vk::PipelineColorBlendAttachmentState {
+        color_write_mask: vk::ColorComponentFlags::RGBA,
+        blend_enable: vk::TRUE,
+        src_color_blend_factor: vk::BlendFactor::ONE,
+        dst_color_blend_factor: vk::BlendFactor::DST_ALPHA,
+        color_blend_op: vk::BlendOp::ADD,
+        src_alpha_blend_factor: vk::BlendFactor::ONE,
+        dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+        alpha_blend_op: vk::BlendOp::ADD,
+    }
 }
 
-struct MaterialResources {
-    color_image: AllocatedImage,
-    color_sampler: vk::Sampler,
-    metal_rough_image: AllocatedImage,
-    metal_rough_sampler: vk::Sampler,
-    data_buffer: vk::Buffer,
-    data_buffer_offset: u32,
+/// Topologies of a class can replace each other with dynamic state
+fn topology_class(topology: vk::PrimitiveTopology) -> vk::PrimitiveTopology {
+    match topology {
+        vk::PrimitiveTopology::LINE_LIST
+        | vk::PrimitiveTopology::LINE_STRIP
+        | vk::PrimitiveTopology::LINE_LIST_WITH_ADJACENCY
+        | vk::PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY => {
+            vk::PrimitiveTopology::LINE_LIST
+        }
+        vk::PrimitiveTopology::TRIANGLE_LIST
+        | vk::PrimitiveTopology::TRIANGLE_STRIP
+        | vk::PrimitiveTopology::TRIANGLE_FAN
+        | vk::PrimitiveTopology::TRIANGLE_LIST_WITH_ADJACENCY
+        | vk::PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY => {
+            vk::PrimitiveTopology::TRIANGLE_LIST
+        }
+        // Points and patches are classes of their own
+        topology => topology,
+    }
+}
+
+/// Part of the pipeline key describing the layout a builder would create
+fn layout_key(
+    pipeline_layout: Option<vk::PipelineLayout>,
+    supplied_layouts: &SuppliedSetLayouts,
+    push_descriptor_set: Option<u32>,
+    supplied_push_constants: Option<&[vk::PushConstantRange]>,
+) -> PipelineLayoutKey {
+    match pipeline_layout {
+        Some(layout) => PipelineLayoutKey::Explicit(layout),
+        None => PipelineLayoutKey::Reflected {
+            set_layouts: supplied_layouts
+                .iter()
+                .map(|(set, (layout, _))| (*set, *layout))
+                .collect(),
+            push_descriptor_set,
+            push_constants: supplied_push_constants.map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|range| (range.stage_flags, range.offset, range.size))
+                    .collect()
+            }),
+        },
+    }
+}
+
+/// Packed constants of each stage,
+/// checked against the constants the stages declare
+fn specialization_data(
+    reflection: &ShaderReflection,
+    spec_constants: &BTreeMap<vk::ShaderStageFlags, SpecializationConstants>,
+) -> Result<BTreeMap<vk::ShaderStageFlags, SpecializationData>> {
+    spec_constants
+        .iter()
+        .map(|(stage, constants)| {
+            reflection.check_spec_constants(*stage, constants)?;
+            Ok((*stage, constants.pack()))
+        })
+        .collect()
 }
 
-struct GltfMetallicRoughness {
-    opaque_material: Material,
-    transparent_material: Material,
-    material_layout: vk::DescriptorSetLayout,
+/// Sets in the pipeline layout, including supplied sets the shader doesn't use
+fn set_count(
+    reflection: &ShaderReflection,
+    supplied_layouts: &SuppliedSetLayouts,
+) -> u32 {
+    supplied_layouts
+        .keys()
+        .next_back()
+        .map_or(0, |set| set + 1)
+        .max(reflection.set_count())
+}
+
+/// Error if the supplied set layouts or push constant ranges
+/// don't cover what the shader uses
+fn check_pipeline_layout(
+    reflection: &ShaderReflection,
+    supplied_layouts: &SuppliedSetLayouts,
+    supplied_push_constants: Option<&[vk::PushConstantRange]>,
+) -> Result<()> {
+    for (set, (_, bindings)) in supplied_layouts {
+        reflection.check_desc_set_layout(*set, bindings)?;
+    }
+    if let Some(ranges) = supplied_push_constants {
+        reflection.check_push_constants(ranges)?;
+    }
+    Ok(())
+}
+
+/// Like `check_pipeline_layout`, but nothing can be generated
+/// for a layout that already exists: it has no push constants
+/// unless ranges were supplied, and every set must be supplied.
+/// Returns the set layouts in set order.
+fn check_supplied_pipeline_layout(
+    reflection: &ShaderReflection,
+    supplied_layouts: &SuppliedSetLayouts,
+    supplied_push_constants: Option<&[vk::PushConstantRange]>,
+) -> Result<Vec<vk::DescriptorSetLayout>> {
+    let set_count = set_count(reflection, supplied_layouts);
+    let set_layouts = (0..set_count)
+        .map(|set| {
+            supplied_layouts
+                .get(&set)
+                .map(|(layout, _)| *layout)
+                .ok_or_else(|| {
+                    eyre!(
+                        "Shader uses {} descriptor sets but the pipeline layout does not describe set {}",
+                        set_count,
+                        set
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    check_pipeline_layout(
+        reflection,
+        supplied_layouts,
+        Some(supplied_push_constants.unwrap_or(&[])),
+    )?;
+    Ok(set_layouts)
+}
+
+/// Create a pipeline layout for a shader from the supplied set layouts and
+/// push constant ranges, checking both against the shader's reflection.
+/// Every other set the shader uses gets its layout from the layout cache.
+/// Returns the layout along with the layout of each set.
+fn create_pipeline_layout(
+    device: &ash::Device,
+    reflection: &ShaderReflection,
+    supplied_layouts: &SuppliedSetLayouts,
+    push_descriptor_set: Option<u32>,
+    supplied_push_constants: Option<&[vk::PushConstantRange]>,
+    layout_cache: &mut DescriptorSetLayoutCache,
+) -> Result<(vk::PipelineLayout, Vec<vk::DescriptorSetLayout>)> {
+    check_pipeline_layout(
+        reflection,
+        supplied_layouts,
+        supplied_push_constants,
+    )?;
+    if let Some(set) =
+        push_descriptor_set.filter(|set| supplied_layouts.contains_key(set))
+    {
+        return Err(eyre!(
+            "Set {} is supplied, so its layout can't be generated for push descriptors",
+            set
+        ));
+    }
+    let push_constant_ranges = match supplied_push_constants {
+        Some(ranges) => ranges.to_vec(),
+        None => reflection.push_constant_ranges(),
+    };
+
+    let set_count = set_count(reflection, supplied_layouts);
+    let set_layouts = (0..set_count)
+        .map(|set| match supplied_layouts.get(&set) {
+            Some((layout, _)) => Ok(*layout),
+            None => reflection.create_desc_set_layout(
+                set,
+                push_descriptor_set == Some(set),
+                device,
+                layout_cache,
+            ),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(&set_layouts)
+        .push_constant_ranges(&push_constant_ranges)
+        .build();
+    let layout = unsafe { device.create_pipeline_layout(&info, None)? };
+    Ok((layout, set_layouts))
+}
+
+/// Resources written into the descriptor set of a material instance.
+/// Textures are referenced by index from MaterialConstants.
+pub struct MaterialResources {
+    /// Holds MaterialConstants at data_buffer_offset
+    pub data_buffer: vk::Buffer,
+    pub data_buffer_offset: u32,
+}
+
+/// Material system for glTF metallic-roughness materials drawn with the
+/// mesh shader. Its materials live in RenderResources,
+/// so draws find them through MaterialInstance names.
+pub struct GltfMetallicRoughness {
     writer: DescriptorWriter,
 }
 
 impl GltfMetallicRoughness {
+    pub const OPAQUE_MATERIAL: &'static str = "gltf opaque";
+    pub const TRANSPARENT_MATERIAL: &'static str = "gltf transparent";
+    pub const SHADER: &'static str = "mesh";
+    /// Set of the mesh shader that material instances write
+    pub const MATERIAL_SET: u32 = 1;
+    /// Set of the mesh shader that holds the bindless textures
+    const TEXTURE_SET: u32 = 2;
+
     pub fn new(
         ctx: &Context,
-        swapchain: &Swapchain,
-        scene_layout: vk::DescriptorSetLayout,
+        resources: &mut RenderResources,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        msaa: Msaa,
     ) -> Result<Self> {
-        let shader = GraphicsShader::new("mesh.glsl", &ctx.device)?;
+        let materials = Self::build_materials(
+            ctx,
+            resources,
+            color_format,
+            depth_format,
+            msaa,
+        )?;
+        resources.replace_materials(materials, &ctx.device);
+
+        Ok(Self {
+            writer: DescriptorWriter::new(),
+        })
+    }
 
-        let matrix_range = [vk::PushConstantRange {
+    /// Build the opaque and transparent materials by name,
+    /// e.g. with another sample count, without replacing the current ones.
+    /// With dynamic blending and depth testing both share one pipeline.
+    /// Set 0 is pushed if possible and set 2 is the bindless texture array,
+    /// the layout of the material set is generated from the shader.
+    pub fn build_materials(
+        ctx: &Context,
+        resources: &mut RenderResources,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        msaa: Msaa,
+    ) -> Result<Vec<(String, Material)>> {
+        let device = &ctx.device;
+        let (texture_layout, texture_bindings) = resources.bindless_layout()?;
+        let push_descriptor_set = ctx.push_descriptor.is_some().then_some(0);
+        let push_constant_ranges = vec![vk::PushConstantRange {
             offset: 0,
-            size: std::mem::size_of::<GpuDrawPushConstants>() as u32,
+            size: GpuDrawPushConstants::SIZE,
             stage_flags: vk::ShaderStageFlags::VERTEX,
         }];
 
-        let material_layout = DescriptorSetLayoutBuilder::new()
-            .add_binding(
-                0,
-                vk::DescriptorType::UNIFORM_BUFFER,
-                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
-            )
-            .add_binding(
-                1,
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+        // Each material gets its own shader modules
+        // because building a material destroys its shader modules
+        let opaque_material = Material::builder_graphics(device)
+            .shader(GraphicsShader::new(Self::SHADER, &[], device)?)
+            .desc_set_layout(
+                Self::TEXTURE_SET,
+                texture_layout,
+                texture_bindings.clone(),
             )
-            .add_binding(
-                2,
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
-            )
-            .build(&ctx.device)?;
-
-        let set_layouts = [scene_layout, material_layout];
-        let mesh_pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(&set_layouts)
-            .push_constant_ranges(&matrix_range)
-            .build();
-        let mesh_pipeline_layout = unsafe {
-            ctx.device
-                .create_pipeline_layout(&mesh_pipeline_layout_info, None)?
-        };
-
-        let opaque_material = Material::builder_graphics(&ctx.device)
-            .shader(shader.clone())
+            .push_descriptor_set(push_descriptor_set)
+            .push_constant_ranges(push_constant_ranges.clone())
             .input_topology(vk::PrimitiveTopology::TRIANGLE_LIST)
             .polygon_mode(vk::PolygonMode::FILL)
-            .cull_mode(vk::CullModeFlags::BACK, vk::FrontFace::CLOCKWISE)
-            .disable_multisampling()
+            .cull_mode(vk::CullModeFlags::NONE, vk::FrontFace::CLOCKWISE)
+            .multisampling(msaa.samples, msaa.min_sample_shading)
             .disable_blending()
-            .depth_test_enable(true, Some(vk::CompareOp::GREATER_OR_EQUAL))
-            .color_attachment_format(swapchain.image_format)
-            .depth_attachment_format(swapchain.depth_image.format)
-            .pipeline_layout(mesh_pipeline_layout)
-            .build()?;
-
-        let transparent_material = Material::builder_graphics(&ctx.device)
-            .shader(shader)
-            .input_topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .cull_mode(vk::CullModeFlags::BACK, vk::FrontFace::CLOCKWISE)
-            .disable_multisampling()
-            .enable_additive_blending()
-            .depth_test_enable(false, None)
-            .color_attachment_format(swapchain.image_format)
-            .depth_attachment_format(swapchain.depth_image.format)
-            .pipeline_layout(mesh_pipeline_layout)
-            .build()?;
+            .depth_test_enable(true, Some(vk::CompareOp::LESS_OR_EQUAL))
+            .color_attachment_format(color_format)
+            .depth_attachment_format(depth_format)
+            .extended_dynamic_state(ctx.dynamic_state_support)
+            .build(
+                &mut resources.pipeline_cache,
+                &mut resources.desc_set_layout_cache,
+            )
+            .context("Failed to build opaque glTF material")?;
+
+        let transparent_material = if opaque_material.has_dynamic_blending() {
+            let mut material = opaque_material.clone();
+            let state = material.dynamic_state_mut()?;
+            state.blend = Some((&additive_blend_attachment()).into());
+            state.depth_test = false;
+            state.depth_write = false;
+            state.depth_compare_op = vk::CompareOp::ALWAYS;
+            Ok(material)
+        } else {
+            Material::builder_graphics(device)
+                .shader(GraphicsShader::new(Self::SHADER, &[], device)?)
+                .desc_set_layout(
+                    Self::TEXTURE_SET,
+                    texture_layout,
+                    texture_bindings,
+                )
+                .push_descriptor_set(push_descriptor_set)
+                .push_constant_ranges(push_constant_ranges)
+                .input_topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+                .polygon_mode(vk::PolygonMode::FILL)
+                .cull_mode(vk::CullModeFlags::NONE, vk::FrontFace::CLOCKWISE)
+                .multisampling(msaa.samples, msaa.min_sample_shading)
+                .enable_additive_blending()
+                .depth_test_enable(false, None)
+                .color_attachment_format(color_format)
+                .depth_attachment_format(depth_format)
+                .extended_dynamic_state(ctx.dynamic_state_support)
+                .build(
+                    &mut resources.pipeline_cache,
+                    &mut resources.desc_set_layout_cache,
+                )
+        };
+        let transparent_material = match transparent_material {
+            Ok(material) => material,
+            Err(err) => {
+                opaque_material.cleanup(device);
+                return Err(
+                    err.wrap_err("Failed to build transparent glTF material")
+                );
+            }
+        };
 
-        Ok(Self {
-            opaque_material,
-            transparent_material,
-            material_layout,
-            writer: DescriptorWriter::new(),
+        Ok(vec![
+            (Self::OPAQUE_MATERIAL.into(), opaque_material),
+            (Self::TRANSPARENT_MATERIAL.into(), transparent_material),
+        ])
+    }
+
+    /// Layout of the material set along with its bindings.
+    /// Both materials share it, since it comes from the same shader.
+    pub fn material_layout(
+        resources: &RenderResources,
+    ) -> Result<(vk::DescriptorSetLayout, Vec<DescriptorBinding>)> {
+        resources
+            .materials
+            .get(Self::OPAQUE_MATERIAL)
+            .and_then(|material| material.desc_set_layout(Self::MATERIAL_SET))
+            .and_then(|layout| {
+                let bindings =
+                    resources.desc_set_layout_cache.bindings(layout)?;
+                Some((layout, bindings.to_vec()))
+            })
+            .ok_or_eyre("glTF material layout not found")
+    }
+
+    /// Allocate and write the descriptor set of a material instance.
+    /// The set stays valid until the allocator's pools are cleared.
+    pub fn write_material(
+        &mut self,
+        device: &ash::Device,
+        pass: MaterialPass,
+        resources: &MaterialResources,
+        render_resources: &RenderResources,
+        desc_allocator: &mut DescriptorAllocator,
+    ) -> Result<MaterialInstance> {
+        let material_name = match pass {
+            MaterialPass::Transparent => Self::TRANSPARENT_MATERIAL,
+            MaterialPass::Opaque | MaterialPass::Other => Self::OPAQUE_MATERIAL,
+        };
+        let (layout, _) = Self::material_layout(render_resources)?;
+        let desc_set = desc_allocator.allocate(device, layout)?;
+
+        self.writer.clear();
+        self.writer.write_buffer(
+            0,
+            resources.data_buffer,
+            std::mem::size_of::<MaterialConstants>() as u64,
+            resources.data_buffer_offset as u64,
+            vk::DescriptorType::UNIFORM_BUFFER,
+        );
+        self.writer.update_set(device, desc_set);
+
+        Ok(MaterialInstance {
+            material_name: material_name.into(),
+            desc_set,
+            pass,
         })
     }
-    fn clear_resources(ctx: &Context) {}
+
+    /// Remove the materials this system registered.
+    /// Material instances written by it must not be drawn afterwards.
+    pub fn clear_resources(
+        &self,
+        device: &ash::Device,
+        resources: &mut RenderResources,
+    ) {
+        for name in [Self::OPAQUE_MATERIAL, Self::TRANSPARENT_MATERIAL] {
+            if let Some(material) = resources.materials.remove(name) {
+                material.cleanup(device);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::topology_class;
+
+    #[test]
+    fn test_topology_class() {
+        let class = topology_class;
+        assert_eq!(
+            class(vk::PrimitiveTopology::TRIANGLE_STRIP),
+            class(vk::PrimitiveTopology::TRIANGLE_LIST)
+        );
+        assert_eq!(
+            class(vk::PrimitiveTopology::TRIANGLE_FAN),
+            class(vk::PrimitiveTopology::TRIANGLE_LIST_WITH_ADJACENCY)
+        );
+        assert_eq!(
+            class(vk::PrimitiveTopology::LINE_STRIP),
+            class(vk::PrimitiveTopology::LINE_LIST)
+        );
+        assert_ne!(
+            class(vk::PrimitiveTopology::LINE_LIST),
+            class(vk::PrimitiveTopology::TRIANGLE_LIST)
+        );
+        assert_ne!(
+            class(vk::PrimitiveTopology::POINT_LIST),
+            class(vk::PrimitiveTopology::PATCH_LIST)
+        );
+        assert_ne!(
+            class(vk::PrimitiveTopology::POINT_LIST),
+            class(vk::PrimitiveTopology::LINE_LIST)
+        );
+    }
 }
@@ -0,0 +1,603 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ash::vk;
+use color_eyre::eyre::{Context as _, OptionExt, Result};
+use serde::Deserialize;
+
+use super::{
+    attachments::Msaa, context::Context, material::Material,
+    render_resources::RenderResources, shader::GraphicsShader,
+    specialization::SpecializationConstants, ASSETS_DIR,
+};
+
+/// Subdirectory of the assets directory that holds material files
+const MATERIALS_DIR: &str = "materials";
+const MATERIAL_FILE_EXTENSION: &str = "ron";
+
+/// Description of a graphics material loaded from a RON file.
+/// The material is registered under the file stem,
+/// e.g. `assets/materials/textured.ron` becomes "textured".
+/// Descriptor set layouts are generated from the shader,
+/// set 0 is the scene data that frames push into every material.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDef {
+    /// Name of the compiled combined shader (without the -vert/-frag stage suffix)
+    pub shader: String,
+    /// Permutation keywords of the shader variant to use
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Set the shader declares the bindless texture array in, if any
+    #[serde(default)]
+    pub bindless_textures_set: Option<u32>,
+    /// Generated from the shader if None
+    #[serde(default)]
+    pub push_constants: Option<Vec<PushConstantDef>>,
+    /// e.g. light counts and feature toggles
+    #[serde(default)]
+    pub spec_constants: Vec<SpecConstantDef>,
+    #[serde(default)]
+    pub blend: BlendMode,
+    /// Depth testing and writing is disabled if None
+    #[serde(default = "default_depth_test")]
+    pub depth_test: Option<CompareOp>,
+    /// Stencil testing is disabled if None
+    #[serde(default)]
+    pub stencil: Option<StencilDef>,
+    /// Disable to only write depth and stencil
+    #[serde(default = "default_color_write")]
+    pub color_write: bool,
+    #[serde(default)]
+    pub cull_mode: CullMode,
+    #[serde(default)]
+    pub front_face: FrontFace,
+    #[serde(default)]
+    pub polygon_mode: PolygonMode,
+    /// PatchList for shaders with tessellation stages
+    #[serde(default)]
+    pub topology: Topology,
+    /// Vertices per patch if the topology is PatchList
+    #[serde(default = "default_patch_control_points")]
+    pub patch_control_points: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PushConstantDef {
+    pub stages: Vec<ShaderStage>,
+    #[serde(default)]
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpecConstantDef {
+    pub stage: ShaderStage,
+    /// constant_id in the shader
+    pub id: u32,
+    pub value: SpecConstantValueDef,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum SpecConstantValueDef {
+    Bool(bool),
+    Int(i32),
+    UInt(u32),
+    Float(f32),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum ShaderStage {
+    Vertex,
+    TessControl,
+    TessEval,
+    Geometry,
+    Fragment,
+}
+
+/// Alpha blending is the default, same as `GraphicsMaterialBuilder`
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum BlendMode {
+    Opaque,
+    #[default]
+    Alpha,
+    Additive,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum CompareOp {
+    Never,
+    Less,
+    Equal,
+    LessOrEqual,
+    Greater,
+    NotEqual,
+    GreaterOrEqual,
+    Always,
+}
+
+/// Same ops for front and back faces, all stencil bits are compared
+/// and written
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StencilDef {
+    pub compare: CompareOp,
+    #[serde(default)]
+    pub reference: u32,
+    #[serde(default)]
+    pub fail: StencilOp,
+    #[serde(default)]
+    pub pass: StencilOp,
+    #[serde(default)]
+    pub depth_fail: StencilOp,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum StencilOp {
+    #[default]
+    Keep,
+    Zero,
+    Replace,
+    IncrementAndClamp,
+    DecrementAndClamp,
+    Invert,
+    IncrementAndWrap,
+    DecrementAndWrap,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum CullMode {
+    #[default]
+    None,
+    Front,
+    Back,
+    FrontAndBack,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum FrontFace {
+    #[default]
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum PolygonMode {
+    #[default]
+    Fill,
+    Line,
+    Point,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum Topology {
+    PointList,
+    LineList,
+    LineStrip,
+    #[default]
+    TriangleList,
+    TriangleStrip,
+    TriangleFan,
+    PatchList,
+}
+
+fn default_depth_test() -> Option<CompareOp> {
+    Some(CompareOp::LessOrEqual)
+}
+
+fn default_color_write() -> bool {
+    true
+}
+
+fn default_patch_control_points() -> u32 {
+    3
+}
+
+impl MaterialDef {
+    pub fn from_ron(source: &str) -> Result<Self> {
+        Ok(ron::from_str(source)?)
+    }
+
+    /// Load every material file in the materials directory of the assets
+    /// directory, keyed by file stem
+    pub fn load_all() -> Result<HashMap<String, MaterialDef>> {
+        let assets_dir = unsafe {
+            ASSETS_DIR
+                .as_ref()
+                .ok_or_eyre("Assets directory not specified")?
+        };
+        let mut materials_dir = PathBuf::from(assets_dir);
+        materials_dir.push(MATERIALS_DIR);
+        Self::load_dir(&materials_dir)
+    }
+
+    /// Load every material file in `dir`, keyed by file stem
+    fn load_dir(dir: &Path) -> Result<HashMap<String, MaterialDef>> {
+        let mut defs = HashMap::new();
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read directory: {:#?}", dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file()
+                || path.extension().and_then(|ext| ext.to_str())
+                    != Some(MATERIAL_FILE_EXTENSION)
+            {
+                continue;
+            }
+
+            let name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_eyre("Invalid material file name")?
+                .to_string();
+            let source = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read file: {:#?}", path))?;
+            let def = Self::from_ron(&source).with_context(|| {
+                format!("Failed to parse material file: {:#?}", path)
+            })?;
+            defs.insert(name, def);
+        }
+
+        Ok(defs)
+    }
+
+    /// Create the pipeline described by this definition,
+    /// or share the cached one if another material has the same state
+    pub fn build(
+        &self,
+        ctx: &Context,
+        resources: &mut RenderResources,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        msaa: Msaa,
+    ) -> Result<Material> {
+        let device = &ctx.device;
+        let push_constant_ranges =
+            self.push_constants.as_ref().map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|range| vk::PushConstantRange {
+                        stage_flags: range.stages.iter().fold(
+                            vk::ShaderStageFlags::empty(),
+                            |flags, stage| flags | (*stage).into(),
+                        ),
+                        offset: range.offset,
+                        size: range.size,
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+        let mut spec_constants = BTreeMap::new();
+        for def in &self.spec_constants {
+            let stage = vk::ShaderStageFlags::from(def.stage);
+            let constants = spec_constants
+                .remove(&stage)
+                .unwrap_or_else(SpecializationConstants::new);
+            let constants = match def.value {
+                SpecConstantValueDef::Bool(value) => {
+                    constants.set(def.id, value)
+                }
+                SpecConstantValueDef::Int(value) => {
+                    constants.set(def.id, value)
+                }
+                SpecConstantValueDef::UInt(value) => {
+                    constants.set(def.id, value)
+                }
+                SpecConstantValueDef::Float(value) => {
+                    constants.set(def.id, value)
+                }
+            };
+            spec_constants.insert(stage, constants);
+        }
+
+        let shader = GraphicsShader::new(&self.shader, &self.keywords, device)?;
+        let mut builder = Material::builder_graphics(device)
+            .shader(shader)
+            .push_descriptor_set(ctx.push_descriptor.is_some().then_some(0))
+            .polygon_mode(self.polygon_mode.into())
+            .cull_mode(self.cull_mode.into(), self.front_face.into())
+            .depth_test_enable(
+                self.depth_test.is_some(),
+                self.depth_test.map(|op| op.into()),
+            )
+            .multisampling(msaa.samples, msaa.min_sample_shading)
+            .color_attachment_format(color_format)
+            .depth_attachment_format(depth_format)
+            .extended_dynamic_state(ctx.dynamic_state_support)
+            .enabled_features(ctx.enabled_features);
+        builder = match self.topology {
+            Topology::PatchList => {
+                builder.patch_control_points(self.patch_control_points)
+            }
+            topology => builder.input_topology(topology.into()),
+        };
+        if let Some(set) = self.bindless_textures_set {
+            let (layout, bindings) = resources.bindless_layout()?;
+            builder = builder.desc_set_layout(set, layout, bindings);
+        }
+        if let Some(ranges) = push_constant_ranges {
+            builder = builder.push_constant_ranges(ranges);
+        }
+        for (stage, constants) in spec_constants {
+            builder = builder.specialization(stage, constants);
+        }
+        if let Some(stencil) = self.stencil {
+            let state = vk::StencilOpState::from(stencil);
+            builder = builder.stencil_test(state, state);
+        }
+        builder = match self.blend {
+            BlendMode::Opaque => builder.disable_blending(),
+            BlendMode::Alpha => builder.enable_alpha_blending(),
+            BlendMode::Additive => builder.enable_additive_blending(),
+        };
+        if !self.color_write {
+            builder = builder.disable_color_writes();
+        }
+
+        builder.build(
+            &mut resources.pipeline_cache,
+            &mut resources.desc_set_layout_cache,
+        )
+    }
+}
+
+impl From<ShaderStage> for vk::ShaderStageFlags {
+    fn from(stage: ShaderStage) -> Self {
+        match stage {
+            ShaderStage::Vertex => vk::ShaderStageFlags::VERTEX,
+            ShaderStage::TessControl => {
+                vk::ShaderStageFlags::TESSELLATION_CONTROL
+            }
+            ShaderStage::TessEval => {
+                vk::ShaderStageFlags::TESSELLATION_EVALUATION
+            }
+            ShaderStage::Geometry => vk::ShaderStageFlags::GEOMETRY,
+            ShaderStage::Fragment => vk::ShaderStageFlags::FRAGMENT,
+        }
+    }
+}
+
+impl From<CompareOp> for vk::CompareOp {
+    fn from(op: CompareOp) -> Self {
+        match op {
+            CompareOp::Never => vk::CompareOp::NEVER,
+            CompareOp::Less => vk::CompareOp::LESS,
+            CompareOp::Equal => vk::CompareOp::EQUAL,
+            CompareOp::LessOrEqual => vk::CompareOp::LESS_OR_EQUAL,
+            CompareOp::Greater => vk::CompareOp::GREATER,
+            CompareOp::NotEqual => vk::CompareOp::NOT_EQUAL,
+            CompareOp::GreaterOrEqual => vk::CompareOp::GREATER_OR_EQUAL,
+            CompareOp::Always => vk::CompareOp::ALWAYS,
+        }
+    }
+}
+
+impl From<StencilDef> for vk::StencilOpState {
+    fn from(stencil: StencilDef) -> Self {
+        vk::StencilOpState {
+            fail_op: stencil.fail.into(),
+            pass_op: stencil.pass.into(),
+            depth_fail_op: stencil.depth_fail.into(),
+            compare_op: stencil.compare.into(),
+            compare_mask: 0xff,
+            write_mask: 0xff,
+            reference: stencil.reference,
+        }
+    }
+}
+
+impl From<StencilOp> for vk::StencilOp {
+    fn from(op: StencilOp) -> Self {
+        match op {
+            StencilOp::Keep => vk::StencilOp::KEEP,
+            StencilOp::Zero => vk::StencilOp::ZERO,
+            StencilOp::Replace => vk::StencilOp::REPLACE,
+            StencilOp::IncrementAndClamp => vk::StencilOp::INCREMENT_AND_CLAMP,
+            StencilOp::DecrementAndClamp => vk::StencilOp::DECREMENT_AND_CLAMP,
+            StencilOp::Invert => vk::StencilOp::INVERT,
+            StencilOp::IncrementAndWrap => vk::StencilOp::INCREMENT_AND_WRAP,
+            StencilOp::DecrementAndWrap => vk::StencilOp::DECREMENT_AND_WRAP,
+        }
+    }
+}
+
+impl From<CullMode> for vk::CullModeFlags {
+    fn from(mode: CullMode) -> Self {
+        match mode {
+            CullMode::None => vk::CullModeFlags::NONE,
+            CullMode::Front => vk::CullModeFlags::FRONT,
+            CullMode::Back => vk::CullModeFlags::BACK,
+            CullMode::FrontAndBack => vk::CullModeFlags::FRONT_AND_BACK,
+        }
+    }
+}
+
+impl From<FrontFace> for vk::FrontFace {
+    fn from(face: FrontFace) -> Self {
+        match face {
+            FrontFace::Clockwise => vk::FrontFace::CLOCKWISE,
+            FrontFace::CounterClockwise => vk::FrontFace::COUNTER_CLOCKWISE,
+        }
+    }
+}
+
+impl From<PolygonMode> for vk::PolygonMode {
+    fn from(mode: PolygonMode) -> Self {
+        match mode {
+            PolygonMode::Fill => vk::PolygonMode::FILL,
+            PolygonMode::Line => vk::PolygonMode::LINE,
+            PolygonMode::Point => vk::PolygonMode::POINT,
+        }
+    }
+}
+
+impl From<Topology> for vk::PrimitiveTopology {
+    fn from(topology: Topology) -> Self {
+        match topology {
+            Topology::PointList => vk::PrimitiveTopology::POINT_LIST,
+            Topology::LineList => vk::PrimitiveTopology::LINE_LIST,
+            Topology::LineStrip => vk::PrimitiveTopology::LINE_STRIP,
+            Topology::TriangleList => vk::PrimitiveTopology::TRIANGLE_LIST,
+            Topology::TriangleStrip => vk::PrimitiveTopology::TRIANGLE_STRIP,
+            Topology::TriangleFan => vk::PrimitiveTopology::TRIANGLE_FAN,
+            Topology::PatchList => vk::PrimitiveTopology::PATCH_LIST,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own under the temp directory
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "vulkaning-materials-{}-{}",
+            test,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn assets_materials_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("assets")
+            .join(MATERIALS_DIR)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let def = MaterialDef::from_ron(r#"(shader: "default")"#).unwrap();
+        assert_eq!(def.shader, "default");
+        assert!(def.keywords.is_empty());
+        assert_eq!(def.bindless_textures_set, None);
+        assert!(def.push_constants.is_none());
+        assert!(def.spec_constants.is_empty());
+        assert!(matches!(def.blend, BlendMode::Alpha));
+        assert!(matches!(def.depth_test, Some(CompareOp::LessOrEqual)));
+        assert!(def.stencil.is_none());
+        assert!(def.color_write);
+        assert!(matches!(def.cull_mode, CullMode::None));
+        assert!(matches!(def.front_face, FrontFace::Clockwise));
+        assert_eq!(def.polygon_mode, PolygonMode::Fill);
+        assert!(matches!(def.topology, Topology::TriangleList));
+        assert_eq!(def.patch_control_points, 3);
+    }
+
+    #[test]
+    fn test_parse() {
+        let def = MaterialDef::from_ron(
+            r#"(
+                shader: "outline",
+                keywords: ["MASK"],
+                push_constants: Some([(stages: [Vertex, Fragment], size: 80)]),
+                spec_constants: [
+                    (stage: Vertex, id: 0, value: Float(0.02)),
+                ],
+                blend: Opaque,
+                depth_test: None,
+                stencil: Some((compare: NotEqual, reference: 1)),
+                topology: PatchList,
+                patch_control_points: 4,
+            )"#,
+        )
+        .unwrap();
+        assert_eq!(def.keywords, ["MASK"]);
+        let ranges = def.push_constants.unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].offset, ranges[0].size), (0, 80));
+        assert_eq!(ranges[0].stages.len(), 2);
+        assert_eq!(def.spec_constants.len(), 1);
+        assert!(matches!(
+            def.spec_constants[0].value,
+            SpecConstantValueDef::Float(value) if value == 0.02
+        ));
+        assert!(def.depth_test.is_none());
+
+        let stencil = vk::StencilOpState::from(def.stencil.unwrap());
+        assert_eq!(stencil.compare_op, vk::CompareOp::NOT_EQUAL);
+        assert_eq!(stencil.reference, 1);
+        assert_eq!(stencil.pass_op, vk::StencilOp::KEEP);
+        assert_eq!(
+            vk::PrimitiveTopology::from(def.topology),
+            vk::PrimitiveTopology::PATCH_LIST
+        );
+        assert_eq!(def.patch_control_points, 4);
+    }
+
+    #[test]
+    fn test_reject_invalid() {
+        // Unknown fields, e.g. typos, are errors instead of being ignored
+        assert!(
+            MaterialDef::from_ron(r#"(shader: "default", blnd: Opaque)"#)
+                .is_err()
+        );
+        assert!(MaterialDef::from_ron(
+            r#"(shader: "outline", stencil: Some((compare: Always, ref: 1)))"#
+        )
+        .is_err());
+        // Missing shader
+        assert!(MaterialDef::from_ron("(blend: Opaque)").is_err());
+        // Unknown variant
+        assert!(MaterialDef::from_ron(
+            r#"(shader: "default", blend: Multiply)"#
+        )
+        .is_err());
+        // Malformed
+        assert!(MaterialDef::from_ron(r#"(shader: "default""#).is_err());
+    }
+
+    #[test]
+    fn test_load_assets() {
+        let defs = MaterialDef::load_dir(&assets_materials_dir()).unwrap();
+        assert!(defs.contains_key("default"));
+
+        let textured = &defs["textured"];
+        assert_eq!(textured.shader, "textured");
+        assert_eq!(textured.bindless_textures_set, Some(1));
+
+        let wireframe = &defs["debug-wireframe"];
+        assert_eq!(wireframe.shader, "debug");
+        assert_eq!(wireframe.polygon_mode, PolygonMode::Line);
+
+        let mask = &defs["outline-mask"];
+        assert_eq!(mask.keywords, ["MASK"]);
+        assert!(!mask.color_write);
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = temp_dir("load");
+        fs::write(dir.join("a.ron"), r#"(shader: "default")"#).unwrap();
+        // Files with other extensions and subdirectories are skipped
+        fs::write(dir.join("notes.txt"), "not a material").unwrap();
+        fs::create_dir(dir.join("nested.ron")).unwrap();
+
+        let defs = MaterialDef::load_dir(&dir).unwrap();
+        assert_eq!(defs.len(), 1);
+        assert_eq!(defs["a"].shader, "default");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_dir_errors() {
+        let dir = temp_dir("errors");
+        assert!(MaterialDef::load_dir(&dir.join("missing")).is_err());
+
+        fs::write(dir.join("good.ron"), r#"(shader: "default")"#).unwrap();
+        fs::write(dir.join("bad.ron"), r#"(shader: "default", foo: 1)"#)
+            .unwrap();
+        let err = MaterialDef::load_dir(&dir).unwrap_err();
+        assert!(format!("{err:?}").contains("bad.ron"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
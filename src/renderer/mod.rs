@@ -3,19 +3,37 @@ pub mod plugins;
 mod vkinit;
 mod vkutils;
 
+mod attachments;
+mod bindless;
 mod buffer;
 mod camera;
 mod context;
+mod debug_view;
 mod descriptors;
+#[cfg(feature = "embed-shaders")]
+mod embedded_shaders;
 mod frame;
 mod image;
 mod inner;
 mod material;
+mod material_def;
 mod mesh;
 mod model;
+mod pipeline_cache;
+mod reflection;
 mod render_object;
 mod render_resources;
+mod render_texture;
+mod render_window;
+mod selection;
 mod shader;
+#[cfg(any(feature = "hot-reload", test))]
+#[cfg_attr(not(feature = "hot-reload"), allow(dead_code))]
+mod shader_compiler;
+#[cfg(any(feature = "hot-reload", test))]
+mod shader_manifest;
+mod shader_variant;
+mod specialization;
 mod swapchain;
 mod texture;
 mod upload_context;
@@ -23,20 +41,22 @@ mod vertex;
 
 mod gpu_data;
 
-use bevy::ecs::system::Resource;
+use bevy::ecs::{entity::Entity, system::Resource};
 use color_eyre::eyre::{eyre, Result};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
 use self::{
-    camera::Camera, inner::RendererInner, model::Model,
+    attachments::Msaa, camera::Camera, debug_view::DebugView,
+    descriptors::DescriptorAllocatorStats, inner::RendererInner, model::Model,
     texture::TextureAssetData,
 };
 
 pub static mut ASSETS_DIR: Option<String> = None;
 pub static mut SHADERBUILD_DIR: Option<String> = None;
+pub static mut SHADERS_DIR: Option<String> = None;
 
 #[derive(Default, Resource)]
 pub struct AssetData {
@@ -50,9 +70,15 @@ pub struct Renderer {
 }
 
 impl Renderer {
-    pub fn new(window: &winit::window::Window) -> Result<Self> {
+    pub fn new(
+        window_entity: Entity,
+        window: &winit::window::Window,
+    ) -> Result<Self> {
         Ok(Self {
-            inner: Some(Arc::new(Mutex::new(RendererInner::new(window)?))),
+            inner: Some(Arc::new(Mutex::new(RendererInner::new(
+                window_entity,
+                window,
+            )?))),
         })
     }
 
@@ -64,14 +90,114 @@ impl Renderer {
         }
     }
 
-    pub fn draw_frame(&self, camera: &Camera) -> Result<()> {
+    pub fn add_window(
+        &self,
+        window_entity: Entity,
+        window: &winit::window::Window,
+    ) -> Result<()> {
         if let Some(inner) = &self.inner {
-            inner.lock().unwrap().draw_frame(camera)
+            inner.lock().unwrap().add_window(window_entity, window)
+        } else {
+            Err(eyre!("Failed to add window because renderer has already been destroyed"))
+        }
+    }
+
+    pub fn remove_window(&self, window_entity: Entity) -> Result<()> {
+        if let Some(inner) = &self.inner {
+            inner.lock().unwrap().remove_window(window_entity)
+        } else {
+            Err(eyre!("Failed to remove window because renderer has already been destroyed"))
+        }
+    }
+
+    pub fn has_window(&self, window_entity: Entity) -> bool {
+        if let Some(inner) = &self.inner {
+            inner.lock().unwrap().has_window(window_entity)
+        } else {
+            false
+        }
+    }
+
+    pub fn is_primary_window(&self, window_entity: Entity) -> bool {
+        if let Some(inner) = &self.inner {
+            inner.lock().unwrap().is_primary_window(window_entity)
+        } else {
+            false
+        }
+    }
+
+    /// `selected_models` are the names of the models to outline
+    pub fn draw_frame(
+        &self,
+        cameras: &[&Camera],
+        selected_models: &HashSet<String>,
+    ) -> Result<()> {
+        if let Some(inner) = &self.inner {
+            inner.lock().unwrap().draw_frame(cameras, selected_models)
         } else {
             Err(eyre!("Failed to draw frame because renderer has already been destroyed"))
         }
     }
 
+    /// Rebuild every material that uses one of the given shaders
+    #[cfg(feature = "hot-reload")]
+    pub fn reload_shaders(
+        &self,
+        shader_names: &HashSet<String>,
+    ) -> Result<()> {
+        if let Some(inner) = &self.inner {
+            inner.lock().unwrap().reload_shaders(shader_names)
+        } else {
+            Err(eyre!("Failed to reload shaders because renderer has already been destroyed"))
+        }
+    }
+
+    /// Switch to the next debug view whose materials are loaded
+    pub fn cycle_debug_view(&self) -> Result<DebugView> {
+        if let Some(inner) = &self.inner {
+            Ok(inner.lock().unwrap().cycle_debug_view())
+        } else {
+            Err(eyre!("Failed to cycle debug view because renderer has already been destroyed"))
+        }
+    }
+
+    pub fn msaa(&self) -> Result<Msaa> {
+        if let Some(inner) = &self.inner {
+            Ok(inner.lock().unwrap().msaa())
+        } else {
+            Err(eyre!("Failed to get MSAA settings because renderer has already been destroyed"))
+        }
+    }
+
+    /// Statistics of every descriptor allocator by what it allocates for
+    pub fn desc_stats(
+        &self,
+    ) -> Result<Vec<(String, DescriptorAllocatorStats)>> {
+        if let Some(inner) = &self.inner {
+            Ok(inner.lock().unwrap().desc_stats())
+        } else {
+            Err(eyre!("Failed to get descriptor statistics because renderer has already been destroyed"))
+        }
+    }
+
+    /// Use 1, 2, 4 or 8 samples per pixel, clamped to what the GPU supports,
+    /// and optionally shade at least `min_sample_shading` of them per pixel.
+    /// Rebuilds every material, so it should not be called every frame.
+    pub fn set_msaa(
+        &self,
+        sample_count: u32,
+        min_sample_shading: Option<f32>,
+    ) -> Result<Msaa> {
+        if let Some(inner) = &self.inner {
+            inner
+                .lock()
+                .unwrap()
+                .set_msaa(sample_count, min_sample_shading)
+        } else {
+            Err(eyre!("Failed to set MSAA because renderer has already been destroyed"))
+        }
+    }
+
     pub fn cleanup(&mut self) {
         if let Some(inner) = self.inner.take() {
             let inner = match Arc::try_unwrap(inner) {
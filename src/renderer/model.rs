@@ -33,6 +33,12 @@ impl Model {
         }
     }
 
+    /// Device address of the GpuVertexData buffer, for GpuDrawPushConstants
+    pub fn vertex_buffer_address(&self) -> Result<vk::DeviceAddress> {
+        self.vertex_buffer_address
+            .ok_or_eyre("Model has not been uploaded")
+    }
+
     pub fn draw(
         &self,
         cmd: vk::CommandBuffer,
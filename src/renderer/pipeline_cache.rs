@@ -0,0 +1,356 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Weak},
+};
+
+use ash::vk;
+use bevy::log;
+
+use super::{
+    context::DynamicStateSupport, specialization::SpecializationConstants,
+    vertex::VertexInputDescription,
+};
+
+/// Pipeline objects shared by every material built from the same state.
+/// Destroyed when the last material using them is cleaned up.
+#[derive(PartialEq)]
+pub struct Pipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    /// False if `layout` was supplied to the builder,
+    /// in which case it's destroyed by whoever created it
+    pub owns_layout: bool,
+    /// Layout of each set of `layout`, owned by the layout cache
+    pub set_layouts: Vec<vk::DescriptorSetLayout>,
+    pub bind_point: vk::PipelineBindPoint,
+}
+
+impl Pipeline {
+    pub fn cleanup(self, device: &ash::Device) {
+        log::info!("Cleaning up pipeline ...");
+        unsafe {
+            if self.owns_layout {
+                device.destroy_pipeline_layout(self.layout, None);
+            }
+            device.destroy_pipeline(self.pipeline, None);
+        }
+    }
+}
+
+/// Everything a pipeline is created from.
+/// Builders with equal keys create identical pipelines.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub shaders: Vec<ShaderKey>,
+    pub spec_constants: BTreeMap<vk::ShaderStageFlags, SpecializationConstants>,
+    pub layout: PipelineLayoutKey,
+    /// None for compute pipelines
+    pub graphics: Option<GraphicsStateKey>,
+}
+
+/// Identifies a shader stage by variant name and SPIR-V contents,
+/// since every material loads its own shader modules
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShaderKey {
+    pub stage: vk::ShaderStageFlags,
+    pub variant: String,
+    pub spirv_hash: u64,
+}
+
+impl ShaderKey {
+    pub fn new(
+        stage: vk::ShaderStageFlags,
+        variant: &str,
+        spirv: &[u8],
+    ) -> Self {
+        let mut hasher = DefaultHasher::new();
+        spirv.hash(&mut hasher);
+        Self {
+            stage,
+            variant: variant.into(),
+            spirv_hash: hasher.finish(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PipelineLayoutKey {
+    /// Layout supplied to the builder
+    Explicit(vk::PipelineLayout),
+    /// Layout created from supplied set layouts and push constant ranges,
+    /// the rest is generated from the shaders
+    Reflected {
+        /// (set, layout) of each supplied set
+        set_layouts: Vec<(u32, vk::DescriptorSetLayout)>,
+        /// Generated set whose layout is built for push descriptors
+        push_descriptor_set: Option<u32>,
+        /// (stages, offset, size) of each range, None if generated
+        push_constants: Option<Vec<(vk::ShaderStageFlags, u32, u32)>>,
+    },
+}
+
+/// Fixed-function state of a graphics pipeline.
+/// Floats are stored as bits so that the key can be hashed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GraphicsStateKey {
+    pub vertex_input: VertexInputKey,
+    pub topology: vk::PrimitiveTopology,
+    pub primitive_restart: vk::Bool32,
+    pub patch_control_points: u32,
+    pub polygon_mode: vk::PolygonMode,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub line_width: u32,
+    pub samples: vk::SampleCountFlags,
+    pub sample_shading: vk::Bool32,
+    pub min_sample_shading: u32,
+    pub blend: BlendKey,
+    pub depth_test: vk::Bool32,
+    pub depth_write: vk::Bool32,
+    pub depth_compare_op: vk::CompareOp,
+    pub stencil_test: vk::Bool32,
+    pub stencil_front: StencilKey,
+    pub stencil_back: StencilKey,
+    pub color_format: Option<vk::Format>,
+    pub depth_format: vk::Format,
+    pub stencil_format: vk::Format,
+    /// State that is normalized in this key because it's set at bind time
+    pub dynamic_state: DynamicStateSupport,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VertexInputKey {
+    /// (binding, stride, input rate)
+    pub bindings: Vec<(u32, u32, vk::VertexInputRate)>,
+    /// (binding, location, format, offset)
+    pub attributes: Vec<(u32, u32, vk::Format, u32)>,
+    pub flags: vk::PipelineVertexInputStateCreateFlags,
+}
+
+impl From<&VertexInputDescription> for VertexInputKey {
+    fn from(desc: &VertexInputDescription) -> Self {
+        Self {
+            bindings: desc
+                .bindings
+                .iter()
+                .map(|b| (b.binding, b.stride, b.input_rate))
+                .collect(),
+            attributes: desc
+                .attributes
+                .iter()
+                .map(|a| (a.binding, a.location, a.format, a.offset))
+                .collect(),
+            flags: desc.flags,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlendKey {
+    pub enable: vk::Bool32,
+    pub src_color: vk::BlendFactor,
+    pub dst_color: vk::BlendFactor,
+    pub color_op: vk::BlendOp,
+    pub src_alpha: vk::BlendFactor,
+    pub dst_alpha: vk::BlendFactor,
+    pub alpha_op: vk::BlendOp,
+    pub write_mask: vk::ColorComponentFlags,
+}
+
+impl From<&vk::PipelineColorBlendAttachmentState> for BlendKey {
+    fn from(blend: &vk::PipelineColorBlendAttachmentState) -> Self {
+        Self {
+            enable: blend.blend_enable,
+            src_color: blend.src_color_blend_factor,
+            dst_color: blend.dst_color_blend_factor,
+            color_op: blend.color_blend_op,
+            src_alpha: blend.src_alpha_blend_factor,
+            dst_alpha: blend.dst_alpha_blend_factor,
+            alpha_op: blend.alpha_blend_op,
+            write_mask: blend.color_write_mask,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StencilKey {
+    pub fail: vk::StencilOp,
+    pub pass: vk::StencilOp,
+    pub depth_fail: vk::StencilOp,
+    pub compare: vk::CompareOp,
+    pub compare_mask: u32,
+    pub write_mask: u32,
+    pub reference: u32,
+}
+
+impl From<&vk::StencilOpState> for StencilKey {
+    fn from(state: &vk::StencilOpState) -> Self {
+        Self {
+            fail: state.fail_op,
+            pass: state.pass_op,
+            depth_fail: state.depth_fail_op,
+            compare: state.compare_op,
+            compare_mask: state.compare_mask,
+            write_mask: state.write_mask,
+            reference: state.reference,
+        }
+    }
+}
+
+/// Hands out shared pipelines so that materials with the same state
+/// don't create duplicate pipelines.
+/// Entries don't keep pipelines alive, materials do.
+#[derive(Default)]
+pub struct PipelineCache {
+    pipelines: HashMap<PipelineKey, Weak<Pipeline>>,
+}
+
+impl PipelineCache {
+    /// Pipeline created from this key that is still in use
+    pub fn get(&mut self, key: &PipelineKey) -> Option<Arc<Pipeline>> {
+        let pipeline = self.pipelines.get(key)?.upgrade();
+        if pipeline.is_none() {
+            self.pipelines.remove(key);
+        }
+        pipeline
+    }
+
+    pub fn insert(
+        &mut self,
+        key: PipelineKey,
+        pipeline: Pipeline,
+    ) -> Arc<Pipeline> {
+        let pipeline = Arc::new(pipeline);
+        // Forget pipelines that have been destroyed in the meantime
+        self.pipelines.retain(|_, weak| weak.strong_count() > 0);
+        self.pipelines.insert(key, Arc::downgrade(&pipeline));
+        pipeline
+    }
+
+    /// Number of distinct pipelines still in use
+    pub fn len(&self) -> usize {
+        self.pipelines
+            .values()
+            .filter(|weak| weak.strong_count() > 0)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::Handle;
+
+    use super::*;
+
+    fn compute_key(spirv: &[u8], layout: PipelineLayoutKey) -> PipelineKey {
+        PipelineKey {
+            shaders: vec![ShaderKey::new(
+                vk::ShaderStageFlags::COMPUTE,
+                "gradient",
+                spirv,
+            )],
+            spec_constants: BTreeMap::new(),
+            layout,
+            graphics: None,
+        }
+    }
+
+    fn reflected_layout() -> PipelineLayoutKey {
+        PipelineLayoutKey::Reflected {
+            set_layouts: vec![(1, vk::DescriptorSetLayout::from_raw(1))],
+            push_descriptor_set: Some(0),
+            push_constants: None,
+        }
+    }
+
+    fn pipeline(raw: u64) -> Pipeline {
+        Pipeline {
+            pipeline: vk::Pipeline::from_raw(raw),
+            layout: vk::PipelineLayout::null(),
+            owns_layout: true,
+            set_layouts: Vec::new(),
+            bind_point: vk::PipelineBindPoint::COMPUTE,
+        }
+    }
+
+    #[test]
+    fn test_key_equality() {
+        assert_eq!(
+            compute_key(&[1, 2, 3], reflected_layout()),
+            compute_key(&[1, 2, 3], reflected_layout())
+        );
+        let explicit =
+            PipelineLayoutKey::Explicit(vk::PipelineLayout::from_raw(7));
+        assert_eq!(
+            compute_key(&[1, 2, 3], explicit.clone()),
+            compute_key(&[1, 2, 3], explicit)
+        );
+    }
+
+    #[test]
+    fn test_key_inequality() {
+        let key = compute_key(&[1, 2, 3], reflected_layout());
+
+        // Same variant name but different SPIR-V, e.g. after a reload
+        assert_ne!(key, compute_key(&[1, 2, 4], reflected_layout()));
+
+        let mut other = key.clone();
+        other.spec_constants.insert(
+            vk::ShaderStageFlags::COMPUTE,
+            SpecializationConstants::new().set(0, 16u32),
+        );
+        assert_ne!(key, other);
+
+        let push_descriptor = PipelineLayoutKey::Reflected {
+            set_layouts: vec![(1, vk::DescriptorSetLayout::from_raw(1))],
+            push_descriptor_set: None,
+            push_constants: None,
+        };
+        assert_ne!(key, compute_key(&[1, 2, 3], push_descriptor));
+
+        let push_constants = PipelineLayoutKey::Reflected {
+            set_layouts: vec![(1, vk::DescriptorSetLayout::from_raw(1))],
+            push_descriptor_set: Some(0),
+            push_constants: Some(vec![(vk::ShaderStageFlags::COMPUTE, 0, 16)]),
+        };
+        assert_ne!(key, compute_key(&[1, 2, 3], push_constants));
+
+        assert_ne!(
+            compute_key(
+                &[1, 2, 3],
+                PipelineLayoutKey::Explicit(vk::PipelineLayout::from_raw(7))
+            ),
+            compute_key(
+                &[1, 2, 3],
+                PipelineLayoutKey::Explicit(vk::PipelineLayout::from_raw(8))
+            )
+        );
+    }
+
+    #[test]
+    fn test_weak_expiry() {
+        let mut cache = PipelineCache::default();
+        let key = compute_key(&[1, 2, 3], reflected_layout());
+        let other_key = compute_key(&[4, 5, 6], reflected_layout());
+
+        let shared = cache.insert(key.clone(), pipeline(1));
+        let other = cache.insert(other_key.clone(), pipeline(2));
+        assert_eq!(cache.len(), 2);
+        assert!(Arc::ptr_eq(&cache.get(&key).unwrap(), &shared));
+
+        let material = shared.clone();
+        drop(shared);
+        // Still used by a material
+        assert!(cache.get(&key).is_some());
+
+        drop(material);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&key).is_none());
+        assert!(Arc::ptr_eq(&cache.get(&other_key).unwrap(), &other));
+
+        drop(other);
+        assert_eq!(cache.len(), 0);
+        assert!(cache.get(&other_key).is_none());
+    }
+}
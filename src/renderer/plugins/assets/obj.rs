@@ -143,6 +143,7 @@ async fn load_obj_model<'a, 'b>(
     let mut positions = Vec::new();
     let mut normals = Vec::new();
     let mut texcoords = Vec::new();
+    let mut colors = Vec::new();
     for model in models {
         let index_offset = positions.len() as u32; // Offset of the indices
         indices.reserve(model.mesh.indices.len());
@@ -170,6 +171,19 @@ async fn load_obj_model<'a, 'b>(
                 .chunks_exact(2)
                 .map(|t| [t[0], 1.0 - t[1]]),
         );
+        // Vertex colors are optional in OBJ files
+        let vertex_count = model.mesh.positions.len() / 3;
+        if model.mesh.vertex_color.len() == vertex_count * 3 {
+            colors.extend(
+                model
+                    .mesh
+                    .vertex_color
+                    .chunks_exact(3)
+                    .map(|c| [c[0], c[1], c[2]]),
+            );
+        } else {
+            colors.extend(std::iter::repeat_n([1.0, 1.0, 1.0], vertex_count));
+        }
         indices.extend(model.mesh.indices.iter().map(|i| i + index_offset));
     }
 
@@ -177,11 +191,12 @@ async fn load_obj_model<'a, 'b>(
         .iter()
         .zip(normals.iter())
         .zip(texcoords.iter())
-        .map(|((&position, &normal), &texcoord)| Vertex {
+        .zip(colors.iter())
+        .map(|(((&position, &normal), &texcoord), &color)| Vertex {
             position: position.into(),
             normal: normal.into(),
             texcoord: texcoord.into(),
-            color: normal.into(),
+            color: color.into(),
         })
         .collect();
     let mesh = Mesh::new(vertices, indices);
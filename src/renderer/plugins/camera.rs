@@ -1,6 +1,7 @@
 use bevy::{
     input::mouse::{MouseMotion, MouseWheel},
     prelude::*,
+    window::PrimaryWindow,
 };
 
 use crate::renderer::{camera::Camera, Renderer};
@@ -19,17 +20,23 @@ fn spawn_camera(mut commands: Commands) {
 }
 
 fn rotate_camera(
-    mut camera: Query<&mut Camera>,
+    mut cameras: Query<&mut Camera>,
     mut cursor_moved_evts: EventReader<CursorMoved>,
     mut mouse_button_inps: Res<ButtonInput<MouseButton>>,
     mut window: Query<&Window>,
+    primary_window: Query<Entity, With<PrimaryWindow>>,
 ) {
     if !mouse_button_inps.pressed(MouseButton::Right) {
         return;
     }
+    let Ok(primary_window) = primary_window.get_single() else {
+        return;
+    };
 
     for evt in cursor_moved_evts.read() {
-        let window = window.get(evt.window).unwrap();
+        let Ok(window) = window.get(evt.window) else {
+            continue;
+        };
         let viewport_width = window.width();
         let viewport_height = window.height();
         let last_mouse_pos = if let Some(delta) = evt.delta {
@@ -38,7 +45,11 @@ fn rotate_camera(
             evt.position
         };
         let curr_mouse_pos = evt.position;
-        let mut camera = camera.single_mut();
+        let Some(mut camera) =
+            camera_for_window(&mut cameras, evt.window, primary_window)
+        else {
+            continue;
+        };
         camera.rotate(
             last_mouse_pos,
             curr_mouse_pos,
@@ -49,11 +60,30 @@ fn rotate_camera(
 }
 
 fn zoom_camera(
-    mut camera: Query<&mut Camera>,
+    mut cameras: Query<&mut Camera>,
     mut mouse_wheel_evts: EventReader<MouseWheel>,
+    primary_window: Query<Entity, With<PrimaryWindow>>,
 ) {
+    let Ok(primary_window) = primary_window.get_single() else {
+        return;
+    };
+
     for evt in mouse_wheel_evts.read() {
-        let mut camera = camera.single_mut();
-        camera.zoom(evt.y);
+        if let Some(mut camera) =
+            camera_for_window(&mut cameras, evt.window, primary_window)
+        {
+            camera.zoom(evt.y);
+        }
     }
 }
+
+/// Find the camera that renders into the given window
+fn camera_for_window<'a>(
+    cameras: &'a mut Query<&mut Camera>,
+    window: Entity,
+    primary_window: Entity,
+) -> Option<Mut<'a, Camera>> {
+    cameras.iter_mut().find(|camera| {
+        camera.target().window_entity(primary_window) == Some(window)
+    })
+}
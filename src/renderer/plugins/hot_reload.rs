@@ -0,0 +1,213 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+};
+
+use bevy::prelude::*;
+use color_eyre::eyre::{OptionExt, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::renderer::{
+    shader_compiler::{ShaderCompiler, INCLUDE_SHADER_EXT},
+    shader_manifest, Renderer, SHADERBUILD_DIR, SHADERS_DIR,
+};
+
+use super::AllAssetsLoadState;
+
+/// Recompiles shaders when their source files change
+/// and rebuilds the materials that use them
+pub struct HotReloadPlugin;
+impl Plugin for HotReloadPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, create_shader_watcher).add_systems(
+            Update,
+            reload_changed_shaders.run_if(in_state(AllAssetsLoadState::Loaded)),
+        );
+    }
+}
+
+struct ShaderWatcher {
+    // Stops watching when dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    compiler: ShaderCompiler,
+    shaders_dir: PathBuf,
+    shaderbuild_dir: PathBuf,
+}
+
+impl ShaderWatcher {
+    fn new() -> Result<Self> {
+        let (shaders_dir, shaderbuild_dir) = unsafe {
+            (
+                SHADERS_DIR
+                    .as_ref()
+                    .ok_or_eyre("Shader directory not specified")?,
+                SHADERBUILD_DIR
+                    .as_ref()
+                    .ok_or_eyre("Shader build directory not specified")?,
+            )
+        };
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(Path::new(shaders_dir), RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            compiler: ShaderCompiler::new()?.include_dir(shaders_dir),
+            shaders_dir: shaders_dir.into(),
+            shaderbuild_dir: shaderbuild_dir.into(),
+        })
+    }
+
+    /// Shader files changed since the last call.
+    /// A changed include file stands for every shader that includes it.
+    fn changed_shaders(&self) -> HashSet<PathBuf> {
+        let mut changed = HashSet::new();
+        for event in self.events.try_iter() {
+            match event {
+                // Renames are modify events with the old and new paths.
+                // Editors that save by replacing the file remove it first,
+                // so every path is checked again below.
+                Ok(event)
+                    if matches!(
+                        event.kind,
+                        EventKind::Create(_)
+                            | EventKind::Modify(_)
+                            | EventKind::Remove(_)
+                    ) =>
+                {
+                    changed.extend(event.paths);
+                }
+                Ok(_) => {}
+                Err(err) => error!("Failed to watch shaders: {}", err),
+            }
+        }
+
+        let mut shaders = HashSet::new();
+        // Removed files keep their compiled shaders
+        for path in changed.into_iter().filter(|path| path.is_file()) {
+            if path.extension().and_then(|ext| ext.to_str())
+                == Some(INCLUDE_SHADER_EXT)
+            {
+                match self.shaders_including(&path) {
+                    Ok(paths) => shaders.extend(paths),
+                    Err(err) => error!("{}", err),
+                }
+            } else {
+                shaders.insert(path);
+            }
+        }
+        shaders
+    }
+
+    /// Add recompiled outputs to the build script's manifest.
+    /// `includes` are given if every variant was written,
+    /// then the manifest gets the new hash and the outputs of variants
+    /// that no longer exist are removed.
+    fn record_outputs(
+        &self,
+        path: &Path,
+        includes: Option<&BTreeSet<PathBuf>>,
+        outputs: &[PathBuf],
+    ) -> Result<()> {
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_eyre("Invalid shader file name")?;
+        let hash = includes
+            .map(|includes| shader_manifest::source_hash(path, includes))
+            .transpose()?;
+        shader_manifest::record_outputs(
+            &self.shaderbuild_dir,
+            filename,
+            hash,
+            outputs,
+        )
+    }
+
+    /// Also shaders that include the file through other includes
+    fn shaders_including(&self, include: &Path) -> Result<Vec<PathBuf>> {
+        let include_name =
+            include.file_name().ok_or_eyre("Invalid shader file name")?;
+
+        let mut shaders = Vec::new();
+        for entry in fs::read_dir(&self.shaders_dir)? {
+            let path = entry?.path();
+            if path.is_file()
+                && path != include
+                && self
+                    .compiler
+                    .includes(&path)?
+                    .iter()
+                    .any(|path| path.file_name() == Some(include_name))
+            {
+                shaders.push(path);
+            }
+        }
+        Ok(shaders)
+    }
+}
+
+fn create_shader_watcher(world: &mut World) {
+    match ShaderWatcher::new() {
+        Ok(watcher) => world.insert_non_send_resource(watcher),
+        Err(err) => error!("Failed to watch shaders for changes: {}", err),
+    }
+}
+
+/// Shaders that fail to compile are logged and the materials using them
+/// keep their old pipelines
+fn reload_changed_shaders(
+    watcher: Option<NonSend<ShaderWatcher>>,
+    renderer: NonSend<Renderer>,
+) {
+    let Some(watcher) = watcher else {
+        return;
+    };
+
+    let mut reloaded = HashSet::new();
+    for path in watcher.changed_shaders() {
+        let shaders = match watcher.compiler.compile_file(&path) {
+            // Not a shader file, e.g. an include no shader uses
+            Ok(shaders) if shaders.is_empty() => continue,
+            Ok(shaders) => shaders,
+            Err(err) => {
+                error!("Failed to compile shader {:?}:\n{:?}", path, err);
+                continue;
+            }
+        };
+        let mut outputs = Vec::new();
+        let mut includes = Some(BTreeSet::new());
+        for shader in shaders {
+            match shader.write(&watcher.shaderbuild_dir) {
+                Ok(paths) => outputs.extend(paths),
+                Err(err) => {
+                    error!("Failed to write shader {}: {}", shader.name, err);
+                    includes = None;
+                    continue;
+                }
+            }
+            if let Some(includes) = &mut includes {
+                includes.extend(shader.includes);
+            }
+            info!("Recompiled shader {:?} {:?}", path, shader.keywords);
+            reloaded.insert(shader.name);
+        }
+        if let Err(err) =
+            watcher.record_outputs(&path, includes.as_ref(), &outputs)
+        {
+            error!("Failed to update shader manifest: {}", err);
+        }
+    }
+
+    if reloaded.is_empty() {
+        return;
+    }
+    if let Err(err) = renderer.reload_shaders(&reloaded) {
+        error!("Failed to reload shaders: {}", err);
+    }
+}
@@ -1,15 +1,31 @@
 use bevy::{
     prelude::*,
-    window::{PrimaryWindow, WindowCloseRequested},
+    window::{PrimaryWindow, WindowCloseRequested, WindowResolution},
 };
 
-use crate::renderer::Renderer;
+use crate::renderer::{
+    camera::{Camera, RenderTarget},
+    frame::MONITOR_TEXTURE,
+    selection::{SceneModel, Selected},
+    Renderer,
+};
 
 // Uncategorized plugin containing miscellaneous systems
 pub struct MiscPlugin;
 impl Plugin for MiscPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, request_close_on_esc);
+        app.add_systems(Startup, (spawn_scene_models, spawn_monitor_camera))
+            .add_systems(
+                Update,
+                (
+                    request_close_on_esc,
+                    toggle_debug_window,
+                    cycle_debug_view,
+                    cycle_msaa,
+                    toggle_selection,
+                    log_desc_stats,
+                ),
+            );
     }
 }
 
@@ -24,3 +40,137 @@ fn request_close_on_esc(
         });
     }
 }
+
+/// Marks the detachable debug window and the camera that renders into it
+#[derive(Component)]
+struct DebugWindow;
+
+fn toggle_debug_window(
+    mut commands: Commands,
+    debug_ents: Query<Entity, With<DebugWindow>>,
+    input: Res<ButtonInput<KeyCode>>,
+) {
+    if !input.just_released(KeyCode::F2) {
+        return;
+    }
+
+    if !debug_ents.is_empty() {
+        // Despawning the window entity also closes the window
+        for ent in debug_ents.iter() {
+            commands.entity(ent).despawn();
+        }
+        return;
+    }
+
+    let window = commands
+        .spawn((
+            Window {
+                resolution: WindowResolution::new(800.0, 450.0),
+                title: "vulkaning debug".into(),
+                resizable: false,
+                ..Default::default()
+            },
+            DebugWindow,
+        ))
+        .id();
+
+    // Look at the scene from a second viewpoint
+    let mut camera =
+        Camera::default().with_target(RenderTarget::Window(window));
+    camera.set_position(Vec3::new(5.0, 5.0, 5.0));
+    commands.spawn((camera, DebugWindow));
+}
+
+/// F3 switches between the debug views of the renderer
+fn cycle_debug_view(
+    renderer: NonSend<Renderer>,
+    input: Res<ButtonInput<KeyCode>>,
+) {
+    if !input.just_released(KeyCode::F3) {
+        return;
+    }
+    match renderer.cycle_debug_view() {
+        Ok(view) => info!("Debug view: {:?}", view),
+        Err(err) => error!("Failed to cycle debug view: {}", err),
+    }
+}
+
+/// F4 switches between 1x, 2x, 4x and 8x MSAA,
+/// skipping sample counts the GPU doesn't support
+fn cycle_msaa(renderer: NonSend<Renderer>, input: Res<ButtonInput<KeyCode>>) {
+    if !input.just_released(KeyCode::F4) {
+        return;
+    }
+    let msaa = renderer.msaa().and_then(|msaa| {
+        let next = match msaa.sample_count() {
+            count @ 1..=4 => count * 2,
+            _ => 1,
+        };
+        let msaa = renderer.set_msaa(next, msaa.min_sample_shading)?;
+        // The GPU supports fewer samples, so start over
+        if msaa.sample_count() < next {
+            renderer.set_msaa(1, msaa.min_sample_shading)
+        } else {
+            Ok(msaa)
+        }
+    });
+    match msaa {
+        Ok(msaa) => info!("MSAA: {}x", msaa.sample_count()),
+        Err(err) => error!("Failed to cycle MSAA: {}", err),
+    }
+}
+
+/// The scene is hardcoded in the renderer,
+/// so only its models need entities to be selectable
+fn spawn_scene_models(mut commands: Commands) {
+    commands.spawn(SceneModel {
+        name: "backpack".into(),
+    });
+}
+
+/// Renders the scene from behind into the texture of the monitor quad
+fn spawn_monitor_camera(mut commands: Commands) {
+    let mut camera = Camera::default().with_target(RenderTarget::Texture {
+        name: MONITOR_TEXTURE.into(),
+        width: 512,
+        height: 512,
+    });
+    camera.set_position(Vec3::new(0.0, 2.0, -5.0));
+    commands.spawn(camera);
+}
+
+/// F5 toggles the outline of every scene model
+fn toggle_selection(
+    mut commands: Commands,
+    models: Query<(Entity, Has<Selected>), With<SceneModel>>,
+    input: Res<ButtonInput<KeyCode>>,
+) {
+    if !input.just_released(KeyCode::F5) {
+        return;
+    }
+    for (ent, selected) in models.iter() {
+        if selected {
+            commands.entity(ent).remove::<Selected>();
+        } else {
+            commands.entity(ent).insert(Selected);
+        }
+    }
+}
+
+/// F6 logs what the descriptor allocators have allocated so far
+fn log_desc_stats(
+    renderer: NonSend<Renderer>,
+    input: Res<ButtonInput<KeyCode>>,
+) {
+    if !input.just_released(KeyCode::F6) {
+        return;
+    }
+    match renderer.desc_stats() {
+        Ok(stats) => {
+            for (name, stats) in stats {
+                info!("Descriptor allocator of {}: {:?}", name, stats);
+            }
+        }
+        Err(err) => error!("Failed to get descriptor statistics: {}", err),
+    }
+}
@@ -1,7 +1,11 @@
 mod assets;
 mod camera;
+#[cfg(feature = "hot-reload")]
+mod hot_reload;
 mod misc;
 
+use std::collections::HashSet;
+
 use bevy::prelude::*;
 use bevy::window::{PrimaryWindow, WindowCloseRequested};
 use bevy::winit::WinitWindows;
@@ -9,6 +13,7 @@ use bevy::winit::WinitWindows;
 use self::assets::{ImageAssetsLoadState, ObjAssetsLoadState};
 
 use super::camera::Camera;
+use super::selection::{SceneModel, Selected};
 use super::{AssetData, Renderer};
 
 pub struct RenderPlugin;
@@ -28,14 +33,24 @@ impl Plugin for RenderPlugin {
             check_all_assets_loaded
                 .run_if(in_state(AllAssetsLoadState::NotLoaded)),
         )
+        .add_systems(Update, add_render_windows)
         .add_systems(
             Update,
-            draw_frame.run_if(in_state(AllAssetsLoadState::Loaded)),
+            draw_frame
+                .after(add_render_windows)
+                .run_if(in_state(AllAssetsLoadState::Loaded)),
         )
         .add_systems(
             PostUpdate,
-            cleanup.run_if(in_state(AllAssetsLoadState::Loaded)),
+            (
+                cleanup.run_if(in_state(AllAssetsLoadState::Loaded)),
+                remove_render_windows,
+            )
+                .chain(),
         );
+
+        #[cfg(feature = "hot-reload")]
+        app.add_plugins(hot_reload::HotReloadPlugin);
     }
 }
 
@@ -51,10 +66,48 @@ fn create_renderer(world: &mut World) {
     let window_ent = window_ents.single(world);
     let winit_window = winit_windows.get_window(window_ent).unwrap();
 
-    let renderer = Renderer::new(winit_window).unwrap();
+    let renderer = Renderer::new(window_ent, winit_window).unwrap();
     world.insert_non_send_resource(renderer);
 }
 
+/// Create a surface and swapchain for every window spawned after startup.
+/// Winit windows are created a frame after their Window entity,
+/// so keep checking until the winit window exists.
+fn add_render_windows(
+    windows: Query<Entity, With<Window>>,
+    winit_windows: NonSend<WinitWindows>,
+    renderer: NonSend<Renderer>,
+) {
+    for window_ent in windows.iter() {
+        if renderer.has_window(window_ent) {
+            continue;
+        }
+        if let Some(winit_window) = winit_windows.get_window(window_ent) {
+            if let Err(err) = renderer.add_window(window_ent, winit_window) {
+                error!("Failed to add window to renderer: {}", err);
+            }
+        }
+    }
+}
+
+/// Destroy the surfaces of closed windows.
+/// This must run before bevy_winit destroys the winit windows in Last.
+fn remove_render_windows(
+    mut removed_windows: RemovedComponents<Window>,
+    renderer: NonSend<Renderer>,
+) {
+    for window_ent in removed_windows.read() {
+        if !renderer.has_window(window_ent)
+            || renderer.is_primary_window(window_ent)
+        {
+            continue;
+        }
+        if let Err(err) = renderer.remove_window(window_ent) {
+            error!("Failed to remove window from renderer: {}", err);
+        }
+    }
+}
+
 fn check_all_assets_loaded(
     mut all_assets_state: ResMut<NextState<AllAssetsLoadState>>,
     obj_assets_state: Res<State<ObjAssetsLoadState>>,
@@ -76,16 +129,28 @@ fn init_render_resources(
     commands.remove_resource::<AssetData>();
 }
 
-fn draw_frame(renderer: NonSend<Renderer>, camera: Query<&Camera>) {
-    let camera = camera.single();
-    renderer.draw_frame(camera).unwrap();
+fn draw_frame(
+    renderer: NonSend<Renderer>,
+    cameras: Query<&Camera>,
+    selected_models: Query<&SceneModel, With<Selected>>,
+) {
+    let cameras = cameras.iter().collect::<Vec<_>>();
+    let selected_models = selected_models
+        .iter()
+        .map(|model| model.name.clone())
+        .collect::<HashSet<_>>();
+    renderer.draw_frame(&cameras, &selected_models).unwrap();
 }
 
 fn cleanup(
     mut window_close_evts: EventReader<WindowCloseRequested>,
     mut renderer: NonSendMut<Renderer>,
 ) {
-    if window_close_evts.read().next().is_some() {
+    // Closing a secondary window only removes that window from the renderer
+    let primary_closed = window_close_evts
+        .read()
+        .any(|evt| renderer.is_primary_window(evt.window));
+    if primary_closed {
         renderer.cleanup();
     }
 }
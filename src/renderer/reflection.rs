@@ -0,0 +1,1184 @@
+// Minimal SPIR-V reflection: just enough to derive descriptor set layouts,
+// push constant ranges and vertex inputs from compiled shaders
+
+use std::collections::{BTreeMap, HashMap};
+
+use ash::vk;
+use color_eyre::eyre::{eyre, OptionExt, Result};
+use spirv::{Decoration, Dim, ExecutionModel, Op, StorageClass};
+
+use super::{
+    descriptors::{
+        DescriptorBinding, DescriptorSetLayoutBuilder, DescriptorSetLayoutCache,
+    },
+    specialization::SpecializationConstants,
+    vertex::VertexInputDescription,
+};
+
+/// Everything a pipeline layout and vertex input state need to know about
+/// one or more shader stages
+#[derive(Debug, Clone, Default)]
+pub struct ShaderReflection {
+    pub stages: vk::ShaderStageFlags,
+    /// Descriptor bindings of each set, sorted by binding number
+    pub sets: BTreeMap<u32, Vec<DescriptorBinding>>,
+    /// One range per stage that uses push constants
+    pub push_constants: Vec<vk::PushConstantRange>,
+    /// Vertex shader inputs sorted by location
+    pub vertex_inputs: Vec<VertexInput>,
+    /// Specialization constants keyed by constant_id
+    pub spec_constants: BTreeMap<u32, SpecConstant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecConstant {
+    pub stages: vk::ShaderStageFlags,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+    pub size: u32,
+}
+
+/// Member offsets of a struct with an explicit layout,
+/// e.g. a uniform block or a struct in a std430 buffer
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct StructLayout {
+    pub name: String,
+    pub size: u32,
+    /// Offset of each member by name, in declaration order
+    pub members: Vec<(String, u32)>,
+}
+
+/// Layout of every named struct of a module whose members all have offsets
+#[cfg(test)]
+pub fn struct_layouts(code: &[u8]) -> Result<Vec<StructLayout>> {
+    SpirvModule::parse(code)?.struct_layouts()
+}
+
+impl ShaderReflection {
+    pub fn from_spirv(code: &[u8]) -> Result<Self> {
+        let module = SpirvModule::parse(code)?;
+        module.reflect()
+    }
+
+    /// Combine the reflection of another stage of the same pipeline
+    pub fn merge(mut self, other: Self) -> Result<Self> {
+        self.stages |= other.stages;
+
+        for (set, bindings) in other.sets {
+            let merged = self.sets.entry(set).or_default();
+            for binding in bindings {
+                match merged.iter_mut().find(|b| b.binding == binding.binding) {
+                    Some(existing) => {
+                        if existing.desc_type != binding.desc_type
+                            || existing.count != binding.count
+                        {
+                            return Err(eyre!(
+                                "Shader stages disagree on set {} binding {}: {:?} x{} vs {:?} x{}",
+                                set,
+                                binding.binding,
+                                existing.desc_type,
+                                existing.count,
+                                binding.desc_type,
+                                binding.count
+                            ));
+                        }
+                        existing.stages |= binding.stages;
+                    }
+                    None => merged.push(binding),
+                }
+            }
+            merged.sort_by_key(|b| b.binding);
+        }
+
+        for (id, constant) in other.spec_constants {
+            let merged = self.spec_constants.entry(id).or_insert(constant);
+            if merged.size != constant.size {
+                return Err(eyre!(
+                    "Shader stages disagree on the size of specialization constant {}: {} vs {} bytes",
+                    id,
+                    merged.size,
+                    constant.size
+                ));
+            }
+            merged.stages |= constant.stages;
+        }
+
+        self.push_constants.extend(other.push_constants);
+        if !other.vertex_inputs.is_empty() {
+            self.vertex_inputs = other.vertex_inputs;
+        }
+
+        Ok(self)
+    }
+
+    /// Number of descriptor sets a pipeline layout needs for these stages
+    pub fn set_count(&self) -> u32 {
+        self.sets.keys().next_back().map_or(0, |set| set + 1)
+    }
+
+    pub fn set_bindings(&self, set: u32) -> &[DescriptorBinding] {
+        self.sets
+            .get(&set)
+            .map(|bindings| bindings.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Layout of `set` from the layout cache,
+    /// built for push descriptors if `push_descriptor` is set
+    pub fn create_desc_set_layout(
+        &self,
+        set: u32,
+        push_descriptor: bool,
+        device: &ash::Device,
+        cache: &mut DescriptorSetLayoutCache,
+    ) -> Result<vk::DescriptorSetLayout> {
+        let builder = DescriptorSetLayoutBuilder::new()
+            .add_desc_bindings(self.set_bindings(set));
+        if push_descriptor {
+            builder.push_descriptor().build(device, cache)
+        } else {
+            builder.build(device, cache)
+        }
+    }
+
+    /// A single range covering the push constants of every stage,
+    /// since Vulkan doesn't allow two ranges to share a stage
+    pub fn push_constant_ranges(&self) -> Vec<vk::PushConstantRange> {
+        if self.push_constants.is_empty() {
+            return Vec::new();
+        }
+        let start = self
+            .push_constants
+            .iter()
+            .map(|range| range.offset)
+            .min()
+            .unwrap();
+        let end = self
+            .push_constants
+            .iter()
+            .map(|range| range.offset + range.size)
+            .max()
+            .unwrap();
+        let stage_flags = self
+            .push_constants
+            .iter()
+            .fold(vk::ShaderStageFlags::empty(), |flags, range| {
+                flags | range.stage_flags
+            });
+        vec![vk::PushConstantRange {
+            stage_flags,
+            offset: start,
+            size: end - start,
+        }]
+    }
+
+    /// Error if the supplied layout is missing a binding the shader uses
+    /// or declares it with a different type, count or stages
+    pub fn check_desc_set_layout(
+        &self,
+        set: u32,
+        supplied: &[DescriptorBinding],
+    ) -> Result<()> {
+        for expected in self.set_bindings(set) {
+            let actual = supplied
+                .iter()
+                .find(|b| b.binding == expected.binding)
+                .ok_or_else(|| {
+                    eyre!(
+                        "Shader uses set {} binding {} ({:?}) but the supplied layout does not have it",
+                        set,
+                        expected.binding,
+                        expected.desc_type
+                    )
+                })?;
+            if actual.desc_type != expected.desc_type {
+                return Err(eyre!(
+                    "Set {} binding {} is {:?} in the shader but {:?} in the supplied layout",
+                    set,
+                    expected.binding,
+                    expected.desc_type,
+                    actual.desc_type
+                ));
+            }
+            if actual.count < expected.count {
+                return Err(eyre!(
+                    "Set {} binding {} needs {} descriptors but the supplied layout has {}",
+                    set,
+                    expected.binding,
+                    expected.count,
+                    actual.count
+                ));
+            }
+            if !actual.stages.contains(expected.stages) {
+                return Err(eyre!(
+                    "Set {} binding {} is used in {:?} but the supplied layout only allows {:?}",
+                    set,
+                    expected.binding,
+                    expected.stages,
+                    actual.stages
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Error if a stage's push constants are not covered by a supplied range
+    pub fn check_push_constants(
+        &self,
+        supplied: &[vk::PushConstantRange],
+    ) -> Result<()> {
+        for expected in &self.push_constants {
+            let covered = supplied.iter().any(|range| {
+                range.stage_flags.contains(expected.stage_flags)
+                    && range.offset <= expected.offset
+                    && range.offset + range.size
+                        >= expected.offset + expected.size
+            });
+            if !covered {
+                return Err(eyre!(
+                    "{:?} stage uses push constants at [{}, {}) which no supplied range covers",
+                    expected.stage_flags,
+                    expected.offset,
+                    expected.offset + expected.size
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Error if a stage doesn't declare one of the constants
+    /// or declares it with a different size
+    pub fn check_spec_constants(
+        &self,
+        stage: vk::ShaderStageFlags,
+        constants: &SpecializationConstants,
+    ) -> Result<()> {
+        for (id, size) in constants.sizes() {
+            let declared = self
+                .spec_constants
+                .get(&id)
+                .filter(|constant| constant.stages.contains(stage))
+                .ok_or_else(|| {
+                    eyre!(
+                        "{:?} stage does not declare specialization constant {}",
+                        stage,
+                        id
+                    )
+                })?;
+            if declared.size != size {
+                return Err(eyre!(
+                    "Specialization constant {} is {} bytes in the shader but {} bytes were supplied",
+                    id,
+                    declared.size,
+                    size
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Error if a vertex shader input has no attribute of the same format
+    pub fn check_vertex_input(
+        &self,
+        desc: &VertexInputDescription,
+    ) -> Result<()> {
+        for input in &self.vertex_inputs {
+            let attribute = desc
+                .attributes
+                .iter()
+                .find(|a| a.location == input.location)
+                .ok_or_else(|| {
+                    eyre!(
+                        "Vertex shader input at location {} has no vertex attribute",
+                        input.location
+                    )
+                })?;
+            if attribute.format != input.format {
+                return Err(eyre!(
+                    "Vertex shader input at location {} is {:?} but the vertex attribute is {:?}",
+                    input.location,
+                    input.format,
+                    attribute.format
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SpirvType {
+    Bool,
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: Dim, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+    AccelerationStructure,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Decorations {
+    set: Option<u32>,
+    binding: Option<u32>,
+    location: Option<u32>,
+    offset: Option<u32>,
+    array_stride: Option<u32>,
+    matrix_stride: Option<u32>,
+    spec_id: Option<u32>,
+    builtin: bool,
+    block: bool,
+    buffer_block: bool,
+}
+
+struct Variable {
+    id: u32,
+    pointer_type: u32,
+    storage: StorageClass,
+}
+
+/// The parts of a SPIR-V module that reflection cares about
+#[derive(Default)]
+struct SpirvModule {
+    execution_model: Option<ExecutionModel>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    /// Result and type ids of specialization constants
+    spec_constants: Vec<(u32, u32)>,
+    decorations: HashMap<u32, Decorations>,
+    member_decorations: HashMap<(u32, u32), Decorations>,
+    variables: Vec<Variable>,
+    #[cfg(test)]
+    names: HashMap<u32, String>,
+    #[cfg(test)]
+    member_names: HashMap<(u32, u32), String>,
+}
+
+impl SpirvModule {
+    fn parse(code: &[u8]) -> Result<Self> {
+        if !code.len().is_multiple_of(4) {
+            return Err(eyre!("SPIR-V size is not a multiple of 4"));
+        }
+        let words = code
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        if words.len() < 5 || words[0] != spirv::MAGIC_NUMBER {
+            return Err(eyre!("Invalid SPIR-V header"));
+        }
+
+        let mut module = Self::default();
+        let mut i = 5;
+        while i < words.len() {
+            let word_count = (words[i] >> 16) as usize;
+            let opcode = words[i] & 0xffff;
+            if word_count == 0 || i + word_count > words.len() {
+                return Err(eyre!(
+                    "Malformed SPIR-V instruction at word {}",
+                    i
+                ));
+            }
+            let operands = &words[i + 1..i + word_count];
+            if let Some(op) = Op::from_u32(opcode) {
+                module.parse_instruction(op, operands)?;
+            }
+            i += word_count;
+        }
+
+        Ok(module)
+    }
+
+    fn parse_instruction(&mut self, op: Op, operands: &[u32]) -> Result<()> {
+        let operand = |index: usize| {
+            operands
+                .get(index)
+                .copied()
+                .ok_or_else(|| eyre!("Missing operand {} of {:?}", index, op))
+        };
+
+        match op {
+            Op::EntryPoint => {
+                if self.execution_model.is_none() {
+                    self.execution_model =
+                        ExecutionModel::from_u32(operand(0)?);
+                }
+            }
+            #[cfg(test)]
+            Op::Name => {
+                self.names
+                    .insert(operand(0)?, Self::parse_string(&operands[1..]));
+            }
+            #[cfg(test)]
+            Op::MemberName => {
+                self.member_names.insert(
+                    (operand(0)?, operand(1)?),
+                    Self::parse_string(&operands[2..]),
+                );
+            }
+            Op::Decorate => {
+                let decorations =
+                    self.decorations.entry(operand(0)?).or_default();
+                Self::parse_decoration(decorations, &operands[1..]);
+            }
+            Op::MemberDecorate => {
+                let decorations = self
+                    .member_decorations
+                    .entry((operand(0)?, operand(1)?))
+                    .or_default();
+                Self::parse_decoration(decorations, &operands[2..]);
+            }
+            Op::TypeBool => {
+                self.types.insert(operand(0)?, SpirvType::Bool);
+            }
+            Op::TypeInt => {
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Int {
+                        width: operand(1)?,
+                        signed: operand(2)? != 0,
+                    },
+                );
+            }
+            Op::TypeFloat => {
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Float { width: operand(1)? },
+                );
+            }
+            Op::TypeVector => {
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Vector {
+                        component: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            Op::TypeMatrix => {
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Matrix {
+                        column: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            Op::TypeImage => {
+                let dim = Dim::from_u32(operand(2)?)
+                    .ok_or_eyre("Unknown image dimension")?;
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Image {
+                        dim,
+                        sampled: operand(6)?,
+                    },
+                );
+            }
+            Op::TypeSampler => {
+                self.types.insert(operand(0)?, SpirvType::Sampler);
+            }
+            Op::TypeSampledImage => {
+                self.types.insert(operand(0)?, SpirvType::SampledImage);
+            }
+            Op::TypeArray => {
+                let length = *self
+                    .constants
+                    .get(&operand(2)?)
+                    .ok_or_eyre("Array length is not a known constant")?;
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Array {
+                        element: operand(1)?,
+                        length,
+                    },
+                );
+            }
+            Op::TypeRuntimeArray => {
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::RuntimeArray {
+                        element: operand(1)?,
+                    },
+                );
+            }
+            Op::TypeStruct => {
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Struct {
+                        members: operands[1..].to_vec(),
+                    },
+                );
+            }
+            // The storage class is read from the variables instead
+            Op::TypePointer => {
+                self.types.insert(
+                    operand(0)?,
+                    SpirvType::Pointer {
+                        pointee: operand(2)?,
+                    },
+                );
+            }
+            Op::TypeAccelerationStructureKHR => {
+                self.types
+                    .insert(operand(0)?, SpirvType::AccelerationStructure);
+            }
+            Op::Constant | Op::SpecConstant => {
+                // Only the low word matters for array lengths
+                self.constants.insert(operand(1)?, operand(2)?);
+                if op == Op::SpecConstant {
+                    self.spec_constants.push((operand(1)?, operand(0)?));
+                }
+            }
+            Op::SpecConstantTrue | Op::SpecConstantFalse => {
+                self.spec_constants.push((operand(1)?, operand(0)?));
+            }
+            Op::Variable => {
+                let storage = StorageClass::from_u32(operand(2)?)
+                    .ok_or_eyre("Unknown storage class")?;
+                self.variables.push(Variable {
+                    id: operand(1)?,
+                    pointer_type: operand(0)?,
+                    storage,
+                });
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn parse_decoration(decorations: &mut Decorations, operands: &[u32]) {
+        let Some(decoration) =
+            operands.first().and_then(|d| Decoration::from_u32(*d))
+        else {
+            return;
+        };
+        let literal = operands.get(1).copied();
+        match decoration {
+            Decoration::DescriptorSet => decorations.set = literal,
+            Decoration::Binding => decorations.binding = literal,
+            Decoration::Location => decorations.location = literal,
+            Decoration::Offset => decorations.offset = literal,
+            Decoration::ArrayStride => decorations.array_stride = literal,
+            Decoration::MatrixStride => decorations.matrix_stride = literal,
+            Decoration::SpecId => decorations.spec_id = literal,
+            Decoration::BuiltIn => decorations.builtin = true,
+            Decoration::Block => decorations.block = true,
+            Decoration::BufferBlock => decorations.buffer_block = true,
+            _ => {}
+        }
+    }
+
+    /// Literal strings are nul-terminated UTF-8 packed into words
+    #[cfg(test)]
+    fn parse_string(words: &[u32]) -> String {
+        let bytes = words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .take_while(|byte| *byte != 0)
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    #[cfg(test)]
+    fn struct_layouts(&self) -> Result<Vec<StructLayout>> {
+        let mut layouts = Vec::new();
+        for (type_id, ty) in &self.types {
+            let SpirvType::Struct { members } = ty else {
+                continue;
+            };
+            let Some(name) = self.names.get(type_id) else {
+                continue;
+            };
+            // Structs of function variables have no explicit layout
+            let offsets = (0..members.len() as u32)
+                .map(|index| {
+                    self.member_decorations
+                        .get(&(*type_id, index))
+                        .and_then(|d| d.offset)
+                })
+                .collect::<Option<Vec<_>>>();
+            let Some(offsets) = offsets else {
+                continue;
+            };
+            let members = offsets
+                .into_iter()
+                .enumerate()
+                .map(|(index, offset)| {
+                    let name = self
+                        .member_names
+                        .get(&(*type_id, index as u32))
+                        .cloned()
+                        .unwrap_or_default();
+                    (name, offset)
+                })
+                .collect();
+            layouts.push(StructLayout {
+                name: name.clone(),
+                size: self.size_of(*type_id, None)?,
+                members,
+            });
+        }
+        Ok(layouts)
+    }
+
+    fn reflect(&self) -> Result<ShaderReflection> {
+        let stage = match self
+            .execution_model
+            .ok_or_eyre("SPIR-V module has no entry point")?
+        {
+            ExecutionModel::Vertex => vk::ShaderStageFlags::VERTEX,
+            ExecutionModel::TessellationControl => {
+                vk::ShaderStageFlags::TESSELLATION_CONTROL
+            }
+            ExecutionModel::TessellationEvaluation => {
+                vk::ShaderStageFlags::TESSELLATION_EVALUATION
+            }
+            ExecutionModel::Geometry => vk::ShaderStageFlags::GEOMETRY,
+            ExecutionModel::Fragment => vk::ShaderStageFlags::FRAGMENT,
+            ExecutionModel::GLCompute => vk::ShaderStageFlags::COMPUTE,
+            model => {
+                return Err(eyre!("Unsupported execution model {:?}", model))
+            }
+        };
+
+        let mut reflection = ShaderReflection {
+            stages: stage,
+            ..Default::default()
+        };
+
+        for variable in &self.variables {
+            let decorations = self
+                .decorations
+                .get(&variable.id)
+                .copied()
+                .unwrap_or_default();
+            let pointee = match self.types.get(&variable.pointer_type) {
+                Some(SpirvType::Pointer { pointee, .. }) => *pointee,
+                _ => {
+                    return Err(eyre!(
+                        "Variable {} is not a pointer",
+                        variable.id
+                    ))
+                }
+            };
+
+            match variable.storage {
+                StorageClass::UniformConstant
+                | StorageClass::Uniform
+                | StorageClass::StorageBuffer => {
+                    let (Some(set), Some(binding)) =
+                        (decorations.set, decorations.binding)
+                    else {
+                        continue;
+                    };
+                    let (desc_type, count) =
+                        self.descriptor_type(pointee, variable.storage)?;
+                    reflection.sets.entry(set).or_default().push(
+                        DescriptorBinding {
+                            binding,
+                            desc_type,
+                            count,
+                            stages: stage,
+                        },
+                    );
+                }
+                StorageClass::PushConstant => {
+                    let offset = self.min_member_offset(pointee);
+                    let size = self.size_of(pointee, None)?;
+                    reflection.push_constants.push(vk::PushConstantRange {
+                        stage_flags: stage,
+                        offset,
+                        size: size - offset,
+                    });
+                }
+                StorageClass::Input
+                    if stage == vk::ShaderStageFlags::VERTEX =>
+                {
+                    if decorations.builtin {
+                        continue;
+                    }
+                    let location = decorations
+                        .location
+                        .ok_or_eyre("Vertex input has no location")?;
+                    let (format, size) = self.vertex_format(pointee)?;
+                    reflection.vertex_inputs.push(VertexInput {
+                        location,
+                        format,
+                        size,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        for (result_id, type_id) in &self.spec_constants {
+            let Some(id) = self
+                .decorations
+                .get(result_id)
+                .and_then(|decorations| decorations.spec_id)
+            else {
+                continue;
+            };
+            reflection.spec_constants.insert(
+                id,
+                SpecConstant {
+                    stages: stage,
+                    size: self.size_of(*type_id, None)?,
+                },
+            );
+        }
+
+        for bindings in reflection.sets.values_mut() {
+            bindings.sort_by_key(|b| b.binding);
+        }
+        reflection.vertex_inputs.sort_by_key(|input| input.location);
+
+        Ok(reflection)
+    }
+
+    /// Returns the descriptor type and count.
+    /// Runtime arrays have a count of 0.
+    fn descriptor_type(
+        &self,
+        type_id: u32,
+        storage: StorageClass,
+    ) -> Result<(vk::DescriptorType, u32)> {
+        let ty = self.get_type(type_id)?;
+        let desc_type = match ty {
+            SpirvType::Array { element, length } => {
+                let (desc_type, count) =
+                    self.descriptor_type(*element, storage)?;
+                return Ok((desc_type, count.max(1) * length));
+            }
+            SpirvType::RuntimeArray { element } => {
+                let (desc_type, _) = self.descriptor_type(*element, storage)?;
+                return Ok((desc_type, 0));
+            }
+            SpirvType::SampledImage => {
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+            }
+            SpirvType::Sampler => vk::DescriptorType::SAMPLER,
+            SpirvType::Image { dim, sampled } => match (dim, sampled) {
+                (Dim::DimBuffer, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                (Dim::DimBuffer, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                (Dim::DimSubpassData, _) => {
+                    vk::DescriptorType::INPUT_ATTACHMENT
+                }
+                (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                _ => vk::DescriptorType::SAMPLED_IMAGE,
+            },
+            SpirvType::AccelerationStructure => {
+                vk::DescriptorType::ACCELERATION_STRUCTURE_KHR
+            }
+            SpirvType::Struct { .. } => {
+                let decorations =
+                    self.decorations.get(&type_id).copied().unwrap_or_default();
+                if storage == StorageClass::StorageBuffer
+                    || decorations.buffer_block
+                {
+                    vk::DescriptorType::STORAGE_BUFFER
+                } else {
+                    vk::DescriptorType::UNIFORM_BUFFER
+                }
+            }
+            _ => return Err(eyre!("Type {} is not a descriptor", type_id)),
+        };
+        Ok((desc_type, 1))
+    }
+
+    fn vertex_format(&self, type_id: u32) -> Result<(vk::Format, u32)> {
+        let (scalar, count) = match self.get_type(type_id)? {
+            SpirvType::Vector { component, count } => {
+                (self.get_type(*component)?, *count)
+            }
+            ty => (ty, 1),
+        };
+        let format = match (scalar, count) {
+            (SpirvType::Float { width: 32 }, 1) => vk::Format::R32_SFLOAT,
+            (SpirvType::Float { width: 32 }, 2) => vk::Format::R32G32_SFLOAT,
+            (SpirvType::Float { width: 32 }, 3) => vk::Format::R32G32B32_SFLOAT,
+            (SpirvType::Float { width: 32 }, 4) => {
+                vk::Format::R32G32B32A32_SFLOAT
+            }
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: true,
+                },
+                1,
+            ) => vk::Format::R32_SINT,
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: true,
+                },
+                2,
+            ) => vk::Format::R32G32_SINT,
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: true,
+                },
+                3,
+            ) => vk::Format::R32G32B32_SINT,
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: true,
+                },
+                4,
+            ) => vk::Format::R32G32B32A32_SINT,
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: false,
+                },
+                1,
+            ) => vk::Format::R32_UINT,
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: false,
+                },
+                2,
+            ) => vk::Format::R32G32_UINT,
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: false,
+                },
+                3,
+            ) => vk::Format::R32G32B32_UINT,
+            (
+                SpirvType::Int {
+                    width: 32,
+                    signed: false,
+                },
+                4,
+            ) => vk::Format::R32G32B32A32_UINT,
+            (ty, count) => {
+                return Err(eyre!(
+                    "Unsupported vertex input type {:?} x{}",
+                    ty,
+                    count
+                ))
+            }
+        };
+        Ok((format, 4 * count))
+    }
+
+    /// Size in bytes of a type inside a uniform/storage/push constant block
+    fn size_of(&self, type_id: u32, matrix_stride: Option<u32>) -> Result<u32> {
+        let size = match self.get_type(type_id)? {
+            SpirvType::Bool => 4,
+            SpirvType::Int { width, .. } | SpirvType::Float { width } => {
+                width / 8
+            }
+            SpirvType::Vector { component, count } => {
+                count * self.size_of(*component, None)?
+            }
+            SpirvType::Matrix { column, count } => match matrix_stride {
+                Some(stride) => count * stride,
+                None => count * self.size_of(*column, None)?,
+            },
+            SpirvType::Array { element, length } => {
+                let stride = match self
+                    .decorations
+                    .get(&type_id)
+                    .and_then(|d| d.array_stride)
+                {
+                    Some(stride) => stride,
+                    None => self.size_of(*element, matrix_stride)?,
+                };
+                length * stride
+            }
+            SpirvType::RuntimeArray { .. } => 0,
+            SpirvType::Struct { members } => {
+                let mut size = 0;
+                for (index, member) in members.iter().enumerate() {
+                    let decorations = self
+                        .member_decorations
+                        .get(&(type_id, index as u32))
+                        .copied()
+                        .unwrap_or_default();
+                    let end = decorations.offset.unwrap_or(0)
+                        + self.size_of(*member, decorations.matrix_stride)?;
+                    size = size.max(end);
+                }
+                size
+            }
+            // Buffer references are 64-bit device addresses
+            SpirvType::Pointer { .. } => 8,
+            ty => return Err(eyre!("Type {:?} has no size", ty)),
+        };
+        Ok(size)
+    }
+
+    fn min_member_offset(&self, type_id: u32) -> u32 {
+        match self.types.get(&type_id) {
+            Some(SpirvType::Struct { members }) => (0..members.len() as u32)
+                .filter_map(|index| {
+                    self.member_decorations
+                        .get(&(type_id, index))
+                        .and_then(|d| d.offset)
+                })
+                .min()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn get_type(&self, type_id: u32) -> Result<&SpirvType> {
+        self.types
+            .get(&type_id)
+            .ok_or_else(|| eyre!("Unknown SPIR-V type {}", type_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::renderer::{
+        gpu_data::GpuDrawPushConstants, shader_compiler::ShaderCompiler,
+        vertex::Vertex,
+    };
+
+    fn shaders_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders")
+    }
+
+    /// Merged reflection of every stage of the variant without keywords
+    fn reflect_shader(filename: &str) -> ShaderReflection {
+        let shaders_dir = shaders_dir();
+        let compiler = ShaderCompiler::new().unwrap().include_dir(&shaders_dir);
+        let shader = compiler
+            .compile_file(&shaders_dir.join(filename))
+            .unwrap()
+            .into_iter()
+            .find(|shader| shader.keywords.is_empty())
+            .unwrap();
+        shader
+            .stages
+            .iter()
+            .map(|(_, spv)| ShaderReflection::from_spirv(spv).unwrap())
+            .reduce(|merged, stage| merged.merge(stage).unwrap())
+            .unwrap()
+    }
+
+    fn binding(reflection: &ShaderReflection, set: u32) -> DescriptorBinding {
+        let bindings = reflection.set_bindings(set);
+        assert_eq!(bindings.len(), 1, "set {} bindings: {:?}", set, bindings);
+        bindings[0]
+    }
+
+    #[test]
+    fn test_reflect_mesh_shader() {
+        let reflection = reflect_shader("mesh.combined");
+        let graphics =
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT;
+        assert_eq!(reflection.stages, graphics);
+        assert_eq!(reflection.set_count(), 3);
+
+        let scene = binding(&reflection, 0);
+        assert_eq!(scene.binding, 0);
+        assert_eq!(scene.desc_type, vk::DescriptorType::UNIFORM_BUFFER);
+        assert_eq!(scene.count, 1);
+        assert_eq!(scene.stages, graphics);
+
+        let material = binding(&reflection, 1);
+        assert_eq!(material.binding, 0);
+        assert_eq!(material.desc_type, vk::DescriptorType::UNIFORM_BUFFER);
+        assert_eq!(material.stages, graphics);
+
+        let textures = binding(&reflection, 2);
+        assert_eq!(textures.binding, 0);
+        assert_eq!(
+            textures.desc_type,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        );
+        assert_eq!(textures.count, 0, "textures[] is a runtime array");
+        assert!(textures.stages.contains(vk::ShaderStageFlags::FRAGMENT));
+
+        let ranges = reflection
+            .push_constant_ranges()
+            .iter()
+            .map(|range| (range.stage_flags, range.offset, range.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![(vk::ShaderStageFlags::VERTEX, 0, GpuDrawPushConstants::SIZE)]
+        );
+        // Vertices are pulled from a buffer reference
+        assert!(reflection.vertex_inputs.is_empty());
+    }
+
+    #[test]
+    fn test_reflect_vertex_inputs() {
+        let reflection = reflect_shader("default.combined");
+        let inputs = reflection
+            .vertex_inputs
+            .iter()
+            .map(|input| (input.location, input.format, input.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            inputs,
+            vec![
+                (0, vk::Format::R32G32B32_SFLOAT, 12),
+                (1, vk::Format::R32G32B32_SFLOAT, 12),
+                (2, vk::Format::R32G32B32_SFLOAT, 12),
+                (3, vk::Format::R32G32_SFLOAT, 8),
+            ]
+        );
+        assert!(reflection.push_constants.is_empty());
+
+        // The default vertex input state of graphics materials
+        reflection
+            .check_vertex_input(&Vertex::get_vertex_desc())
+            .unwrap();
+        let mut desc = Vertex::get_vertex_desc();
+        desc.attributes[3].format = vk::Format::R32G32B32_SFLOAT;
+        assert!(reflection.check_vertex_input(&desc).is_err());
+        desc.attributes.pop();
+        assert!(reflection.check_vertex_input(&desc).is_err());
+    }
+
+    #[test]
+    fn test_reflect_compute_shader() {
+        let reflection = reflect_shader("gradient.comp");
+        assert_eq!(reflection.stages, vk::ShaderStageFlags::COMPUTE);
+
+        let image = binding(&reflection, 0);
+        assert_eq!(image.binding, 0);
+        assert_eq!(image.desc_type, vk::DescriptorType::STORAGE_IMAGE);
+        assert_eq!(image.count, 1);
+
+        // local_size_x_id and local_size_y_id
+        for id in [0, 1] {
+            assert_eq!(
+                reflection.spec_constants.get(&id),
+                Some(&SpecConstant {
+                    stages: vk::ShaderStageFlags::COMPUTE,
+                    size: 4,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_reflect_spec_constants() {
+        let reflection = reflect_shader("outline.combined");
+        let float = |stages| SpecConstant { stages, size: 4 };
+        assert_eq!(
+            reflection.spec_constants,
+            BTreeMap::from([
+                (0, float(vk::ShaderStageFlags::VERTEX)),
+                (1, float(vk::ShaderStageFlags::FRAGMENT)),
+                (2, float(vk::ShaderStageFlags::FRAGMENT)),
+                (3, float(vk::ShaderStageFlags::FRAGMENT)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_check_spec_constants() {
+        let reflection = ShaderReflection {
+            spec_constants: BTreeMap::from([
+                (
+                    0,
+                    SpecConstant {
+                        stages: vk::ShaderStageFlags::VERTEX,
+                        size: 4,
+                    },
+                ),
+                (
+                    1,
+                    SpecConstant {
+                        stages: vk::ShaderStageFlags::VERTEX
+                            | vk::ShaderStageFlags::FRAGMENT,
+                        size: 8,
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        let vertex = vk::ShaderStageFlags::VERTEX;
+        let fragment = vk::ShaderStageFlags::FRAGMENT;
+
+        reflection
+            .check_spec_constants(vertex, &SpecializationConstants::new())
+            .unwrap();
+        let constants =
+            SpecializationConstants::new().set(0, 0.5f32).set(1, 2.0f64);
+        reflection.check_spec_constants(vertex, &constants).unwrap();
+        let constants = SpecializationConstants::new().set(1, 7u64);
+        reflection
+            .check_spec_constants(fragment, &constants)
+            .unwrap();
+
+        // Declared in another stage
+        let constants = SpecializationConstants::new().set(0, 0.5f32);
+        assert!(reflection
+            .check_spec_constants(fragment, &constants)
+            .is_err());
+        // Not declared at all
+        let constants = SpecializationConstants::new().set(2, 1u32);
+        assert!(reflection.check_spec_constants(vertex, &constants).is_err());
+        // Declared as a double
+        let constants = SpecializationConstants::new().set(1, 2.0f32);
+        assert!(reflection.check_spec_constants(vertex, &constants).is_err());
+    }
+
+    #[test]
+    fn test_check_pipeline_layout() {
+        let reflection = reflect_shader("mesh.combined");
+        let graphics =
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT;
+        let uniform = DescriptorBinding {
+            binding: 0,
+            desc_type: vk::DescriptorType::UNIFORM_BUFFER,
+            count: 1,
+            stages: graphics,
+        };
+        reflection.check_desc_set_layout(0, &[uniform]).unwrap();
+
+        let vertex_only = DescriptorBinding {
+            stages: vk::ShaderStageFlags::VERTEX,
+            ..uniform
+        };
+        assert!(reflection.check_desc_set_layout(0, &[vertex_only]).is_err());
+        let storage = DescriptorBinding {
+            desc_type: vk::DescriptorType::STORAGE_BUFFER,
+            ..uniform
+        };
+        assert!(reflection.check_desc_set_layout(0, &[storage]).is_err());
+        assert!(reflection.check_desc_set_layout(0, &[]).is_err());
+
+        let range = vk::PushConstantRange {
+            stage_flags: graphics,
+            offset: 0,
+            size: GpuDrawPushConstants::SIZE,
+        };
+        reflection.check_push_constants(&[range]).unwrap();
+        let short = vk::PushConstantRange {
+            size: GpuDrawPushConstants::SIZE - 8,
+            ..range
+        };
+        assert!(reflection.check_push_constants(&[short]).is_err());
+        assert!(reflection.check_push_constants(&[]).is_err());
+    }
+}
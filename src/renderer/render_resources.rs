@@ -1,19 +1,40 @@
 use std::collections::HashMap;
 
 use ash::vk;
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{eyre, OptionExt, Result};
 use gpu_allocator::vulkan::Allocator;
 
-use super::{material::Material, model::Model, texture::Texture, vkinit};
+use super::{
+    bindless::BindlessTextures,
+    buffer::AllocatedBuffer,
+    descriptors::{DescriptorBinding, DescriptorSetLayoutCache},
+    material::{Material, MaterialInstance},
+    model::Model,
+    pipeline_cache::PipelineCache,
+    texture::Texture,
+    vkinit,
+};
 
 /// Shared resources for rendering
 #[derive(Default)]
 pub struct RenderResources {
     pub models: HashMap<String, Model>,
     pub textures: HashMap<String, Texture>,
+    /// Holds every texture added with `add_texture`
+    pub bindless_textures: Option<BindlessTextures>,
     pub materials: HashMap<String, Material>,
+    /// Pipelines shared by the materials
+    pub pipeline_cache: PipelineCache,
+    /// Descriptor sets of these are allocated for the lifetime of the renderer
+    pub material_instances: HashMap<String, MaterialInstance>,
+    /// Long-lived buffers, such as material uniform buffers
+    pub buffers: HashMap<String, AllocatedBuffer>,
     pub samplers: HashMap<vk::Filter, vk::Sampler>,
-    pub desc_set_layouts: HashMap<String, vk::DescriptorSetLayout>,
+    /// Every descriptor set layout, shared by builders with the same bindings
+    pub desc_set_layout_cache: DescriptorSetLayoutCache,
+    /// Counts removals, so that caches of resource handles can tell
+    /// when a handle may have been destroyed and reused
+    generation: u64,
 }
 
 impl RenderResources {
@@ -32,6 +53,104 @@ impl RenderResources {
         Ok(())
     }
 
+    /// Create the bindless texture array and its layout
+    pub fn init_bindless_textures(
+        &mut self,
+        device: &ash::Device,
+        max_bindless_textures: u32,
+    ) -> Result<()> {
+        let capacity = BindlessTextures::capacity_for(max_bindless_textures);
+        let layout = BindlessTextures::layout_builder(capacity)
+            .build(device, &mut self.desc_set_layout_cache)?;
+        self.bindless_textures =
+            Some(BindlessTextures::new(device, layout, capacity)?);
+        Ok(())
+    }
+
+    /// Insert a texture and add it to the bindless texture array.
+    /// Returns the index shaders sample the texture with.
+    /// On error nothing is inserted and the texture is cleaned up.
+    pub fn add_texture(
+        &mut self,
+        name: &str,
+        texture: Texture,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<u32> {
+        let index = if self.textures.contains_key(name) {
+            Err(eyre!("Texture {} already exists", name))
+        } else {
+            self.bindless_textures
+                .as_mut()
+                .ok_or_eyre("Bindless textures not initialized")
+                .and_then(|bindless| bindless.add(device, name, &texture))
+        };
+        match index {
+            Ok(index) => {
+                self.textures.insert(name.into(), texture);
+                Ok(index)
+            }
+            Err(err) => {
+                texture.cleanup(device, allocator);
+                Err(err)
+            }
+        }
+    }
+
+    /// Remove a texture from the resources and the bindless texture array
+    pub fn remove_texture(&mut self, name: &str) -> Option<Texture> {
+        if let Some(bindless) = &mut self.bindless_textures {
+            bindless.remove(name);
+        }
+        let texture = self.textures.remove(name);
+        if texture.is_some() {
+            self.generation += 1;
+        }
+        texture
+    }
+
+    /// Changes whenever a resource is removed.
+    /// Descriptor set caches clear their sets once it changed.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Insert materials by name, cleaning up the ones they replace
+    pub fn replace_materials(
+        &mut self,
+        materials: Vec<(String, Material)>,
+        device: &ash::Device,
+    ) {
+        for (name, material) in materials {
+            if let Some(old) = self.materials.insert(name, material) {
+                old.cleanup(device);
+            }
+        }
+    }
+
+    /// Index of a texture in the bindless texture array
+    pub fn texture_index(&self, name: &str) -> Result<u32> {
+        self.bindless_textures
+            .as_ref()
+            .and_then(|bindless| bindless.index(name))
+            .ok_or_else(|| eyre!("Texture \"{}\" is not bindless", name))
+    }
+
+    /// Layout of the bindless texture array along with its bindings,
+    /// supplied to materials that sample it
+    pub fn bindless_layout(
+        &self,
+    ) -> Result<(vk::DescriptorSetLayout, Vec<DescriptorBinding>)> {
+        self.bindless_textures
+            .as_ref()
+            .and_then(|bindless| {
+                let layout = bindless.layout();
+                let bindings = self.desc_set_layout_cache.bindings(layout)?;
+                Some((layout, bindings.to_vec()))
+            })
+            .ok_or_eyre("Bindless textures not initialized")
+    }
+
     pub fn cleanup(&mut self, device: &ash::Device, allocator: &mut Allocator) {
         self.models
             .drain()
@@ -39,17 +158,21 @@ impl RenderResources {
         self.textures
             .drain()
             .for_each(|(_, texture)| texture.cleanup(device, allocator));
+        self.buffers
+            .drain()
+            .for_each(|(_, buffer)| buffer.cleanup(device, allocator));
+        self.material_instances.clear();
+        if let Some(bindless) = self.bindless_textures.take() {
+            bindless.cleanup(device);
+        }
         self.materials
             .drain()
             .for_each(|(_, material)| material.cleanup(device));
         self.samplers.drain().for_each(|(_, sampler)| unsafe {
             device.destroy_sampler(sampler, None);
         });
-        self.desc_set_layouts
-            .drain()
-            .for_each(|(_, layout)| unsafe {
-                device.destroy_descriptor_set_layout(layout, None)
-            });
+        self.desc_set_layout_cache.cleanup(device);
+        self.generation += 1;
     }
 
     fn default_sampler(device: &ash::Device) -> Result<vk::Sampler> {
@@ -0,0 +1,208 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use ash::vk;
+use bevy::log;
+use color_eyre::eyre::{eyre, OptionExt, Result};
+use gpu_allocator::vulkan::Allocator;
+
+use super::{
+    attachments::RenderAttachments,
+    camera::Camera,
+    context::Context,
+    debug_view::DebugView,
+    descriptors::DescriptorAllocatorStats,
+    frame::Frame,
+    inner::{DrawContext, DrawTarget, FRAME_OVERLAP},
+    render_resources::RenderResources,
+    texture::Texture,
+};
+
+/// Offscreen color + depth target that a camera renders into.
+/// With multisampling the pass resolves into the color texture.
+/// The color image is stored in `RenderResources::textures` under `name`,
+/// so any material can sample it through the bindless texture array.
+pub struct RenderTexture {
+    name: String,
+    extent: vk::Extent2D,
+    attachments: Arc<RenderAttachments>,
+
+    frame_number: u32,
+    frames: Vec<Frame>,
+}
+
+impl RenderTexture {
+    /// The color format matches the swapchain
+    /// so that existing materials can draw into it
+    pub fn new(
+        name: &str,
+        extent: vk::Extent2D,
+        color_format: vk::Format,
+        ctx: &Context,
+        resources: &mut RenderResources,
+        allocator: &mut Allocator,
+        command_pool: &vk::CommandPool,
+        samples: vk::SampleCountFlags,
+    ) -> Result<Self> {
+        if resources.textures.contains_key(name) {
+            return Err(eyre!("Texture {} already exists", name));
+        }
+
+        if !resources.samplers.contains_key(&vk::Filter::LINEAR) {
+            resources.create_sampler(vk::Filter::LINEAR, &ctx.device)?;
+        }
+        let color_texture = Texture::new_render_texture(
+            extent.width,
+            extent.height,
+            color_format,
+            resources.samplers[&vk::Filter::LINEAR],
+            &ctx.device,
+            allocator,
+        )?;
+        resources.add_texture(name, color_texture, &ctx.device, allocator)?;
+        let remove_texture =
+            |resources: &mut RenderResources, allocator: &mut Allocator| {
+                if let Some(texture) = resources.remove_texture(name) {
+                    texture.cleanup(&ctx.device, allocator);
+                }
+            };
+
+        let attachments = match RenderAttachments::new(
+            extent,
+            color_format,
+            ctx.depth_format,
+            samples,
+            &ctx.device,
+            allocator,
+        ) {
+            Ok(attachments) => attachments,
+            Err(err) => {
+                remove_texture(resources, allocator);
+                return Err(err);
+            }
+        };
+
+        let mut frames = Vec::with_capacity(FRAME_OVERLAP as usize);
+        for _ in 0..FRAME_OVERLAP {
+            match Frame::new(ctx, allocator, command_pool) {
+                Ok(frame) => frames.push(frame),
+                Err(err) => {
+                    for frame in frames {
+                        frame.cleanup(&ctx.device, allocator);
+                    }
+                    attachments.cleanup(&ctx.device, allocator);
+                    remove_texture(resources, allocator);
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(Self {
+            name: name.into(),
+            extent,
+            attachments: Arc::new(attachments),
+            frame_number: 0,
+            frames,
+        })
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    pub fn draw_frame(
+        &mut self,
+        context: Arc<Context>,
+        resources: Arc<Mutex<RenderResources>>,
+        camera: &Camera,
+        debug_view: DebugView,
+        selected_models: &HashSet<String>,
+    ) -> Result<()> {
+        let ctx = DrawContext {
+            context,
+            target: DrawTarget::Texture {
+                name: self.name.clone(),
+                extent: self.extent,
+                attachments: self.attachments.clone(),
+            },
+            resources,
+            frame_number: self.frame_number,
+            camera,
+            debug_view,
+            selected_models,
+        };
+        self.get_current_frame().draw(ctx)?;
+        self.frame_number += 1;
+
+        Ok(())
+    }
+
+    /// Recreate the attachments of the geometry pass with a different sample
+    /// count once all frames of this texture have finished rendering
+    pub fn set_samples(
+        &mut self,
+        samples: vk::SampleCountFlags,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<()> {
+        self.wait_idle(device)?;
+        Arc::get_mut(&mut self.attachments)
+            .ok_or_eyre("Render texture attachments are still in use")?
+            .set_samples(samples, device, allocator)
+    }
+
+    /// Descriptor allocator statistics of each frame in flight
+    pub fn desc_stats(&self) -> Vec<DescriptorAllocatorStats> {
+        self.frames.iter().map(|frame| frame.desc_stats()).collect()
+    }
+
+    /// Block until all frames of this texture have finished rendering
+    pub fn wait_idle(&self, device: &ash::Device) -> Result<()> {
+        let fences = self
+            .frames
+            .iter()
+            .map(|frame| frame.render_fence())
+            .collect::<Vec<_>>();
+        unsafe {
+            device.wait_for_fences(&fences, true, 1000000000)?;
+        }
+        Ok(())
+    }
+
+    /// The color texture is removed from `resources` if it is still there
+    pub fn cleanup(
+        mut self,
+        device: &ash::Device,
+        resources: &mut RenderResources,
+        allocator: &mut Allocator,
+    ) {
+        if let Err(err) = self.wait_idle(device) {
+            log::error!("Failed to wait for render texture frames: {}", err);
+        }
+
+        for frame in self.frames.drain(..) {
+            frame.cleanup(device, allocator);
+        }
+
+        if let Some(texture) = resources.remove_texture(&self.name) {
+            texture.cleanup(device, allocator);
+        }
+
+        match Arc::try_unwrap(self.attachments) {
+            Ok(attachments) => {
+                attachments.cleanup(device, allocator);
+                Ok(())
+            }
+            Err(_) => {
+                Err(eyre!("Failed to cleanup render texture attachments"))
+            }
+        }
+        .unwrap();
+    }
+
+    fn get_current_frame(&mut self) -> &mut Frame {
+        &mut self.frames[(self.frame_number % FRAME_OVERLAP) as usize]
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use ash::vk;
+use bevy::log;
+use color_eyre::eyre::{eyre, OptionExt, Result};
+use gpu_allocator::vulkan::Allocator;
+
+use super::{
+    camera::Camera,
+    context::Context,
+    debug_view::DebugView,
+    descriptors::DescriptorAllocatorStats,
+    frame::Frame,
+    inner::{DrawContext, DrawTarget, FRAME_OVERLAP},
+    render_resources::RenderResources,
+    swapchain::Swapchain,
+    texture::Texture,
+};
+
+/// Everything needed to present to a single window:
+/// its surface, swapchain and the frames in flight that draw into it
+pub struct RenderWindow {
+    surface: vk::SurfaceKHR,
+    swapchain: Arc<Swapchain>,
+
+    frame_number: u32,
+    frames: Vec<Frame>,
+
+    background_texture: Arc<Mutex<Texture>>,
+}
+
+impl RenderWindow {
+    pub fn new(
+        surface: vk::SurfaceKHR,
+        window: &winit::window::Window,
+        ctx: &Context,
+        allocator: &mut Allocator,
+        command_pool: &vk::CommandPool,
+        samples: vk::SampleCountFlags,
+    ) -> Result<Self> {
+        let swapchain =
+            Swapchain::new(ctx, surface, allocator, window, samples)?;
+
+        let frames = {
+            let mut frames = Vec::with_capacity(FRAME_OVERLAP as usize);
+            for _ in 0..FRAME_OVERLAP {
+                frames.push(Frame::new(ctx, allocator, command_pool)?);
+            }
+            frames
+        };
+
+        let background_texture = Texture::new_compute_texture(
+            swapchain.image_extent.width,
+            swapchain.image_extent.height,
+            &ctx.device,
+            allocator,
+        )?;
+
+        Ok(Self {
+            surface,
+            swapchain: Arc::new(swapchain),
+            frame_number: 0,
+            frames,
+            background_texture: Arc::new(Mutex::new(background_texture)),
+        })
+    }
+
+    pub fn swapchain(&self) -> &Swapchain {
+        &self.swapchain
+    }
+
+    pub fn draw_frame(
+        &mut self,
+        context: Arc<Context>,
+        resources: Arc<Mutex<RenderResources>>,
+        camera: &Camera,
+        debug_view: DebugView,
+        selected_models: &HashSet<String>,
+    ) -> Result<()> {
+        let ctx = DrawContext {
+            context,
+            target: DrawTarget::Window {
+                swapchain: self.swapchain.clone(),
+                background_texture: self.background_texture.clone(),
+            },
+            resources,
+            frame_number: self.frame_number,
+            camera,
+            debug_view,
+            selected_models,
+        };
+        self.get_current_frame().draw(ctx)?;
+        self.frame_number += 1;
+
+        Ok(())
+    }
+
+    /// Recreate the attachments of the geometry pass with a different sample
+    /// count once all frames of this window have finished rendering
+    pub fn set_samples(
+        &mut self,
+        samples: vk::SampleCountFlags,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<()> {
+        self.wait_idle(device)?;
+        Arc::get_mut(&mut self.swapchain)
+            .ok_or_eyre("Swapchain is still in use")?
+            .attachments
+            .set_samples(samples, device, allocator)
+    }
+
+    /// Descriptor allocator statistics of each frame in flight
+    pub fn desc_stats(&self) -> Vec<DescriptorAllocatorStats> {
+        self.frames.iter().map(|frame| frame.desc_stats()).collect()
+    }
+
+    /// Block until all frames of this window have finished rendering
+    pub fn wait_idle(&self, device: &ash::Device) -> Result<()> {
+        let fences = self
+            .frames
+            .iter()
+            .map(|frame| frame.render_fence())
+            .collect::<Vec<_>>();
+        unsafe {
+            device.wait_for_fences(&fences, true, 1000000000)?;
+        }
+        Ok(())
+    }
+
+    /// Make sure to call this BEFORE the window itself gets destroyed
+    pub fn cleanup(mut self, ctx: &Context, allocator: &mut Allocator) {
+        if let Err(err) = self.wait_idle(&ctx.device) {
+            log::error!("Failed to wait for window frames: {}", err);
+        }
+
+        let device = &ctx.device;
+
+        // Clean up all frames
+        for frame in self.frames.drain(..) {
+            frame.cleanup(device, allocator);
+        }
+
+        // Clean up background texture
+        match Arc::try_unwrap(self.background_texture) {
+            Ok(texture) => {
+                texture.into_inner().unwrap().cleanup(device, allocator);
+                Ok(())
+            }
+            Err(_) => Err(eyre!("Failed to cleanup background texture")),
+        }
+        .unwrap();
+
+        // Clean up swapchain
+        match Arc::try_unwrap(self.swapchain) {
+            Ok(swapchain) => {
+                swapchain.cleanup(device, allocator);
+                Ok(())
+            }
+            Err(_) => Err(eyre!("Failed to cleanup swapchain")),
+        }
+        .unwrap();
+
+        // Segfault occurs here if window gets destroyed before surface
+        ctx.destroy_surface(self.surface);
+    }
+
+    fn get_current_frame(&mut self) -> &mut Frame {
+        &mut self.frames[(self.frame_number % FRAME_OVERLAP) as usize]
+    }
+}
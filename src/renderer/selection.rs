@@ -0,0 +1,12 @@
+use bevy::ecs::component::Component;
+
+/// Entity standing in for a model of the render resources,
+/// e.g. "backpack"
+#[derive(Component)]
+pub struct SceneModel {
+    pub name: String,
+}
+
+/// Marks scene models that are drawn with an outline
+#[derive(Component)]
+pub struct Selected;
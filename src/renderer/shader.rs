@@ -1,85 +1,209 @@
 use ash::vk;
 use bevy::log;
-use color_eyre::eyre::{Context, OptionExt, Result};
-use std::{fs::File, io::Read, path::PathBuf};
+#[cfg(feature = "hot-reload")]
+use color_eyre::eyre::OptionExt;
+use color_eyre::eyre::{eyre, Context, Result};
+use std::{fs::File, io::Read, path::Path};
 
-use super::SHADERBUILD_DIR;
+#[cfg(feature = "embed-shaders")]
+use super::embedded_shaders;
+use super::{
+    pipeline_cache::ShaderKey, reflection::ShaderReflection,
+    shader_variant::variant_name, SHADERBUILD_DIR,
+};
+#[cfg(feature = "hot-reload")]
+use super::{
+    shader_compiler::{ShaderCompiler, COMBINED_SHADER_EXT},
+    shader_manifest, SHADERS_DIR,
+};
 
 #[derive(Clone)]
 pub struct GraphicsShader {
+    pub name: String,
     pub vert_shader_mod: vk::ShaderModule,
+    pub tesc_shader_mod: Option<vk::ShaderModule>,
+    pub tese_shader_mod: Option<vk::ShaderModule>,
+    pub geom_shader_mod: Option<vk::ShaderModule>,
     pub frag_shader_mod: vk::ShaderModule,
+    /// Combined reflection of all stages
+    pub reflection: ShaderReflection,
+    /// Identify the stages in pipeline keys
+    pub stage_keys: Vec<ShaderKey>,
 }
 
 impl GraphicsShader {
-    pub fn new(shadername: &str, device: &ash::Device) -> Result<Self> {
-        let shaderbuild_dir = unsafe {
-            SHADERBUILD_DIR
-                .as_ref()
-                .ok_or_eyre("Shader build directory not specified")?
+    /// Load the variant of a combined shader with the given keywords enabled.
+    /// build.rs compiles every variant into the shader build directory,
+    /// named by keyword hash. The stages are read from there if it has the
+    /// vertex stage, otherwise from the shaders embedded in the binary.
+    /// With the hot-reload feature a variant that neither has
+    /// is compiled on demand.
+    /// Tessellation and geometry stages are loaded if the variant has them.
+    pub fn new(
+        shadername: &str,
+        keywords: &[String],
+        device: &ash::Device,
+    ) -> Result<Self> {
+        let variant = variant_name(shadername, keywords);
+        // Pipeline order, so that the keys of equal shaders are equal
+        let stage_suffixes = [
+            (vk::ShaderStageFlags::VERTEX, "vert"),
+            (vk::ShaderStageFlags::TESSELLATION_CONTROL, "tesc"),
+            (vk::ShaderStageFlags::TESSELLATION_EVALUATION, "tese"),
+            (vk::ShaderStageFlags::GEOMETRY, "geom"),
+            (vk::ShaderStageFlags::FRAGMENT, "frag"),
+        ];
+        let suffixes = stage_suffixes.map(|(_, suffix)| suffix);
+        let has_required_stages = |spirv: &[Option<Vec<u8>>]| {
+            spirv.first().is_some_and(Option::is_some)
+                && spirv.last().is_some_and(Option::is_some)
+        };
+
+        let stage_spirv = load_stages(&variant, &suffixes)?;
+        #[cfg(feature = "hot-reload")]
+        let stage_spirv = if has_required_stages(&stage_spirv) {
+            stage_spirv
+        } else {
+            let shaderbuild_dir = unsafe {
+                SHADERBUILD_DIR
+                    .as_ref()
+                    .ok_or_eyre("Shader build directory not specified")?
+            };
+            compile_variant(shadername, keywords, shaderbuild_dir)?;
+            load_stages(&variant, &suffixes)?
         };
+        if !has_required_stages(&stage_spirv) {
+            return Err(eyre!(
+                "Shader {} has no vertex or fragment stage, \
+                 it was not compiled by the build script",
+                variant
+            ));
+        }
+        let stages = stage_suffixes
+            .iter()
+            .zip(stage_spirv)
+            .filter_map(|((stage, _), spv)| Some((*stage, spv?)))
+            .collect::<Vec<_>>();
 
-        let mut vert_filepath = PathBuf::from(shaderbuild_dir);
-        vert_filepath.push(format!("{}-vert.spv", shadername));
-        let mut frag_filepath = PathBuf::from(shaderbuild_dir);
-        frag_filepath.push(format!("{}-frag.spv", shadername));
-
-        let mut vert_spv = Vec::new();
-        let mut vert_file = File::open(&vert_filepath).with_context(|| {
-            format!("Failed to open file: {:#?}", vert_filepath)
-        })?;
-        vert_file.read_to_end(&mut vert_spv).with_context(|| {
-            format!("Failed to read file: {:#?}", vert_filepath)
-        })?;
-
-        let mut frag_spv = Vec::new();
-        let mut frag_file = File::open(&frag_filepath).with_context(|| {
-            format!("Failed to open file: {:#?}", frag_filepath)
-        })?;
-        frag_file.read_to_end(&mut frag_spv).with_context(|| {
-            format!("Failed to read file: {:#?}", frag_filepath)
-        })?;
-
-        let vert_shader_mod = create_shader_module(device, &vert_spv)?;
-        let frag_shader_mod = create_shader_module(device, &frag_spv)?;
+        let reflection = stages
+            .iter()
+            .try_fold(None, |merged: Option<ShaderReflection>, (_, spv)| {
+                let reflection = ShaderReflection::from_spirv(spv)?;
+                match merged {
+                    Some(merged) => merged.merge(reflection).map(Some),
+                    None => Ok(Some(reflection)),
+                }
+            })
+            .with_context(|| {
+                format!("Failed to reflect shader: {}", shadername)
+            })?
+            .unwrap();
+
+        let stage_keys = stages
+            .iter()
+            .map(|(stage, spv)| ShaderKey::new(*stage, &variant, spv))
+            .collect();
+
+        let mut modules = Vec::with_capacity(stages.len());
+        for (stage, spv) in &stages {
+            match create_shader_module(device, spv) {
+                Ok(module) => modules.push((*stage, module)),
+                Err(err) => {
+                    for (_, module) in modules {
+                        unsafe { device.destroy_shader_module(module, None) };
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let module = |stage| {
+            modules
+                .iter()
+                .find(|(s, _)| *s == stage)
+                .map(|(_, module)| *module)
+        };
 
         Ok(Self {
-            vert_shader_mod,
-            frag_shader_mod,
+            name: shadername.into(),
+            vert_shader_mod: module(vk::ShaderStageFlags::VERTEX).unwrap(),
+            tesc_shader_mod: module(vk::ShaderStageFlags::TESSELLATION_CONTROL),
+            tese_shader_mod: module(
+                vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+            ),
+            geom_shader_mod: module(vk::ShaderStageFlags::GEOMETRY),
+            frag_shader_mod: module(vk::ShaderStageFlags::FRAGMENT).unwrap(),
+            reflection,
+            stage_keys,
         })
     }
 
+    /// Module of every stage in pipeline order
+    pub fn stages(&self) -> Vec<(vk::ShaderStageFlags, vk::ShaderModule)> {
+        let optional = [
+            (
+                vk::ShaderStageFlags::TESSELLATION_CONTROL,
+                self.tesc_shader_mod,
+            ),
+            (
+                vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+                self.tese_shader_mod,
+            ),
+            (vk::ShaderStageFlags::GEOMETRY, self.geom_shader_mod),
+        ]
+        .into_iter()
+        .filter_map(|(stage, module)| Some((stage, module?)));
+
+        std::iter::once((vk::ShaderStageFlags::VERTEX, self.vert_shader_mod))
+            .chain(optional)
+            .chain(std::iter::once((
+                vk::ShaderStageFlags::FRAGMENT,
+                self.frag_shader_mod,
+            )))
+            .collect()
+    }
+
+    /// Pipelines with tessellation stages draw patches
+    pub fn has_tessellation(&self) -> bool {
+        self.tesc_shader_mod.is_some()
+    }
+
     pub fn cleanup(self, device: &ash::Device) {
         log::info!("Cleaning up shader ...");
-        unsafe {
-            device.destroy_shader_module(self.vert_shader_mod, None);
-            device.destroy_shader_module(self.frag_shader_mod, None);
+        for (_, module) in self.stages() {
+            unsafe {
+                device.destroy_shader_module(module, None);
+            }
         }
     }
 }
 
 pub struct ComputeShader {
+    pub name: String,
     pub shader_mod: vk::ShaderModule,
+    pub reflection: ShaderReflection,
+    pub key: ShaderKey,
 }
 
 impl ComputeShader {
     pub fn new(shadername: &str, device: &ash::Device) -> Result<Self> {
-        let shaderbuild_dir = unsafe {
-            SHADERBUILD_DIR
-                .as_ref()
-                .ok_or_eyre("Shader build directory not specified")?
-        };
-
-        let mut filepath = PathBuf::from(shaderbuild_dir);
-        filepath.push(format!("{}-comp.spv", shadername));
-        let mut file = File::open(&filepath)
-            .with_context(|| format!("Failed to open file: {:#?}", filepath))?;
-        let mut spv = Vec::new();
-        file.read_to_end(&mut spv)
-            .with_context(|| format!("Failed to read file: {:#?}", filepath))?;
+        let spv = load_stages(shadername, &["comp"])?
+            .pop()
+            .flatten()
+            .ok_or_else(|| eyre!("Compute shader not found: {}", shadername))?;
+        let reflection =
+            ShaderReflection::from_spirv(&spv).with_context(|| {
+                format!("Failed to reflect shader: {}", shadername)
+            })?;
+        let key =
+            ShaderKey::new(vk::ShaderStageFlags::COMPUTE, shadername, &spv);
         let shader_mod = create_shader_module(device, &spv)?;
 
-        Ok(Self { shader_mod })
+        Ok(Self {
+            name: shadername.into(),
+            shader_mod,
+            reflection,
+            key,
+        })
     }
 
     pub fn cleanup(self, device: &ash::Device) {
@@ -89,6 +213,93 @@ impl ComputeShader {
     }
 }
 
+/// Compile a combined shader variant on demand
+/// and write it to the shader build directory
+#[cfg(feature = "hot-reload")]
+fn compile_variant(
+    shadername: &str,
+    keywords: &[String],
+    shaderbuild_dir: &str,
+) -> Result<()> {
+    let shaders_dir = unsafe {
+        SHADERS_DIR
+            .as_ref()
+            .ok_or_eyre("Shader directory not specified")?
+    };
+
+    let filepath = Path::new(shaders_dir)
+        .join(format!("{}.{}", shadername, COMBINED_SHADER_EXT));
+    let shader = ShaderCompiler::new()?
+        .include_dir(shaders_dir)
+        .compile_variant(&filepath, keywords)?
+        .ok_or_eyre("Not a combined shader")?;
+    let outputs = shader.write(Path::new(shaderbuild_dir))?;
+    // The build script removes the variant once the shader changes
+    shader_manifest::record_outputs(
+        Path::new(shaderbuild_dir),
+        &format!("{}.{}", shadername, COMBINED_SHADER_EXT),
+        None,
+        &outputs,
+    )?;
+    log::info!(
+        "Compiled shader {} with keywords {:?}",
+        shadername,
+        keywords
+    );
+
+    Ok(())
+}
+
+/// Read the `<name>-<suffix>.spv` files of a shader from the shader build
+/// directory if it has the first one, otherwise from the shaders embedded
+/// in the binary. All stages come from the same place, so a shader
+/// recompiled on disk is never mixed with stages of an older build.
+/// None for the stages that weren't found.
+fn load_stages(name: &str, suffixes: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+    let filenames = suffixes
+        .iter()
+        .map(|suffix| format!("{}-{}.spv", name, suffix))
+        .collect::<Vec<_>>();
+
+    let shaderbuild_dir = unsafe { SHADERBUILD_DIR.as_ref() }
+        .map(Path::new)
+        .filter(|dir| {
+            filenames
+                .first()
+                .is_some_and(|filename| dir.join(filename).is_file())
+        });
+    if let Some(shaderbuild_dir) = shaderbuild_dir {
+        return filenames
+            .iter()
+            .map(|filename| {
+                let filepath = shaderbuild_dir.join(filename);
+                if filepath.is_file() {
+                    read_spirv(&filepath).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect();
+    }
+
+    #[cfg(feature = "embed-shaders")]
+    return Ok(filenames
+        .iter()
+        .map(|filename| embedded_shaders::spirv(filename).map(<[u8]>::to_vec))
+        .collect());
+    #[cfg(not(feature = "embed-shaders"))]
+    Ok(vec![None; filenames.len()])
+}
+
+fn read_spirv(filepath: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(filepath)
+        .with_context(|| format!("Failed to open file: {:#?}", filepath))?;
+    let mut spv = Vec::new();
+    file.read_to_end(&mut spv)
+        .with_context(|| format!("Failed to read file: {:#?}", filepath))?;
+    Ok(spv)
+}
+
 fn create_shader_module(
     device: &ash::Device,
     code: &[u8],
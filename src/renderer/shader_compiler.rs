@@ -0,0 +1,734 @@
+//! GLSL to SPIR-V compilation shared by build.rs and the renderer.
+//! build.rs includes this file and shader_variant.rs with `#[path]`,
+//! so it must only depend on them, shaderc and color_eyre.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use color_eyre::eyre::{eyre, Context, OptionExt, Result};
+use shaderc::{CompilationArtifact, IncludeType, ResolvedInclude};
+
+use super::shader_variant::variant_name;
+
+pub const COMBINED_SHADER_EXT: &str = "combined";
+pub const COMP_SHADER_EXT: &str = "comp";
+pub const INCLUDE_SHADER_EXT: &str = "glsl";
+
+/// Declares the permutation keywords of a shader file,
+/// e.g. `#keywords TEXTURED ALPHA_TEST`.
+/// Every combination of keywords is a variant of the shader,
+/// compiled with the enabled keywords defined as macros.
+const KEYWORDS_DIRECTIVE: &str = "#keywords";
+/// Every keyword doubles the number of variants
+const MAX_KEYWORDS: usize = 8;
+
+/// Stages of a combined shader file in pipeline order:
+/// the name after `#shader`, the shader kind and the .spv file suffix.
+/// Vertex and fragment stages are required, tessellation control and
+/// evaluation stages can only be used together.
+const COMBINED_SHADER_STAGES: [(&str, shaderc::ShaderKind, &str); 5] = [
+    ("vertex", shaderc::ShaderKind::Vertex, "vert"),
+    ("tess_control", shaderc::ShaderKind::TessControl, "tesc"),
+    ("tess_eval", shaderc::ShaderKind::TessEvaluation, "tese"),
+    ("geometry", shaderc::ShaderKind::Geometry, "geom"),
+    ("fragment", shaderc::ShaderKind::Fragment, "frag"),
+];
+
+/// SPIR-V of every stage of a single shader variant
+pub struct CompiledShader {
+    /// File stem of the shader file, e.g. "mesh" for mesh.combined
+    pub name: String,
+    /// Keywords enabled in this variant, sorted
+    pub keywords: Vec<String>,
+    /// SPIR-V keyed by the suffix of its .spv file, e.g. "vert"
+    pub stages: Vec<(&'static str, Vec<u8>)>,
+    /// Every file pulled in with `#include`, including nested includes
+    pub includes: BTreeSet<PathBuf>,
+}
+
+impl CompiledShader {
+    /// Write every stage to `<shaderbuild_dir>/<variant name>-<stage>.spv`.
+    /// Returns the paths of the written files.
+    /// Files of stages a combined shader no longer has are removed,
+    /// so that they aren't loaded along with the new stages.
+    pub fn write(&self, shaderbuild_dir: &Path) -> Result<Vec<PathBuf>> {
+        let variant = variant_name(&self.name, &self.keywords);
+        let is_combined = self.stages.iter().any(|(stage, _)| *stage == "vert");
+        if is_combined {
+            for (_, _, suffix) in COMBINED_SHADER_STAGES {
+                if self.stages.iter().any(|(stage, _)| *stage == suffix) {
+                    continue;
+                }
+                let filepath =
+                    shaderbuild_dir.join(format!("{}-{}.spv", variant, suffix));
+                if filepath.is_file() {
+                    fs::remove_file(&filepath).with_context(|| {
+                        format!("Failed to remove file: {:#?}", filepath)
+                    })?;
+                }
+            }
+        }
+
+        let mut filepaths = Vec::new();
+        for (stage, spirv) in &self.stages {
+            let filepath =
+                shaderbuild_dir.join(format!("{}-{}.spv", variant, stage));
+            let mut file = File::create(&filepath).with_context(|| {
+                format!("Failed to create file: {:#?}", filepath)
+            })?;
+            file.write_all(spirv)?;
+            filepaths.push(filepath);
+        }
+        Ok(filepaths)
+    }
+}
+
+/// GLSL of every stage of a shader file plus its declared keywords
+struct ShaderSource {
+    stages: Vec<(shaderc::ShaderKind, &'static str, String)>,
+    keywords: Vec<String>,
+}
+
+pub struct ShaderCompiler {
+    compiler: shaderc::Compiler,
+    options: shaderc::CompileOptions<'static>,
+    include_dirs: Vec<PathBuf>,
+}
+
+impl ShaderCompiler {
+    pub fn new() -> Result<Self> {
+        let compiler = shaderc::Compiler::new()
+            .ok_or_eyre("Failed to create shaderc compiler")?;
+        let options = shaderc::CompileOptions::new()
+            .ok_or_eyre("Failed to create shaderc options")?;
+        Ok(Self {
+            compiler,
+            options,
+            include_dirs: Vec::new(),
+        })
+    }
+
+    /// Search `dir` for `#include <...>` files,
+    /// and for `#include "..."` files not found next to the including file
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Every file a shader file pulls in with `#include`,
+    /// including nested includes.
+    /// Preprocessor conditionals aren't evaluated, so includes that can't be
+    /// found are skipped, e.g. one behind an `#ifdef` that is never defined.
+    /// Compiling reports them if they are actually used.
+    pub fn includes(&self, filepath: &Path) -> Result<BTreeSet<PathBuf>> {
+        let mut includes = BTreeSet::new();
+        let mut pending = vec![filepath.to_path_buf()];
+        while let Some(path) = pending.pop() {
+            let source = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read file: {:#?}", path))?;
+            for (requested, include_type) in
+                source.lines().filter_map(parse_include)
+            {
+                let Some(include) = resolve_include(
+                    requested,
+                    include_type,
+                    &path,
+                    &self.include_dirs,
+                ) else {
+                    continue;
+                };
+                if includes.insert(include.clone()) {
+                    pending.push(include);
+                }
+            }
+        }
+        Ok(includes)
+    }
+
+    /// Compile every variant of a .combined or .comp file.
+    /// Returns nothing for files that aren't compiled on their own,
+    /// such as .glsl include files.
+    pub fn compile_file(&self, filepath: &Path) -> Result<Vec<CompiledShader>> {
+        let Some(source) = parse_shader_file(filepath)? else {
+            return Ok(Vec::new());
+        };
+        if source.keywords.len() > MAX_KEYWORDS {
+            return Err(eyre!(
+                "{:#?} declares more than {} keywords",
+                filepath,
+                MAX_KEYWORDS
+            ));
+        }
+
+        (0..1u32 << source.keywords.len())
+            .map(|mask| {
+                let keywords = source
+                    .keywords
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, kw)| kw.as_str())
+                    .collect::<Vec<_>>();
+                self.compile_source(&source, filepath, &keywords)
+            })
+            .collect()
+    }
+
+    /// Compile a single variant of a .combined or .comp file.
+    /// Returns None for files that aren't compiled on their own.
+    pub fn compile_variant<S: AsRef<str>>(
+        &self,
+        filepath: &Path,
+        keywords: &[S],
+    ) -> Result<Option<CompiledShader>> {
+        let Some(source) = parse_shader_file(filepath)? else {
+            return Ok(None);
+        };
+        let keywords =
+            keywords.iter().map(|kw| kw.as_ref()).collect::<Vec<_>>();
+        if let Some(keyword) = keywords
+            .iter()
+            .find(|kw| !source.keywords.iter().any(|d| d == *kw))
+        {
+            return Err(eyre!(
+                "{:#?} does not declare keyword {}",
+                filepath,
+                keyword
+            ));
+        }
+
+        self.compile_source(&source, filepath, &keywords).map(Some)
+    }
+
+    fn compile_source(
+        &self,
+        source: &ShaderSource,
+        filepath: &Path,
+        keywords: &[&str],
+    ) -> Result<CompiledShader> {
+        let filestem = filepath
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_eyre("Invalid shader file name")?;
+        let filename = filepath
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_eyre("Invalid shader file name")?;
+
+        let mut options = self
+            .options
+            .clone()
+            .ok_or_eyre("Failed to create shaderc options")?;
+        for keyword in keywords {
+            options.add_macro_definition(keyword, Some("1"));
+        }
+        // CompileOptions::clone doesn't copy the include callback,
+        // so each compile sets its own to record the files it includes
+        let includes = Rc::new(RefCell::new(BTreeSet::new()));
+        options.set_include_callback({
+            let includes = includes.clone();
+            let include_dirs = self.include_dirs.clone();
+            move |requested, include_type, requesting, _depth| {
+                let path = resolve_include(
+                    requested,
+                    include_type,
+                    Path::new(requesting),
+                    &include_dirs,
+                )
+                .ok_or_else(|| {
+                    format!("Cannot find include file: {}", requested)
+                })?;
+                let content = fs::read_to_string(&path).map_err(|err| {
+                    format!("Failed to read include file {:?}: {}", path, err)
+                })?;
+                includes.borrow_mut().insert(path.clone());
+                Ok(ResolvedInclude {
+                    resolved_name: path.to_string_lossy().into_owned(),
+                    content,
+                })
+            }
+        });
+        // Nested includes are resolved relative to this path
+        let input_name = filepath.to_string_lossy();
+
+        let stages = source
+            .stages
+            .iter()
+            .map(|(kind, suffix, glsl)| {
+                let spirv = self
+                    .compile_shader(glsl, *kind, &options, &input_name)
+                    .with_context(|| {
+                        format!(
+                            "Failed to compile {} with keywords {:?}",
+                            filename, keywords
+                        )
+                    })?;
+                Ok((*suffix, spirv.as_binary_u8().to_vec()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut keywords =
+            keywords.iter().map(|kw| kw.to_string()).collect::<Vec<_>>();
+        keywords.sort_unstable();
+        keywords.dedup();
+
+        let includes = includes.take();
+
+        Ok(CompiledShader {
+            name: filestem.into(),
+            keywords,
+            stages,
+            includes,
+        })
+    }
+
+    /// Compilation errors are formatted like rustc diagnostics,
+    /// pointing at the line of the shader or include file
+    fn compile_shader(
+        &self,
+        glsl: &str,
+        kind: shaderc::ShaderKind,
+        options: &shaderc::CompileOptions,
+        input_name: &str,
+    ) -> Result<CompilationArtifact> {
+        self.compiler
+            .compile_into_spirv(glsl, kind, input_name, "main", Some(options))
+            .map_err(|err| match err {
+                shaderc::Error::CompilationError(_, message) => {
+                    eyre!("{}", format_diagnostics(&message))
+                }
+                err => err.into(),
+            })
+    }
+}
+
+/// Turns glslang messages such as
+/// `./shaders/grid.combined:12: error: 'foo' : undeclared identifier`
+/// into rustc style diagnostics that quote the offending line:
+///
+/// ```text
+/// error: 'foo' : undeclared identifier
+///   --> ./shaders/grid.combined:12
+///    |
+/// 12 |     vec4 color = foo;
+///    |
+/// ```
+fn format_diagnostics(message: &str) -> String {
+    let mut formatted = String::new();
+    for line in message.lines() {
+        let Some((location, severity, text)) =
+            ["error", "warning"].into_iter().find_map(|severity| {
+                let (location, text) =
+                    line.split_once(&format!(": {}: ", severity))?;
+                Some((location, severity, text))
+            })
+        else {
+            // e.g. "2 errors generated."
+            formatted.push_str(line);
+            formatted.push('\n');
+            continue;
+        };
+
+        formatted.push_str(&format!("{}: {}\n", severity, text.trim()));
+        let Some((file, line_number)) = location
+            .rsplit_once(':')
+            .and_then(|(file, n)| Some((file, n.parse::<usize>().ok()?)))
+        else {
+            formatted.push_str(&format!("  --> {}\n\n", location));
+            continue;
+        };
+
+        let gutter = " ".repeat(line_number.to_string().len());
+        formatted
+            .push_str(&format!("{}--> {}:{}\n", gutter, file, line_number));
+        let source_line = fs::read_to_string(file).ok().and_then(|source| {
+            Some(source.lines().nth(line_number.checked_sub(1)?)?.to_owned())
+        });
+        if let Some(source_line) = source_line {
+            formatted.push_str(&format!("{} |\n", gutter));
+            formatted.push_str(&format!("{} | {}\n", line_number, source_line));
+            formatted.push_str(&format!("{} |\n", gutter));
+        }
+        formatted.push('\n');
+    }
+    formatted
+}
+
+/// The requested file and the kind of an `#include` line
+fn parse_include(line: &str) -> Option<(&str, IncludeType)> {
+    let requested = line.trim_start().strip_prefix("#include")?.trim();
+    if let Some(name) = requested
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+    {
+        Some((name, IncludeType::Relative))
+    } else {
+        requested
+            .strip_prefix('<')
+            .and_then(|r| r.strip_suffix('>'))
+            .map(|name| (name, IncludeType::Standard))
+    }
+}
+
+/// `#include "..."` is looked up next to the including file first,
+/// then in the include directories like `#include <...>`
+fn resolve_include(
+    requested: &str,
+    include_type: IncludeType,
+    requesting: &Path,
+    include_dirs: &[PathBuf],
+) -> Option<PathBuf> {
+    let local_dir = match include_type {
+        IncludeType::Relative => requesting.parent(),
+        IncludeType::Standard => None,
+    };
+    local_dir
+        .into_iter()
+        .chain(include_dirs.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(requested))
+        .find(|path| path.is_file())
+}
+
+fn parse_shader_file(filepath: &Path) -> Result<Option<ShaderSource>> {
+    let Some(ext) = filepath.extension() else {
+        return Ok(None);
+    };
+
+    if ext == COMBINED_SHADER_EXT {
+        parse_combined_shaderfile(filepath).map(Some)
+    } else if ext == COMP_SHADER_EXT {
+        let source = fs::read_to_string(filepath)
+            .with_context(|| format!("Failed to read file: {:#?}", filepath))?;
+        let mut comp_glsl = String::new();
+        let mut keywords = Vec::new();
+        for line in source.lines() {
+            // Keep the line so that line numbers in errors stay correct
+            if !parse_keywords(line, &mut keywords) {
+                comp_glsl.push_str(line);
+            }
+            comp_glsl.push('\n');
+        }
+        Ok(Some(ShaderSource {
+            stages: vec![(shaderc::ShaderKind::Compute, "comp", comp_glsl)],
+            keywords,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Adds the keywords declared by a `#keywords` line.
+/// Returns false if the line isn't a `#keywords` line.
+fn parse_keywords(line: &str, keywords: &mut Vec<String>) -> bool {
+    let Some(declared) = line.trim_start().strip_prefix(KEYWORDS_DIRECTIVE)
+    else {
+        return false;
+    };
+    for keyword in declared.split_whitespace() {
+        if !keywords.iter().any(|kw| kw == keyword) {
+            keywords.push(keyword.into());
+        }
+    }
+    true
+}
+
+/// Stages are returned in pipeline order
+fn parse_combined_shaderfile(filepath: &Path) -> Result<ShaderSource> {
+    let file = File::open(filepath)?;
+    let reader = BufReader::new(file);
+    let lines = reader.lines();
+
+    // GLSL of each stage in COMBINED_SHADER_STAGES, None if not in the file
+    let mut stage_glsl: [Option<String>; COMBINED_SHADER_STAGES.len()] =
+        Default::default();
+    let mut keywords = Vec::new();
+    let mut current_stage = None;
+
+    for (line_index, line) in lines.enumerate() {
+        let mut line = line?;
+
+        // Keywords apply to all stages wherever they are declared.
+        // The line is kept so that line numbers in errors stay correct.
+        if parse_keywords(&line, &mut keywords) {
+            line.clear();
+        }
+
+        if line.trim_start().starts_with("#shader") {
+            let Some(stype) = line.split_whitespace().nth(1) else {
+                return Err(eyre!("Invalid #shader type specifier: {}", line));
+            };
+            let index = COMBINED_SHADER_STAGES
+                .iter()
+                .position(|(name, _, _)| *name == stype)
+                .ok_or_else(|| eyre!("Unknown #shader type: {}", stype))?;
+            if stage_glsl[index].is_some() {
+                return Err(eyre!("Duplicate #shader type: {}", stype));
+            }
+            // Pad the stage with the lines before it,
+            // so its line numbers are the same as in the combined file
+            stage_glsl[index] = Some("\n".repeat(line_index + 1));
+            current_stage = Some(index);
+            continue;
+        }
+
+        // #include directives are resolved by the include callback
+        if let Some(index) = current_stage {
+            let str_buf = stage_glsl[index].as_mut().unwrap();
+            str_buf.push_str(&line);
+            str_buf.push('\n');
+        }
+    }
+
+    let has_stage = |name: &str| {
+        COMBINED_SHADER_STAGES
+            .iter()
+            .zip(&stage_glsl)
+            .any(|((stage, _, _), glsl)| *stage == name && glsl.is_some())
+    };
+    if !has_stage("vertex") {
+        return Err(eyre!("No vertex #shader type specifier found"));
+    }
+    if !has_stage("fragment") {
+        return Err(eyre!("No fragment #shader type specifier found"));
+    }
+    if has_stage("tess_control") != has_stage("tess_eval") {
+        return Err(eyre!(
+            "tess_control and tess_eval #shader types must be used together"
+        ));
+    }
+
+    let stages = COMBINED_SHADER_STAGES
+        .iter()
+        .zip(stage_glsl)
+        .filter_map(|((_, kind, suffix), glsl)| Some((*kind, *suffix, glsl?)))
+        .collect();
+    Ok(ShaderSource { stages, keywords })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a file into a directory of its own under the temp directory
+    fn write_temp_file(test: &str, filename: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "vulkaning-{}-{}",
+            test,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let filepath = dir.join(filename);
+        fs::write(&filepath, contents).unwrap();
+        filepath
+    }
+
+    fn parse_combined(test: &str, contents: &str) -> Result<ShaderSource> {
+        let filepath = write_temp_file(test, "test.combined", contents);
+        parse_combined_shaderfile(&filepath)
+    }
+
+    #[test]
+    fn test_parse_combined_shaderfile() {
+        let contents = "#keywords ALPHA_TEST\n\
+                        #shader fragment\n\
+                        void main() { frag(); }\n\
+                        #keywords TEXTURED ALPHA_TEST\n\
+                        #shader vertex\n\
+                        void main() { vert(); }\n";
+        let source = parse_combined("parse-combined", contents).unwrap();
+
+        assert_eq!(source.keywords, ["ALPHA_TEST", "TEXTURED"]);
+        let suffixes = source
+            .stages
+            .iter()
+            .map(|(_, suffix, _)| *suffix)
+            .collect::<Vec<_>>();
+        assert_eq!(suffixes, ["vert", "frag"], "stages in pipeline order");
+
+        // Each stage keeps the line numbers of the combined file
+        for (_, suffix, glsl) in &source.stages {
+            let (line_number, expected) = match *suffix {
+                "vert" => (6, "void main() { vert(); }"),
+                _ => (3, "void main() { frag(); }"),
+            };
+            assert_eq!(glsl.lines().nth(line_number - 1), Some(expected));
+        }
+        // The #keywords line inside the fragment stage is blanked
+        let frag = &source.stages[1].2;
+        assert_eq!(frag.lines().nth(3), Some(""));
+    }
+
+    #[test]
+    fn test_format_diagnostics() {
+        let contents = "#shader vertex\n\
+                        void main() {}\n\
+                        #shader fragment\n\
+                        void main() {\n\
+                        \x20   vec4 color = foo;\n\
+                        }\n";
+        let filepath =
+            write_temp_file("format-diagnostics", "test.combined", contents);
+        let source = parse_combined_shaderfile(&filepath).unwrap();
+        let (_, _, frag) = &source.stages[1];
+        // shaderc reports lines of the stage source,
+        // which are the lines of the combined file
+        assert_eq!(frag.lines().nth(4), Some("    vec4 color = foo;"));
+
+        let message = format!(
+            "{}:5: error: 'foo' : undeclared identifier\n\
+             1 error generated.\n",
+            filepath.display()
+        );
+        assert_eq!(
+            format_diagnostics(&message),
+            format!(
+                "error: 'foo' : undeclared identifier\n \
+                 --> {}:5\n  |\n\
+                 5 |     vec4 color = foo;\n  |\n\n\
+                 1 error generated.\n",
+                filepath.display()
+            )
+        );
+
+        // Locations without a line number are kept as they are
+        assert_eq!(
+            format_diagnostics("test.combined: warning: version missing"),
+            "warning: version missing\n  --> test.combined\n\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_include() {
+        assert_eq!(
+            parse_include("#include \"common.glsl\""),
+            Some(("common.glsl", IncludeType::Relative))
+        );
+        assert_eq!(
+            parse_include("  #include <lib/noise.glsl>  "),
+            Some(("lib/noise.glsl", IncludeType::Standard))
+        );
+        assert_eq!(parse_include("#include common.glsl"), None);
+        assert_eq!(parse_include("#include \"common.glsl>"), None);
+        assert_eq!(parse_include("// #include \"common.glsl\""), None);
+    }
+
+    #[test]
+    fn test_resolve_include() {
+        let shader = write_temp_file("resolve-include", "test.combined", "");
+        let dir = shader.parent().unwrap().to_owned();
+        let local = write_temp_file("resolve-include", "common.glsl", "");
+        let include_dir = dir.join("include");
+        fs::create_dir_all(&include_dir).unwrap();
+        let global = include_dir.join("common.glsl");
+        fs::write(&global, "").unwrap();
+        fs::write(include_dir.join("noise.glsl"), "").unwrap();
+        let include_dirs = [include_dir.clone()];
+        let resolve = |requested, include_type| {
+            resolve_include(requested, include_type, &shader, &include_dirs)
+        };
+
+        // Quoted includes prefer the including file's directory
+        assert_eq!(resolve("common.glsl", IncludeType::Relative), Some(local));
+        assert_eq!(
+            resolve("noise.glsl", IncludeType::Relative),
+            Some(include_dir.join("noise.glsl"))
+        );
+        // Angle includes only search the include directories
+        assert_eq!(resolve("common.glsl", IncludeType::Standard), Some(global));
+        assert_eq!(resolve("missing.glsl", IncludeType::Relative), None);
+        assert_eq!(resolve("missing.glsl", IncludeType::Standard), None);
+    }
+
+    #[test]
+    fn test_includes() {
+        let test = "includes";
+        let shader = write_temp_file(
+            test,
+            "test.combined",
+            "#include \"a.glsl\"\n#include <b.glsl>\n",
+        );
+        let include_dir = shader.parent().unwrap().join("include");
+        fs::create_dir_all(&include_dir).unwrap();
+        let a = write_temp_file(test, "a.glsl", "#include \"c.glsl\"\n");
+        let c = write_temp_file(test, "c.glsl", "");
+        // Nested quoted includes are looked up next to the file including them
+        let b = include_dir.join("b.glsl");
+        fs::write(&b, "#include \"d.glsl\"\n").unwrap();
+        let d = include_dir.join("d.glsl");
+        fs::write(&d, "#include <b.glsl>\n").unwrap();
+
+        let compiler = ShaderCompiler::new().unwrap().include_dir(&include_dir);
+        assert_eq!(
+            compiler.includes(&shader).unwrap(),
+            BTreeSet::from([a.clone(), b.clone(), c.clone(), d.clone()])
+        );
+
+        // The quoted include isn't next to b.glsl or in the include directory,
+        // the scan can't tell whether the #ifdef disables it
+        fs::write(
+            &b,
+            "#ifdef UNUSED\n#include \"missing.glsl\"\n#endif\n#include \"d.glsl\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            compiler.includes(&shader).unwrap(),
+            BTreeSet::from([a, b, c, d])
+        );
+    }
+
+    #[test]
+    fn test_write_removes_stale_stages() {
+        let dir = write_temp_file("write-stages", "unrelated-geom.spv", "")
+            .parent()
+            .unwrap()
+            .to_owned();
+        let shader = |stages: &[&'static str]| CompiledShader {
+            name: "test".into(),
+            keywords: Vec::new(),
+            stages: stages.iter().map(|stage| (*stage, vec![0; 4])).collect(),
+            includes: BTreeSet::new(),
+        };
+
+        shader(&["vert", "geom", "frag"]).write(&dir).unwrap();
+        assert!(dir.join("test-geom.spv").is_file());
+
+        let written = shader(&["vert", "frag"]).write(&dir).unwrap();
+        assert_eq!(
+            written,
+            vec![dir.join("test-vert.spv"), dir.join("test-frag.spv")]
+        );
+        assert!(!dir.join("test-geom.spv").exists());
+        assert!(dir.join("unrelated-geom.spv").is_file());
+    }
+
+    #[test]
+    fn test_parse_combined_shaderfile_errors() {
+        let cases = [
+            ("#shader fragment\nvoid main() {}\n", "no vertex stage"),
+            ("#shader vertex\nvoid main() {}\n", "no fragment stage"),
+            (
+                "#shader vertex\n#shader fragment\n#shader vertex\n",
+                "duplicate stage",
+            ),
+            (
+                "#shader vertex\n#shader fragment\n#shader mesh\n",
+                "unknown",
+            ),
+            ("#shader\n", "missing stage name"),
+            (
+                "#shader vertex\n#shader tess_control\n#shader fragment\n",
+                "tess_control without tess_eval",
+            ),
+        ];
+        for (i, (contents, case)) in cases.iter().enumerate() {
+            let test = format!("parse-combined-error-{}", i);
+            assert!(parse_combined(&test, contents).is_err(), "{}", case);
+        }
+    }
+}
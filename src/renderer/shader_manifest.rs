@@ -0,0 +1,299 @@
+//! Manifest of the .spv files in the shader build directory, shared by
+//! build.rs, which only recompiles shaders whose hash changed,
+//! and hot reloading, which records the shaders it compiles so that
+//! the build script removes them once they're stale.
+//! Like shader_compiler.rs it must only depend on shader_variant.rs
+//! and color_eyre.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Context, Result};
+
+use super::shader_variant::Fnv1a;
+
+/// Records what each shader file was compiled from and into,
+/// so unchanged shaders aren't compiled again
+pub const MANIFEST_FILE: &str = "shaders.manifest";
+
+/// The compile options are set in the compiler's source,
+/// so changing it recompiles every shader
+const COMPILER_SOURCE: &str = include_str!("shader_compiler.rs");
+
+/// Manifest line of a shader file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// Hash of the source, its transitive includes and the compile options
+    pub hash: u64,
+    /// Names of the .spv files in the shader build directory
+    pub outputs: Vec<String>,
+}
+
+/// Shader file names mapped to their entries
+pub type Manifest = BTreeMap<String, ManifestEntry>;
+
+pub fn source_hash(
+    filepath: &Path,
+    includes: &BTreeSet<PathBuf>,
+) -> Result<u64> {
+    let mut hasher = Fnv1a::default();
+    hasher.write(COMPILER_SOURCE.as_bytes());
+    // Includes are sorted, so the hash doesn't depend on include order
+    for path in std::iter::once(filepath).chain(includes.iter().map(|p| &**p)) {
+        let source = fs::read(path)
+            .with_context(|| format!("Failed to read file: {:#?}", path))?;
+        hasher.write(path.to_string_lossy().as_bytes());
+        hasher.write_u8(0);
+        hasher.write(&source);
+        hasher.write_u8(0);
+    }
+    Ok(hasher.finish())
+}
+
+/// Remove outputs of a previous build that weren't written again
+pub fn remove_outputs(dir: &Path, old_outputs: &[String], outputs: &[String]) {
+    for output in old_outputs {
+        if !outputs.contains(output) {
+            // Already gone if it was removed by hand
+            let _ = fs::remove_file(dir.join(output));
+        }
+    }
+}
+
+/// Each line is a shader file name, its hash and its outputs,
+/// separated by tabs so that file names may contain spaces.
+/// A missing or unreadable manifest rebuilds every shader.
+pub fn read_manifest(path: &Path) -> Manifest {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Manifest::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let filename = fields.next()?.to_string();
+            let hash = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let outputs = fields
+                .filter(|output| !output.is_empty())
+                .map(String::from)
+                .collect();
+            Some((filename, ManifestEntry { hash, outputs }))
+        })
+        .collect()
+}
+
+/// Files with tabs or line breaks in their names can't be written,
+/// they are compiled again on the next build
+pub fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let is_valid = |name: &str| !name.contains(['\t', '\n', '\r']);
+    let contents = manifest
+        .iter()
+        .filter(|(filename, entry)| {
+            is_valid(filename) && entry.outputs.iter().all(|o| is_valid(o))
+        })
+        .map(|(filename, entry)| {
+            let mut line = format!("{}\t{:016x}", filename, entry.hash);
+            for output in &entry.outputs {
+                line.push('\t');
+                line.push_str(output);
+            }
+            line.push('\n');
+            line
+        })
+        .collect::<String>();
+    fs::write(path, contents)
+        .with_context(|| format!("Failed to write file: {:#?}", path))
+}
+
+/// Add the outputs of a shader file compiled at runtime to the manifest
+/// of `shaderbuild_dir`. `hash` is given if every variant of the file
+/// was compiled, the outputs of its old variants are removed then.
+/// Otherwise the old hash is kept, so the next build compiles the file
+/// again if it changed and removes the outputs that are stale.
+pub fn record_outputs(
+    shaderbuild_dir: &Path,
+    filename: &str,
+    hash: Option<u64>,
+    outputs: &[PathBuf],
+) -> Result<()> {
+    let outputs = outputs
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str())
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    let manifest_path = shaderbuild_dir.join(MANIFEST_FILE);
+    let mut manifest = read_manifest(&manifest_path);
+    // A hash of 0 makes the next build compile a new file
+    let entry =
+        manifest
+            .entry(filename.to_string())
+            .or_insert_with(|| ManifestEntry {
+                hash: 0,
+                outputs: Vec::new(),
+            });
+    if let Some(hash) = hash {
+        remove_outputs(shaderbuild_dir, &entry.outputs, &outputs);
+        *entry = ManifestEntry { hash, outputs };
+    } else {
+        for output in outputs {
+            if !entry.outputs.contains(&output) {
+                entry.outputs.push(output);
+            }
+        }
+    }
+    write_manifest(&manifest_path, &manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own under the temp directory
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "vulkaning-manifest-{}-{}",
+            test,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(hash: u64, outputs: &[&str]) -> ManifestEntry {
+        ManifestEntry {
+            hash,
+            outputs: outputs.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let path = temp_dir("roundtrip").join(MANIFEST_FILE);
+        let manifest = Manifest::from([
+            (
+                "my shader.combined".to_string(),
+                entry(0x1234, &["my shader-vert.spv", "my shader-frag.spv"]),
+            ),
+            ("no outputs.comp".to_string(), entry(u64::MAX, &[])),
+        ]);
+
+        write_manifest(&path, &manifest).unwrap();
+        assert_eq!(read_manifest(&path), manifest);
+    }
+
+    #[test]
+    fn test_read_manifest_skips_invalid_lines() {
+        let path = temp_dir("invalid").join(MANIFEST_FILE);
+        assert!(read_manifest(&path).is_empty(), "missing manifest");
+
+        fs::write(
+            &path,
+            "a.comp\t00000000000000ff\ta.spv\n\
+             b.comp\tnot a hash\tb.spv\n\
+             c.comp\n",
+        )
+        .unwrap();
+        let manifest = read_manifest(&path);
+        assert_eq!(
+            manifest,
+            Manifest::from([("a.comp".into(), entry(0xff, &["a.spv"]))])
+        );
+    }
+
+    #[test]
+    fn test_write_manifest_skips_unrepresentable_names() {
+        let path = temp_dir("unrepresentable").join(MANIFEST_FILE);
+        let manifest = Manifest::from([
+            ("a\tb.comp".to_string(), entry(1, &[])),
+            ("c.comp".to_string(), entry(2, &["c\n.spv"])),
+            ("d.comp".to_string(), entry(3, &["d.spv"])),
+        ]);
+
+        write_manifest(&path, &manifest).unwrap();
+        assert_eq!(
+            read_manifest(&path),
+            Manifest::from([("d.comp".into(), entry(3, &["d.spv"]))])
+        );
+    }
+
+    #[test]
+    fn test_source_hash() {
+        let dir = temp_dir("hash");
+        let shader = dir.join("test.combined");
+        let include_a = dir.join("a.glsl");
+        let include_b = dir.join("b.glsl");
+        fs::write(&shader, "#include \"a.glsl\"").unwrap();
+        fs::write(&include_a, "a").unwrap();
+        fs::write(&include_b, "b").unwrap();
+
+        let includes = BTreeSet::from([include_a.clone()]);
+        let hash = source_hash(&shader, &includes).unwrap();
+        assert_eq!(source_hash(&shader, &includes).unwrap(), hash);
+
+        // Changing an include changes the hash
+        fs::write(&include_a, "a changed").unwrap();
+        let changed = source_hash(&shader, &includes).unwrap();
+        assert_ne!(changed, hash);
+
+        // Includes are hashed by name and in sorted order
+        let both = BTreeSet::from([include_b.clone(), include_a.clone()]);
+        assert_ne!(source_hash(&shader, &both).unwrap(), changed);
+        assert_eq!(
+            source_hash(&shader, &both).unwrap(),
+            source_hash(&shader, &BTreeSet::from([include_a, include_b]))
+                .unwrap()
+        );
+
+        assert!(source_hash(&dir.join("missing.combined"), &includes).is_err());
+    }
+
+    #[test]
+    fn test_record_outputs() {
+        let dir = temp_dir("record");
+        let path = dir.join(MANIFEST_FILE);
+        for output in ["a-vert.spv", "a-frag.spv", "a_1-vert.spv"] {
+            fs::write(dir.join(output), "").unwrap();
+        }
+        write_manifest(
+            &path,
+            &Manifest::from([(
+                "a.combined".into(),
+                entry(1, &["a-vert.spv", "a-frag.spv"]),
+            )]),
+        )
+        .unwrap();
+
+        // A variant compiled on demand keeps the hash of the build
+        record_outputs(&dir, "a.combined", None, &[dir.join("a_1-vert.spv")])
+            .unwrap();
+        assert_eq!(
+            read_manifest(&path)["a.combined"],
+            entry(1, &["a-vert.spv", "a-frag.spv", "a_1-vert.spv"])
+        );
+
+        // Recompiling the whole file replaces the old outputs
+        record_outputs(&dir, "a.combined", Some(2), &[dir.join("a-vert.spv")])
+            .unwrap();
+        assert_eq!(
+            read_manifest(&path)["a.combined"],
+            entry(2, &["a-vert.spv"])
+        );
+        assert!(dir.join("a-vert.spv").is_file());
+        assert!(!dir.join("a-frag.spv").exists());
+        assert!(!dir.join("a_1-vert.spv").exists());
+
+        // Files the build didn't know about are compiled by the next build
+        record_outputs(&dir, "b.combined", None, &[dir.join("b-vert.spv")])
+            .unwrap();
+        assert_eq!(
+            read_manifest(&path)["b.combined"],
+            entry(0, &["b-vert.spv"])
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Naming of compiled shader variants, shared by build.rs and the renderer
+//! so that both agree on file names. Unlike shader_compiler.rs this doesn't
+//! need shaderc, so it is available without the hot-reload feature.
+
+use std::hash::Hasher;
+
+/// Name of the .spv files of a shader variant, without the stage suffix.
+/// The variant without keywords keeps the plain shader name,
+/// other variants append the hash of their keywords.
+pub fn variant_name<S: AsRef<str>>(shader: &str, keywords: &[S]) -> String {
+    if keywords.is_empty() {
+        shader.into()
+    } else {
+        format!("{}-{:016x}", shader, keyword_hash(keywords))
+    }
+}
+
+/// Order independent hash of a set of keywords.
+/// This is FNV-1a so that build.rs and the renderer always agree.
+pub fn keyword_hash<S: AsRef<str>>(keywords: &[S]) -> u64 {
+    let mut keywords =
+        keywords.iter().map(|kw| kw.as_ref()).collect::<Vec<_>>();
+    keywords.sort_unstable();
+    keywords.dedup();
+
+    let mut hasher = Fnv1a::default();
+    for keyword in keywords {
+        hasher.write(keyword.as_bytes());
+        hasher.write_u8(0);
+    }
+    hasher.finish()
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is stable across Rust versions
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyword_hash_ignores_order_and_duplicates() {
+        assert_eq!(
+            keyword_hash(&["ALPHA_TEST", "TEXTURED"]),
+            keyword_hash(&["TEXTURED", "ALPHA_TEST"])
+        );
+        assert_eq!(
+            keyword_hash(&["TEXTURED", "TEXTURED"]),
+            keyword_hash(&["TEXTURED"])
+        );
+        assert_ne!(
+            keyword_hash(&["ALPHA_TEST"]),
+            keyword_hash(&["ALPHA_TEST", "TEXTURED"])
+        );
+        // Keywords are separated, so they can't run into each other
+        assert_ne!(keyword_hash(&["AB", "C"]), keyword_hash(&["A", "BC"]));
+    }
+
+    #[test]
+    fn test_keyword_hash_is_stable() {
+        // Compiled shaders are named by this hash, changing it
+        // would orphan every variant in existing shader build directories
+        assert_eq!(keyword_hash(&["ALPHA_TEST"]), 0x1a497610d325bbc8);
+    }
+
+    #[test]
+    fn test_variant_name() {
+        assert_eq!(variant_name::<&str>("mesh", &[]), "mesh");
+        assert_eq!(
+            variant_name("mesh", &["ALPHA_TEST"]),
+            "mesh-1a497610d325bbc8"
+        );
+        assert_eq!(
+            variant_name("mesh", &["ALPHA_TEST".to_string()]),
+            variant_name("mesh", &["ALPHA_TEST"])
+        );
+    }
+}
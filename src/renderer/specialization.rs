@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use ash::vk;
+
+/// Value of a specialization constant, laid out as the shader expects it
+pub trait SpecConstantValue {
+    fn to_spec_bytes(self) -> Vec<u8>;
+}
+
+macro_rules! impl_spec_constant_value {
+    ($($ty:ty),*) => {
+        $(impl SpecConstantValue for $ty {
+            fn to_spec_bytes(self) -> Vec<u8> {
+                self.to_ne_bytes().to_vec()
+            }
+        })*
+    };
+}
+
+impl_spec_constant_value!(u32, i32, f32, u64, i64, f64);
+
+/// GLSL bools are 32-bit
+impl SpecConstantValue for bool {
+    fn to_spec_bytes(self) -> Vec<u8> {
+        (if self { vk::TRUE } else { vk::FALSE }).to_spec_bytes()
+    }
+}
+
+/// Specialization constants of one shader stage, keyed by `constant_id`.
+/// For example `layout (local_size_x_id = 0, local_size_y_id = 1) in;`
+/// takes its workgroup size from constants 0 and 1.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct SpecializationConstants {
+    values: BTreeMap<u32, Vec<u8>>,
+}
+
+impl SpecializationConstants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set<T: SpecConstantValue>(mut self, id: u32, value: T) -> Self {
+        self.values.insert(id, value.to_spec_bytes());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Constant IDs with the size of their values in bytes
+    pub fn sizes(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.values
+            .iter()
+            .map(|(id, value)| (*id, value.len() as u32))
+    }
+
+    /// Values packed one after another, with an entry for each
+    pub fn pack(&self) -> SpecializationData {
+        let mut entries = Vec::with_capacity(self.values.len());
+        let mut data = Vec::new();
+        for (id, value) in &self.values {
+            entries.push(vk::SpecializationMapEntry {
+                constant_id: *id,
+                offset: data.len() as u32,
+                size: value.len(),
+            });
+            data.extend_from_slice(value);
+        }
+        SpecializationData { entries, data }
+    }
+}
+
+/// Must outlive the `vk::SpecializationInfo` pointing into it
+pub struct SpecializationData {
+    entries: Vec<vk::SpecializationMapEntry>,
+    data: Vec<u8>,
+}
+
+impl SpecializationData {
+    pub fn info(&self) -> vk::SpecializationInfo {
+        vk::SpecializationInfo::builder()
+            .map_entries(&self.entries)
+            .data(&self.data)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        let constants = SpecializationConstants::new()
+            .set(3, true)
+            .set(0, 1.5f64)
+            .set(1, -2i32);
+        assert_eq!(
+            constants.sizes().collect::<Vec<_>>(),
+            vec![(0, 8), (1, 4), (3, 4)]
+        );
+        assert!(SpecializationConstants::new().sizes().next().is_none());
+    }
+
+    #[test]
+    fn test_pack() {
+        let data = SpecializationConstants::new()
+            .set(2, 7u32)
+            .set(0, 0.25f64)
+            .set(5, true)
+            .pack();
+        let entries = data
+            .entries
+            .iter()
+            .map(|entry| (entry.constant_id, entry.offset, entry.size))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![(0, 0, 8), (2, 8, 4), (5, 12, 4)]);
+
+        let mut expected = 0.25f64.to_ne_bytes().to_vec();
+        expected.extend_from_slice(&7u32.to_ne_bytes());
+        expected.extend_from_slice(&vk::TRUE.to_ne_bytes());
+        assert_eq!(data.data, expected);
+
+        let info = data.info();
+        assert_eq!(info.map_entry_count, 3);
+        assert_eq!(info.data_size, 16);
+    }
+
+    #[test]
+    fn test_set_replaces_value() {
+        let constants =
+            SpecializationConstants::new().set(0, 1u32).set(0, 2.0f64);
+        assert_eq!(constants.sizes().collect::<Vec<_>>(), vec![(0, 8)]);
+        assert_eq!(constants.pack().data, 2.0f64.to_ne_bytes());
+    }
+}
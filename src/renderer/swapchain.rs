@@ -3,7 +3,7 @@ use bevy::log;
 use color_eyre::eyre::Result;
 use gpu_allocator::vulkan::Allocator;
 
-use super::{context::Context, image::AllocatedImage};
+use super::{attachments::RenderAttachments, context::Context};
 
 pub struct Swapchain {
     pub swapchain: vk::SwapchainKHR,
@@ -13,22 +13,27 @@ pub struct Swapchain {
     pub image_extent: vk::Extent2D,
     pub image_views: Vec<vk::ImageView>,
 
-    pub depth_image: AllocatedImage,
+    /// Depth and multisampled color images of the geometry pass
+    pub attachments: RenderAttachments,
 }
 
 impl Swapchain {
     pub fn new(
         ctx: &Context,
+        surface: vk::SurfaceKHR,
         allocator: &mut Allocator,
         window: &winit::window::Window,
+        samples: vk::SampleCountFlags,
     ) -> Result<Self> {
         let (swapchain, swapchain_loader, images, image_format, image_extent) =
-            create_swapchain(ctx, window)?;
+            create_swapchain(ctx, surface, window)?;
         let image_views = create_image_views(ctx, &image_format, &images)?;
 
-        let depth_image = AllocatedImage::new_depth_image(
-            image_extent.width,
-            image_extent.height,
+        let attachments = RenderAttachments::new(
+            image_extent,
+            image_format,
+            ctx.depth_format,
+            samples,
             &ctx.device,
             allocator,
         )?;
@@ -40,7 +45,7 @@ impl Swapchain {
             image_format,
             image_extent,
             image_views,
-            depth_image,
+            attachments,
         };
 
         Ok(objs)
@@ -49,7 +54,7 @@ impl Swapchain {
     pub fn cleanup(self, device: &ash::Device, allocator: &mut Allocator) {
         log::info!("Cleaning up swapchain ...");
         unsafe {
-            self.depth_image.cleanup(device, allocator);
+            self.attachments.cleanup(device, allocator);
             for view in &self.image_views {
                 device.destroy_image_view(*view, None);
             }
@@ -61,6 +66,7 @@ impl Swapchain {
 
 fn create_swapchain(
     ctx: &Context,
+    surface: vk::SurfaceKHR,
     window: &winit::window::Window,
 ) -> Result<(
     vk::SwapchainKHR,
@@ -71,7 +77,7 @@ fn create_swapchain(
 )> {
     let swapchain_support = query_swapchain_support(
         &ctx.physical_device,
-        &ctx.surface,
+        &surface,
         &ctx.surface_loader,
     )?;
 
@@ -116,7 +122,7 @@ fn create_swapchain(
     };
 
     let info = vk::SwapchainCreateInfoKHR {
-        surface: ctx.surface,
+        surface,
         min_image_count,
         image_format: surface_format.format,
         image_color_space: surface_format.color_space,
@@ -73,6 +73,25 @@ impl Texture {
         })
     }
 
+    /// Create a texture that a camera can render into
+    /// and that materials can sample afterwards
+    pub fn new_render_texture(
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        sampler: vk::Sampler,
+        device: &ash::Device,
+        allocator: &mut Allocator,
+    ) -> Result<Self> {
+        let image = AllocatedImage::new_render_target_image(
+            width, height, format, device, allocator,
+        )?;
+        Ok(Self {
+            image,
+            sampler: Some(sampler),
+        })
+    }
+
     pub fn image(&self) -> &AllocatedImage {
         &self.image
     }
@@ -26,6 +26,17 @@ pub fn pad_uniform_buffer_size(
     }
 }
 
+/// Whether a depth format also has a stencil aspect
+pub fn format_has_stencil(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::S8_UINT
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
 pub fn copy_image_to_image(
     cmd: vk::CommandBuffer,
     src: vk::Image,
@@ -126,7 +137,9 @@ pub fn transition_image_layout(
 
 #[cfg(test)]
 mod tests {
-    use crate::renderer::vkutils::pad_uniform_buffer_size;
+    use ash::vk;
+
+    use crate::renderer::vkutils::{format_has_stencil, pad_uniform_buffer_size};
 
     #[test]
     fn test_pad_uniform_buffer_32_size_0_alignment() {
@@ -157,4 +170,14 @@ mod tests {
     fn test_pad_uniform_buffer_22_size_54_alignment() {
         assert_eq!(pad_uniform_buffer_size(22, 54), 74);
     }
+
+    #[test]
+    fn test_format_has_stencil() {
+        assert!(format_has_stencil(vk::Format::S8_UINT));
+        assert!(format_has_stencil(vk::Format::D24_UNORM_S8_UINT));
+        assert!(format_has_stencil(vk::Format::D32_SFLOAT_S8_UINT));
+        assert!(!format_has_stencil(vk::Format::D32_SFLOAT));
+        assert!(!format_has_stencil(vk::Format::D16_UNORM));
+        assert!(!format_has_stencil(vk::Format::R8G8B8A8_UNORM));
+    }
 }